@@ -0,0 +1,345 @@
+extern crate wars;
+
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env::args;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use wars::bot::{self, Bot};
+use wars::game::{Event, Game, GameState, Map, PlayerNumber, UnitId, action};
+use wars::model::{unit_type, weapon};
+
+const USAGE: &str = "\
+Usage: simulate <map> [options]
+
+<map> is a map file path or the name of a map in data/maps (e.g. u-turn)
+
+Options:
+    --games N         Number of games to play (default 100)
+    --bot PLAYER=BOT  Bot for a player number (default random)
+    --max-rounds N    Rounds before a game is called a draw (default 100)
+    --seed N          Random seed for reproducible runs
+    --format FORMAT   Report format: text, json or csv (default text)
+    --output FILE     Write the report to a file instead of stdout
+";
+
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+struct Options {
+    map: String,
+    games: u32,
+    bots: HashMap<PlayerNumber, String>,
+    max_rounds: u32,
+    seed: Option<u64>,
+    format: Format,
+    output: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = args().skip(1); // executable name
+    let mut options = Options {
+        map: String::new(),
+        games: 100,
+        bots: HashMap::new(),
+        max_rounds: 100,
+        seed: None,
+        format: Format::Text,
+        output: None,
+    };
+
+    fn value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+        value
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| format!("Invalid or missing value for {option}"))
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--games" => options.games = value(&arg, args.next())?,
+            "--max-rounds" => options.max_rounds = value(&arg, args.next())?,
+            "--seed" => options.seed = Some(value(&arg, args.next())?),
+            "--output" => options.output = Some(value(&arg, args.next())?),
+            "--bot" => {
+                let bot: String = value(&arg, args.next())?;
+                let (player_number, name) = bot
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected PLAYER=BOT, got {bot}"))?;
+                let player_number = value(&arg, Some(player_number.to_owned()))?;
+                options.bots.insert(player_number, name.to_owned());
+            }
+            "--format" => {
+                options.format = match value::<String>(&arg, args.next())?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    other => return Err(format!("Unknown format {other}")),
+                }
+            }
+            "--help" | "-h" => return Err(String::new()),
+            _ if options.map.is_empty() && !arg.starts_with("--") => options.map = arg,
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    if options.map.is_empty() {
+        return Err("Map argument is required".to_owned());
+    }
+    Ok(options)
+}
+
+fn load_map(map: &str) -> Result<Map, String> {
+    let maps_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/maps");
+    let path = if Path::new(map).is_file() {
+        Path::new(map).to_path_buf()
+    } else {
+        maps_dir.join(format!("{map}.json"))
+    };
+    let mut map_json = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut map_json))
+        .map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+    Map::from_json(&map_json).map_err(|e| format!("Error loading map: {e}"))
+}
+
+#[derive(Serialize, Default)]
+struct Report {
+    map: String,
+    games: u32,
+    draws: u32,
+    wins: BTreeMap<PlayerNumber, u32>,
+    win_rates: BTreeMap<PlayerNumber, f64>,
+    average_rounds: f64,
+    units_built: BTreeMap<String, u32>,
+    damage_dealt: BTreeMap<String, u64>,
+    #[serde(skip)]
+    total_rounds: u64,
+}
+
+impl Report {
+    /// Replay the events of a turn on the game state preceding it to see
+    /// which weapons were used, since events only carry the damage dealt.
+    fn record_turn(&mut self, mut game: Game, events: &[Event]) {
+        for event in events {
+            match *event {
+                Event::Attack(attacker_id, target_id, damage)
+                | Event::Counterattack(attacker_id, target_id, damage) => {
+                    if let Some((name, damage)) =
+                        attack_weapon(&game, attacker_id, target_id, damage)
+                    {
+                        *self.damage_dealt.entry(name.to_owned()).or_default() += damage as u64;
+                    }
+                }
+                Event::Build(_, _, built_type, _) => {
+                    *self
+                        .units_built
+                        .entry(unit_type(built_type).name.to_owned())
+                        .or_default() += 1;
+                }
+                Event::WinGame(player_number) => {
+                    *self.wins.entry(player_number).or_default() += 1;
+                }
+                _ => (),
+            }
+
+            if let Err(e) = action::process(&mut game, event) {
+                eprintln!("Could not replay {event:?}: {e}");
+                return;
+            }
+        }
+    }
+    fn finish(&mut self) {
+        let games = self.games.max(1) as f64;
+        self.win_rates = self
+            .wins
+            .iter()
+            .map(|(&player_number, &wins)| (player_number, wins as f64 / games))
+            .collect();
+        self.average_rounds = self.total_rounds as f64 / games;
+    }
+    fn as_text(&self) -> String {
+        let mut lines = vec![
+            format!("Map: {}", self.map),
+            format!("Games: {}", self.games),
+            format!("Draws: {}", self.draws),
+            format!("Average length: {:.1} rounds", self.average_rounds),
+            "Win rates:".to_owned(),
+        ];
+        lines.extend(self.win_rates.iter().map(|(player_number, rate)| {
+            format!("  Player {player_number}: {:.1}%", rate * 100.0)
+        }));
+        lines.push("Units built:".to_owned());
+        lines.extend(
+            self.units_built
+                .iter()
+                .map(|(name, count)| format!("  {name}: {count}")),
+        );
+        lines.push("Damage dealt:".to_owned());
+        lines.extend(
+            self.damage_dealt
+                .iter()
+                .map(|(name, damage)| format!("  {name}: {damage}")),
+        );
+        lines.join("\n")
+    }
+    fn as_csv(&self) -> String {
+        let mut rows = vec![
+            "metric,key,value".to_owned(),
+            format!("map,,{}", self.map),
+            format!("games,,{}", self.games),
+            format!("draws,,{}", self.draws),
+            format!("average_rounds,,{}", self.average_rounds),
+        ];
+        rows.extend(
+            self.win_rates
+                .iter()
+                .map(|(player_number, rate)| format!("win_rate,{player_number},{rate}")),
+        );
+        rows.extend(
+            self.units_built
+                .iter()
+                .map(|(name, count)| format!("units_built,{name},{count}")),
+        );
+        rows.extend(
+            self.damage_dealt
+                .iter()
+                .map(|(name, damage)| format!("damage_dealt,{name},{damage}")),
+        );
+        rows.join("\n")
+    }
+}
+
+/// Name of the weapon used in an attack and the damage actually dealt with it
+fn attack_weapon(
+    game: &Game,
+    attacker_id: UnitId,
+    target_id: UnitId,
+    damage: u32,
+) -> Option<(&'static str, u32)> {
+    let attacker = game.units.get_ref(&attacker_id)?;
+    let target = game.units.get_ref(&target_id)?;
+    let (_, attacker_tile) = game.tiles.get_unit_tile(attacker_id)?;
+    let (_, target_tile) = game.tiles.get_unit_tile(target_id)?;
+    let distance = attacker_tile
+        .position()
+        .distance_to(&target_tile.position());
+    let (used_weapon, _) =
+        action::select_attack_weapon(attacker, target, distance, target_tile.terrain)?;
+    Some((weapon(used_weapon).name, damage.min(target.health)))
+}
+
+fn play(
+    map: &Map,
+    bots: &mut HashMap<PlayerNumber, Box<dyn Bot>>,
+    luck_state: u64,
+    max_rounds: u32,
+    report: &mut Report,
+) {
+    let mut players: Vec<_> = map.player_numbers().into_iter().map(|pn| (pn, 0)).collect();
+    players.sort();
+
+    let mut game = Game {
+        luck_state,
+        ..Game::new(map.clone(), &players)
+    };
+    action::start(&mut game, &mut |_| ()).expect("Could not start game");
+
+    while game.state == GameState::InProgress && game.round_count <= max_rounds {
+        let Some(player_number) = game.in_turn_number() else {
+            break;
+        };
+        let before = game.clone();
+        let mut events = Vec::new();
+        let mut emit = |event| events.push(event);
+        let bot = bots.get_mut(&player_number).expect("Player has no bot");
+        if let Err(e) = bot::play_turn(bot.as_mut(), &mut game, &mut emit) {
+            eprintln!("Player {player_number} bot error: {e}, ending turn");
+            if let Err(e) = action::end_turn(&mut game, &mut emit) {
+                eprintln!("Could not end turn: {e}, abandoning game");
+                break;
+            }
+        }
+        report.record_turn(before, &events);
+    }
+
+    report.games += 1;
+    report.total_rounds += game.round_count.min(max_rounds) as u64;
+    if game.state != GameState::Finished {
+        report.draws += 1;
+    }
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{message}\n");
+            }
+            eprint!("{USAGE}");
+            std::process::exit(1);
+        }
+    };
+    let map = load_map(&options.map).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    // Bots and then games are seeded in order so that --seed gives
+    // reproducible runs
+    let mut rng = options
+        .seed
+        .map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed);
+    let mut player_numbers = map.player_numbers();
+    player_numbers.sort();
+    let mut bots: HashMap<PlayerNumber, Box<dyn Bot>> = player_numbers
+        .into_iter()
+        .map(|player_number| {
+            let name = options
+                .bots
+                .get(&player_number)
+                .map(String::as_str)
+                .unwrap_or(bot::DEFAULT_BOT);
+            let bot = bot::seeded_by_name(name, rng.u64(..)).unwrap_or_else(|| {
+                let names: Vec<_> = bot::names().collect();
+                eprintln!("Unknown bot {name}, available bots: {}", names.join(", "));
+                std::process::exit(1);
+            });
+            (player_number, bot)
+        })
+        .collect();
+
+    let mut report = Report {
+        map: map.name.clone(),
+        wins: bots
+            .keys()
+            .map(|&player_number| (player_number, 0))
+            .collect(),
+        ..Report::default()
+    };
+    for _ in 0..options.games {
+        play(
+            &map,
+            &mut bots,
+            rng.u64(..),
+            options.max_rounds,
+            &mut report,
+        );
+    }
+    report.finish();
+
+    let output = match options.format {
+        Format::Text => report.as_text(),
+        Format::Json => serde_json::to_string_pretty(&report).expect("Could not serialize report"),
+        Format::Csv => report.as_csv(),
+    };
+    match options.output {
+        Some(path) => File::create(&path)
+            .and_then(|mut f| writeln!(f, "{output}"))
+            .expect("Error writing report"),
+        None => println!("{output}"),
+    }
+}
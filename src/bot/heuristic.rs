@@ -0,0 +1,559 @@
+use crate::bot::{Bot, TurnPlan};
+use crate::game::{Action, Game, PlayerNumber, Position, Tile, Unit, UnitId, UnitType, action};
+use crate::model::*;
+use std::collections::HashSet;
+
+/// Units at or below this health head back to a repair tile
+const RETREAT_HEALTH: u32 = 40;
+/// Score lost per hex between a unit and its nearest objective
+const DISTANCE_PENALTY: i64 = 25;
+/// Longest weapon range considered when estimating threats
+const MAX_RANGE: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// Upper bound of the random bonus added to each candidate score
+    fn noise(self) -> i64 {
+        match self {
+            Difficulty::Easy => 400,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 0,
+        }
+    }
+    /// Share of the enemy's possible reply next turn that is taken into
+    /// account when choosing where to move, in percent
+    fn lookahead(self) -> i64 {
+        match self {
+            Difficulty::Easy => 0,
+            Difficulty::Normal => 50,
+            Difficulty::Hard => 100,
+        }
+    }
+}
+
+/// Plays by scoring every move of every unit and greedily performing the
+/// best one. Attacks are scored as damage trades in credits, capturing
+/// properties and repairing damaged units are rewarded, and builds are
+/// picked to counter the enemy's armor mix.
+pub struct HeuristicBot {
+    difficulty: Difficulty,
+    rng: fastrand::Rng,
+}
+
+impl HeuristicBot {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Self {
+            difficulty,
+            rng: fastrand::Rng::new(),
+        }
+    }
+    pub fn with_seed(difficulty: Difficulty, seed: u64) -> Self {
+        Self {
+            difficulty,
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    fn noise(&mut self) -> i64 {
+        self.rng.i64(0..=self.difficulty.noise())
+    }
+
+    /// Best scoring action for a unit, if it has anything to do
+    fn best_unit_action(
+        &mut self,
+        game: &Game,
+        player_number: PlayerNumber,
+        unit_id: UnitId,
+    ) -> Option<(i64, Action)> {
+        let unit = game.units.get_ref(&unit_id)?;
+        let mut destinations: Vec<_> = game.unit_actions(unit_id).into_iter().collect();
+        destinations.sort_by_key(|(destination, _)| *destination);
+
+        let unit_objectives = objectives(game, player_number, unit);
+        let mut candidates = Vec::new();
+
+        for (destination, actions) in destinations {
+            let Ok((_, tile)) = game.tiles.get_at(&destination) else {
+                continue;
+            };
+            let distance = unit_objectives
+                .iter()
+                .map(|objective| destination.distance_to(objective))
+                .min()
+                .unwrap_or(0) as i64;
+            let threat = self.threat(game, player_number, unit, &destination, &tile);
+            let base = -distance * DISTANCE_PENALTY + repair_value(player_number, unit, &tile)
+                - threat * self.difficulty.lookahead() / 100;
+
+            for candidate in actions {
+                let score = match candidate {
+                    Action::MoveAndWait(..) => Some(base),
+                    Action::MoveAndAttack(_, _, target_id) => game
+                        .unit_can_attack_target(&unit_id, &target_id, &destination)
+                        .and_then(|damage| {
+                            attack_trade(game, unit, &destination, &tile, target_id, damage)
+                        })
+                        .map(|trade| base + trade),
+                    Action::MoveAndCapture(..) => Some(base + capture_value(unit, &tile)),
+                    Action::MoveAndDeploy(..) => {
+                        let value = deploy_value(game, player_number, unit, &destination);
+                        (value > 0).then_some(base + value)
+                    }
+                    Action::Undeploy(_) => game
+                        .unit_attack_options(unit_id, &destination)
+                        .is_empty()
+                        .then_some(0),
+                    Action::MoveAndUnload(_, _, carried_id, ref unload_position) => {
+                        game.units.get_ref(&carried_id).map(|carried| {
+                            let distance = objectives(game, player_number, carried)
+                                .iter()
+                                .map(|objective| unload_position.distance_to(objective))
+                                .min()
+                                .unwrap_or(0) as i64;
+                            base + unit_value(carried) / 2 - distance * DISTANCE_PENALTY
+                        })
+                    }
+                    _ => None,
+                };
+                if let Some(score) = score {
+                    candidates.push((score, candidate));
+                }
+            }
+        }
+
+        candidates
+            .into_iter()
+            .map(|(score, action)| (score + self.noise(), action))
+            .max_by_key(|(score, _)| *score)
+    }
+
+    /// Estimated value the enemy could destroy next turn if the unit stood at
+    /// the position. Enemies are assumed to reach anything within their
+    /// movement and weapon range, ignoring terrain and blocking units.
+    fn threat(
+        &self,
+        game: &Game,
+        player_number: PlayerNumber,
+        unit: &Unit,
+        position: &Position,
+        tile: &Tile,
+    ) -> i64 {
+        if self.difficulty.lookahead() == 0 {
+            return 0;
+        }
+        let damage: u32 = game
+            .units
+            .iter_with_ids()
+            .filter(|(_, enemy)| enemy.owner.is_some() && enemy.owner != Some(player_number))
+            .filter_map(|(&enemy_id, enemy)| {
+                let distance = position_of(game, enemy_id)?.distance_to(position);
+                let reach = if enemy.deployed {
+                    0
+                } else {
+                    enemy.unit_type_data().movement
+                };
+                (1..=MAX_RANGE)
+                    .filter(|range| *range <= distance && distance <= range + reach)
+                    .filter_map(|range| {
+                        action::calculate_attack_damage(enemy, unit, range, tile.terrain)
+                    })
+                    .max()
+            })
+            .sum();
+        unit_value(unit) * damage.min(unit.health) as i64 / UNIT_MAX_HEALTH as i64
+    }
+
+    /// Build the unit type that best counters the enemy's units on each free
+    /// base, most valuable choices first
+    fn plan_builds(&mut self, plan: &mut TurnPlan, player_number: PlayerNumber) {
+        let enemies: Vec<Unit> = plan
+            .game
+            .units
+            .iter()
+            .filter(|unit| unit.owner.is_some() && unit.owner != Some(player_number))
+            .cloned()
+            .collect();
+        let capturers = plan
+            .game
+            .units
+            .owned_by_player(player_number)
+            .filter(|(_, unit)| unit.can_capture())
+            .count();
+        let capturable = plan
+            .game
+            .tiles
+            .iter()
+            .filter(|tile| tile.is_capturable() && tile.owner != Some(player_number))
+            .count();
+
+        let mut bases: Vec<Position> = plan
+            .game
+            .build_actions(player_number)
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::Build(position, _) => Some(position),
+                _ => None,
+            })
+            .collect();
+        bases.dedup();
+
+        for base in bases {
+            let need_capturers = capturers < capturable.div_ceil(3);
+            let best = plan
+                .game
+                .build_actions(player_number)
+                .into_iter()
+                .filter_map(|a| match a {
+                    Action::Build(position, build_type) if position == base => Some(build_type),
+                    _ => None,
+                })
+                .map(|build_type| {
+                    let score = build_value(build_type, &enemies, need_capturers) + self.noise();
+                    (score, build_type)
+                })
+                .max_by_key(|(score, _)| *score);
+            if let Some((_, build_type)) = best {
+                plan.perform(Action::Build(base, build_type));
+            }
+        }
+    }
+}
+
+impl Bot for HeuristicBot {
+    fn plan_turn(&mut self, game: &Game, player_number: PlayerNumber) -> Vec<Action> {
+        let mut plan = TurnPlan::new(game);
+        let mut done = HashSet::new();
+
+        loop {
+            let being_carried: HashSet<UnitId> = plan
+                .game
+                .units
+                .owned_by_player(player_number)
+                .flat_map(|(_, unit)| unit.carried.iter().copied())
+                .collect();
+            let mut ready: Vec<UnitId> = plan
+                .game
+                .units
+                .owned_by_player(player_number)
+                .filter(|(unit_id, unit)| {
+                    !unit.moved && !done.contains(unit_id) && !being_carried.contains(unit_id)
+                })
+                .map(|(unit_id, _)| unit_id)
+                .collect();
+            ready.sort();
+
+            let best = ready
+                .into_iter()
+                .filter_map(|unit_id| {
+                    self.best_unit_action(&plan.game, player_number, unit_id)
+                        .map(|(score, action)| (score, unit_id, action))
+                })
+                .max_by_key(|(score, _, _)| *score);
+            let Some((_, unit_id, best_action)) = best else {
+                break;
+            };
+
+            done.insert(unit_id);
+            plan.perform(best_action);
+        }
+
+        self.plan_builds(&mut plan, player_number);
+        plan.finish()
+    }
+}
+
+pub(super) fn position_of(game: &Game, unit_id: UnitId) -> Option<Position> {
+    game.tiles
+        .get_unit_tile(unit_id)
+        .map(|(_, tile)| tile.position())
+}
+
+pub(super) fn unit_value(unit: &Unit) -> i64 {
+    (unit.unit_type_data().price * unit.health / UNIT_MAX_HEALTH) as i64
+}
+
+/// Positions the unit should be heading towards: a repair tile when badly
+/// damaged, otherwise enemy units and, for capturing units, properties
+pub(super) fn objectives(game: &Game, player_number: PlayerNumber, unit: &Unit) -> Vec<Position> {
+    if unit.health <= RETREAT_HEALTH {
+        let repair_tiles: Vec<_> = game
+            .tiles
+            .owned_by_player(player_number)
+            .filter(|(_, tile)| tile.can_repair_unit(unit))
+            .map(|(_, tile)| tile.position())
+            .collect();
+        if !repair_tiles.is_empty() {
+            return repair_tiles;
+        }
+    }
+
+    let properties = game
+        .tiles
+        .iter()
+        .filter(|tile| unit.can_capture() && tile.is_capturable())
+        .filter(|tile| tile.owner != Some(player_number))
+        .map(Tile::position);
+    let enemies = game
+        .units
+        .iter_with_ids()
+        .filter(|(_, enemy)| enemy.owner.is_some() && enemy.owner != Some(player_number))
+        .filter_map(|(&enemy_id, _)| position_of(game, enemy_id));
+    properties.chain(enemies).collect()
+}
+
+/// Value of the damage dealt minus the value of the counterattack received
+fn attack_trade(
+    game: &Game,
+    attacker: &Unit,
+    attack_from: &Position,
+    attack_from_tile: &Tile,
+    target_id: UnitId,
+    damage: u32,
+) -> Option<i64> {
+    let target = game.units.get_ref(&target_id)?;
+    let distance = attack_from.distance_to(&position_of(game, target_id)?);
+    let dealt = unit_value(target) * damage.min(target.health) as i64 / target.health as i64;
+    if damage >= target.health {
+        // Destroying a unit also removes its future damage
+        return Some(dealt + unit_value(target) / 2);
+    }
+
+    let damaged_target = Unit {
+        health: target.health - damage,
+        ..target.clone()
+    };
+    let received = action::calculate_attack_damage(
+        &damaged_target,
+        attacker,
+        distance,
+        attack_from_tile.terrain,
+    )
+    .map(|counter| {
+        unit_value(attacker) * counter.min(attacker.health) as i64 / attacker.health as i64
+    })
+    .unwrap_or(0);
+    Some(dealt - received)
+}
+
+fn capture_value(unit: &Unit, tile: &Tile) -> i64 {
+    let value = if tile.has_terrain_flag(TerrainFlag::HQ) {
+        2000
+    } else if !tile.terrain_data().build_classes.is_empty() {
+        600
+    } else {
+        400
+    };
+    let progress = value * unit.health.min(tile.capture_points) as i64 / tile.capture_points as i64;
+    if unit.health >= tile.capture_points {
+        progress + value / 2
+    } else {
+        progress
+    }
+}
+
+fn repair_value(player_number: PlayerNumber, unit: &Unit, tile: &Tile) -> i64 {
+    if unit.health > RETREAT_HEALTH
+        || tile.owner != Some(player_number)
+        || !tile.can_repair_unit(unit)
+    {
+        return 0;
+    }
+    let repaired = tile.repair_rate().min(UNIT_MAX_HEALTH - unit.health);
+    (unit.unit_type_data().price * repaired / UNIT_MAX_HEALTH) as i64 * 2
+}
+
+/// Value of deploying at the position, based on the enemies that would be in
+/// range next turn
+fn deploy_value(game: &Game, player_number: PlayerNumber, unit: &Unit, position: &Position) -> i64 {
+    let deployed = Unit {
+        deployed: true,
+        ..unit.clone()
+    };
+    let targets: i64 = game
+        .units
+        .iter_with_ids()
+        .filter(|(_, enemy)| enemy.owner.is_some() && enemy.owner != Some(player_number))
+        .filter_map(|(&enemy_id, enemy)| {
+            let (_, tile) = game.tiles.get_unit_tile(enemy_id)?;
+            let distance = position.distance_to(&tile.position());
+            let damage = action::calculate_attack_damage(&deployed, enemy, distance, tile.terrain)?;
+            Some(unit_value(enemy) * damage.min(enemy.health) as i64 / enemy.health as i64)
+        })
+        .max()
+        .unwrap_or(0);
+    targets / 2
+}
+
+/// Best damage any of the unit type's weapons can do against the armor
+fn effectiveness(attacker: UnitType, armor: Armor) -> i64 {
+    unit_type(attacker)
+        .weapons
+        .iter()
+        .filter_map(|&w| (weapon(w).power_map)(armor))
+        .max()
+        .unwrap_or(0) as i64
+}
+
+/// How well the unit type fits the enemy's army: the value it can destroy in
+/// an average attack on the enemy units, minus the value it loses in an
+/// average attack from them
+fn build_value(build_type: UnitType, enemies: &[Unit], need_capturers: bool) -> i64 {
+    let data = unit_type(build_type);
+    let price = data.price as i64;
+    let capture_bonus = if need_capturers && data.flags.contains(&UnitFlag::Capture) {
+        1000
+    } else {
+        0
+    };
+    if enemies.is_empty() {
+        return capture_bonus + price / 10;
+    }
+
+    let count = enemies.len() as i64;
+    let offense: i64 = enemies
+        .iter()
+        .map(|enemy| {
+            effectiveness(build_type, enemy.unit_type_data().armor_type) * unit_value(enemy) / 100
+        })
+        .sum::<i64>()
+        / count;
+    let vulnerability: i64 = enemies
+        .iter()
+        .map(|enemy| effectiveness(enemy.unit_type, data.armor_type).min(100) * price / 100)
+        .sum::<i64>()
+        / count;
+    capture_bonus + offense - vulnerability / 2
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bot::heuristic::*;
+    use crate::game::*;
+    use std::collections::HashMap;
+
+    fn plains_map(units: &[(Position, Unit)], funds: u32) -> Map {
+        let mut tiles: HashMap<TileId, Tile> = (0..5)
+            .flat_map(|y| (0..5).map(move |x| (x, y)))
+            .enumerate()
+            .map(|(tile_id, (x, y))| {
+                let tile = Tile {
+                    terrain: Terrain::Plains,
+                    x,
+                    y,
+                    ..Tile::default()
+                };
+                (tile_id, tile)
+            })
+            .collect();
+        let units = units
+            .iter()
+            .enumerate()
+            .map(|(unit_id, (position, unit))| {
+                let tile = tiles
+                    .values_mut()
+                    .find(|tile| tile.position() == *position)
+                    .unwrap();
+                tile.unit = Some(unit_id);
+                (unit_id, unit.clone())
+            })
+            .collect();
+        Map {
+            name: "Test".into(),
+            units,
+            tiles,
+            funds,
+        }
+    }
+
+    #[test]
+    fn attacks_weak_enemy_in_range() {
+        let tank = Unit {
+            unit_type: UnitType::MediumTank,
+            owner: Some(1),
+            ..Unit::default()
+        };
+        let infantry = Unit {
+            unit_type: UnitType::Infantry,
+            owner: Some(2),
+            health: 30,
+            ..Unit::default()
+        };
+        let map = plains_map(&[(Position(0, 0), tank), (Position(2, 2), infantry)], 0);
+        let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+        action::start(&mut game, &mut |_| ()).unwrap();
+
+        let mut bot = HeuristicBot::with_seed(Difficulty::Hard, 1);
+        let actions = bot.plan_turn(&game, 1);
+        assert!(
+            matches!(actions[0], Action::MoveAndAttack(0, _, 1)),
+            "{actions:?}"
+        );
+    }
+
+    #[test]
+    fn captures_property() {
+        let infantry = Unit {
+            unit_type: UnitType::Infantry,
+            owner: Some(1),
+            ..Unit::default()
+        };
+        let mut map = plains_map(&[(Position(0, 0), infantry)], 0);
+        let city = map
+            .tiles
+            .values_mut()
+            .find(|tile| tile.position() == Position(1, 1))
+            .unwrap();
+        city.terrain = Terrain::City;
+        let base = map
+            .tiles
+            .values_mut()
+            .find(|tile| tile.position() == Position(4, 4))
+            .unwrap();
+        base.terrain = Terrain::Base;
+        base.owner = Some(2);
+        let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+        action::start(&mut game, &mut |_| ()).unwrap();
+
+        let mut bot = HeuristicBot::with_seed(Difficulty::Hard, 1);
+        let actions = bot.plan_turn(&game, 1);
+        assert!(
+            matches!(&actions[0], Action::MoveAndCapture(0, path) if path.last() == Some(&Position(1, 1))),
+            "{actions:?}"
+        );
+    }
+
+    #[test]
+    fn builds_counter_to_tanks() {
+        let tank = Unit {
+            unit_type: UnitType::HeavyTank,
+            owner: Some(2),
+            ..Unit::default()
+        };
+        let mut map = plains_map(&[(Position(4, 4), tank)], 500);
+        let base = map
+            .tiles
+            .values_mut()
+            .find(|tile| tile.position() == Position(0, 0))
+            .unwrap();
+        base.terrain = Terrain::Base;
+        base.owner = Some(1);
+        let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+        action::start(&mut game, &mut |_| ()).unwrap();
+
+        let mut bot = HeuristicBot::with_seed(Difficulty::Hard, 1);
+        let actions = bot.plan_turn(&game, 1);
+        assert!(
+            matches!(
+                actions[0],
+                Action::Build(Position(0, 0), UnitType::ATInfantry)
+            ),
+            "{actions:?}"
+        );
+    }
+}
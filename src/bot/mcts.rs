@@ -0,0 +1,499 @@
+use crate::bot::heuristic::{objectives, position_of, unit_value};
+use crate::bot::{Bot, TurnPlan};
+use crate::game::{Action, Game, GameState, PlayerNumber, Position, Tile, UnitId, action};
+use crate::model::*;
+use std::time::{Duration, Instant};
+
+/// Destinations considered for units that only move
+const WAIT_DESTINATIONS: usize = 3;
+/// Turns of income a property is worth when evaluating a position
+const PROPERTY_TURNS: i64 = 10;
+/// Value of holding a property units can be built on on top of its income,
+/// as players without units or such properties lose
+const BASE_VALUE: i64 = 2000;
+/// Value lost per hex between a unit and its nearest objective
+const DISTANCE_PENALTY: i64 = 10;
+/// Lead in credits that makes a position worth about 0.73, or a quarter of
+/// the players' combined strength if that is more, so that a player far
+/// ahead still tells good moves from bad ones
+const EVALUATION_SCALE: f64 = 1000.0;
+
+/// How much searching a bot may do per turn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    Iterations(u32),
+    Time(Duration),
+}
+
+/// Plans its turn one decision at a time with Monte Carlo tree search over
+/// the decisions of the turn. Each iteration replays a path of the tree on a
+/// copy of the game, plays the rest of the turn and the following
+/// `rollout_turns` turns randomly, and scores the result by comparing the
+/// material and income of the players.
+pub struct MctsBot {
+    budget: Budget,
+    rollout_turns: u32,
+    exploration: f64,
+    rng: fastrand::Rng,
+}
+
+impl MctsBot {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            rollout_turns: 0,
+            exploration: 0.5,
+            rng: fastrand::Rng::new(),
+        }
+    }
+    pub fn with_seed(budget: Budget, seed: u64) -> Self {
+        Self {
+            rng: fastrand::Rng::with_seed(seed),
+            ..Self::new(budget)
+        }
+    }
+    /// Turns played randomly after the bot's own turn before a position is
+    /// evaluated
+    pub fn rollout_turns(self, rollout_turns: u32) -> Self {
+        Self {
+            rollout_turns,
+            ..self
+        }
+    }
+    pub fn exploration(self, exploration: f64) -> Self {
+        Self {
+            exploration,
+            ..self
+        }
+    }
+
+    /// Most promising next decision for the player in turn
+    fn search(&mut self, game: &Game, player_number: PlayerNumber, budget: Budget) -> Action {
+        let mut tree = vec![Node::new(None, candidate_actions(game))];
+        let deadline = match budget {
+            Budget::Time(duration) => Some(Instant::now() + duration),
+            Budget::Iterations(_) => None,
+        };
+        let mut iterations = 0;
+
+        loop {
+            match (budget, deadline) {
+                (_, Some(deadline)) if iterations > 0 && Instant::now() >= deadline => break,
+                (Budget::Iterations(n), _) if iterations >= n => break,
+                _ => iterations += 1,
+            }
+
+            // Each iteration rolls its own luck
+            let mut game = game.clone();
+            game.luck_state = self.rng.u64(..);
+            let mut path = vec![0];
+            let mut node = 0;
+
+            // Selection. With luck a child's action can fail on this replay,
+            // and the iteration then carries on from the last node reached.
+            while tree[node].untried.is_empty() && !tree[node].children.is_empty() {
+                let child = self.select_child(&tree, node);
+                let selected = tree[child].action.clone().unwrap();
+                if action::perform(&mut game, selected, &mut |_| ()).is_err() {
+                    break;
+                }
+                node = child;
+                path.push(node);
+            }
+
+            // Expansion, until the end of the turn
+            if !tree[node].untried.is_empty() {
+                let index = self.rng.usize(..tree[node].untried.len());
+                let expanded = tree[node].untried.swap_remove(index);
+                if action::perform(&mut game, expanded.clone(), &mut |_| ()).is_ok() {
+                    let untried = if in_turn(&game, player_number) {
+                        candidate_actions(&game)
+                    } else {
+                        Vec::new()
+                    };
+                    tree.push(Node::new(Some(expanded), untried));
+                    let child = tree.len() - 1;
+                    tree[node].children.push(child);
+                    path.push(child);
+                }
+            }
+
+            // Simulation
+            let value = self.rollout(game, player_number);
+
+            // Backpropagation
+            for &index in &path {
+                tree[index].visits += 1;
+                tree[index].value += value;
+            }
+        }
+
+        tree[0]
+            .children
+            .iter()
+            .max_by_key(|&&child| tree[child].visits)
+            .and_then(|&child| tree[child].action.clone())
+            .unwrap_or(Action::EndTurn)
+    }
+
+    fn select_child(&self, tree: &[Node], node: usize) -> usize {
+        let parent_visits = (tree[node].visits.max(1) as f64).ln();
+        let ucb = |child: usize| {
+            let Node { visits, value, .. } = tree[child];
+            let visits = visits.max(1) as f64;
+            value / visits + self.exploration * (parent_visits / visits).sqrt()
+        };
+        *tree[node]
+            .children
+            .iter()
+            .max_by(|&&a, &&b| ucb(a).total_cmp(&ucb(b)))
+            .unwrap()
+    }
+
+    /// Play randomly until the end of the player's turn and `rollout_turns`
+    /// turns after it, then evaluate
+    fn rollout(&mut self, mut game: Game, player_number: PlayerNumber) -> f64 {
+        let mut turns_left = self.rollout_turns;
+        while game.state == GameState::InProgress {
+            if !in_turn(&game, player_number) {
+                if turns_left == 0 {
+                    break;
+                }
+                turns_left -= 1;
+            }
+            let turn_count = game.turn_count;
+            while game.state == GameState::InProgress && game.turn_count == turn_count {
+                let chosen = self.rollout_action(candidate_actions(&game));
+                if action::perform(&mut game, chosen, &mut |_| ()).is_err()
+                    && action::end_turn(&mut game, &mut |_| ()).is_err()
+                {
+                    return evaluate(&game, player_number);
+                }
+            }
+        }
+        evaluate(&game, player_number)
+    }
+
+    /// Rollouts act with every unit and base before ending the turn, and
+    /// favour actions that change the game over just moving around
+    fn rollout_action(&mut self, mut candidates: Vec<Action>) -> Action {
+        if candidates.len() > 1 {
+            candidates.retain(|candidate| *candidate != Action::EndTurn);
+        }
+        let active: Vec<_> = candidates
+            .iter()
+            .filter(|candidate| {
+                matches!(
+                    candidate,
+                    Action::MoveAndAttack(..) | Action::MoveAndCapture(..) | Action::Build(..)
+                )
+            })
+            .cloned()
+            .collect();
+        if !active.is_empty() && self.rng.u32(0..4) > 0 {
+            candidates = active;
+        }
+        self.rng.choice(candidates).unwrap_or(Action::EndTurn)
+    }
+}
+
+impl Bot for MctsBot {
+    fn plan_turn(&mut self, game: &Game, player_number: PlayerNumber) -> Vec<Action> {
+        let mut plan = TurnPlan::new(game);
+        let started = Instant::now();
+
+        while in_turn(&plan.game, player_number) {
+            let candidates = candidate_actions(&plan.game);
+            let chosen = if candidates.len() <= 1 {
+                candidates.into_iter().next().unwrap_or(Action::EndTurn)
+            } else {
+                // Split what is left of the budget evenly over the decisions
+                // still to be made
+                let decisions = remaining_decisions(&plan.game, player_number);
+                let budget = match self.budget {
+                    Budget::Iterations(n) => Budget::Iterations((n / decisions).max(1)),
+                    Budget::Time(duration) => {
+                        Budget::Time(duration.saturating_sub(started.elapsed()) / decisions)
+                    }
+                };
+                self.search(&plan.game, player_number, budget)
+            };
+            if chosen == Action::EndTurn || !plan.perform(chosen) {
+                break;
+            }
+        }
+
+        plan.finish()
+    }
+}
+
+struct Node {
+    action: Option<Action>,
+    children: Vec<usize>,
+    untried: Vec<Action>,
+    visits: u32,
+    value: f64,
+}
+
+impl Node {
+    fn new(action: Option<Action>, untried: Vec<Action>) -> Self {
+        Self {
+            action,
+            children: Vec::new(),
+            untried,
+            visits: 0,
+            value: 0.0,
+        }
+    }
+}
+
+fn in_turn(game: &Game, player_number: PlayerNumber) -> bool {
+    game.state == GameState::InProgress && game.in_turn_number() == Some(player_number)
+}
+
+fn ready_units(game: &Game, player_number: PlayerNumber) -> Vec<UnitId> {
+    let mut ready: Vec<UnitId> = game
+        .units
+        .owned_by_player(player_number)
+        .filter(|(unit_id, unit)| !unit.moved && position_of(game, *unit_id).is_some())
+        .map(|(unit_id, _)| unit_id)
+        .collect();
+    ready.sort();
+    ready
+}
+
+fn free_bases(game: &Game, player_number: PlayerNumber) -> Vec<Position> {
+    let mut bases: Vec<Position> = game
+        .tiles
+        .owned_by_player(player_number)
+        .filter(|(_, tile)| !tile.terrain_data().build_classes.is_empty() && tile.unit.is_none())
+        .map(|(_, tile)| tile.position())
+        .collect();
+    bases.sort();
+    bases
+}
+
+fn remaining_decisions(game: &Game, player_number: PlayerNumber) -> u32 {
+    (ready_units(game, player_number).len() + free_bases(game, player_number).len() + 1) as u32
+}
+
+/// A small set of sensible actions for the player in turn. Units are
+/// decided in id order and bases after them, so that the same position is
+/// not reached through every ordering of the same decisions. For the next
+/// unit these are every attack, every capture, deploying, unloading and
+/// waiting on the destinations closest to its objectives; for the next base
+/// building any affordable unit or ending the turn.
+fn candidate_actions(game: &Game) -> Vec<Action> {
+    let Some(player_number) = game.in_turn_number() else {
+        return Vec::new();
+    };
+    let mut candidates = Vec::new();
+
+    if let Some(&unit_id) = ready_units(game, player_number).first() {
+        unit_candidates(game, player_number, unit_id, &mut candidates);
+        if !candidates.is_empty() {
+            return candidates;
+        }
+    } else {
+        let builds = game.build_actions(player_number);
+        if let Some(Action::Build(base, _)) = builds.first().cloned() {
+            candidates.extend(
+                builds.into_iter().filter(
+                    |build| matches!(build, Action::Build(position, _) if *position == base),
+                ),
+            );
+        }
+    }
+
+    candidates.push(Action::EndTurn);
+    candidates
+}
+
+fn unit_candidates(
+    game: &Game,
+    player_number: PlayerNumber,
+    unit_id: UnitId,
+    candidates: &mut Vec<Action>,
+) {
+    let (Some(unit), Some(position)) = (game.units.get_ref(&unit_id), position_of(game, unit_id))
+    else {
+        return;
+    };
+    let unit_objectives = objectives(game, player_number, unit);
+    let distance_to_objective = |destination: &Position| {
+        unit_objectives
+            .iter()
+            .map(|objective| destination.distance_to(objective))
+            .min()
+            .unwrap_or(0)
+    };
+
+    let mut destinations: Vec<_> = game.unit_actions(unit_id).into_iter().collect();
+    destinations.sort_by_key(|(destination, _)| (distance_to_objective(destination), *destination));
+
+    // Staying put is always considered, other moves that only wait or deploy
+    // when they lead somewhere
+    let mut moves = 0;
+    for (destination, actions) in destinations {
+        let consider_moving = destination == position || moves < WAIT_DESTINATIONS;
+        if destination != position {
+            moves += 1;
+        }
+        candidates.extend(actions.into_iter().filter(|candidate| {
+            consider_moving
+                || !matches!(
+                    candidate,
+                    Action::MoveAndWait(..) | Action::MoveAndDeploy(..)
+                )
+        }));
+    }
+}
+
+fn property_value(tile: &Tile) -> i64 {
+    let income = if tile.has_terrain_flag(TerrainFlag::Funds) {
+        FUNDS_PER_PROPERTY as i64 * PROPERTY_TURNS
+    } else {
+        0
+    };
+    if !tile.terrain_data().build_classes.is_empty() {
+        income + BASE_VALUE
+    } else {
+        income
+    }
+}
+
+/// Player's lead in material, income and progress towards objectives over
+/// their strongest opponent, mapped from 0 to 1
+fn evaluate(game: &Game, player_number: PlayerNumber) -> f64 {
+    let strength = |player_number: PlayerNumber| -> i64 {
+        let units: i64 = game
+            .units
+            .owned_by_player(player_number)
+            .map(|(unit_id, unit)| {
+                let distance = position_of(game, unit_id)
+                    .and_then(|position| {
+                        objectives(game, player_number, unit)
+                            .iter()
+                            .map(|objective| position.distance_to(objective))
+                            .min()
+                    })
+                    .unwrap_or(0);
+                unit_value(unit) - distance as i64 * DISTANCE_PENALTY
+            })
+            .sum();
+        let properties: i64 = game
+            .tiles
+            .iter()
+            .filter(|tile| tile.is_capturable())
+            .map(|tile| {
+                let value = property_value(tile);
+                let capturer = tile
+                    .unit
+                    .and_then(|unit_id| game.units.get_ref(&unit_id))
+                    .filter(|unit| unit.owner != tile.owner)
+                    .and_then(|unit| unit.owner);
+                let captured = value * (MAX_CAPTURE_POINTS - tile.capture_points) as i64
+                    / MAX_CAPTURE_POINTS as i64;
+                match (tile.owner, capturer) {
+                    (Some(owner), Some(_)) if owner == player_number => value - captured,
+                    (Some(owner), None) if owner == player_number => value,
+                    (_, Some(capturer)) if capturer == player_number => captured,
+                    _ => 0,
+                }
+            })
+            .sum();
+        let funds = game
+            .get_player(player_number)
+            .map(|p| p.funds as i64)
+            .unwrap_or(0);
+        units + funds / 2 + properties
+    };
+
+    let alive = game
+        .players
+        .iter()
+        .any(|p| p.number == player_number && p.alive);
+    if !alive {
+        return 0.0;
+    }
+    let opponent = game
+        .players
+        .iter()
+        .filter(|p| p.number != player_number && p.alive)
+        .map(|p| strength(p.number))
+        .max();
+    let Some(opponent) = opponent else {
+        return 1.0;
+    };
+    let own = strength(player_number);
+    let lead = (own - opponent) as f64;
+    let scale = EVALUATION_SCALE.max((own.abs() + opponent.abs()) as f64 / 4.0);
+    1.0 / (1.0 + (-lead / scale).exp())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bot::mcts::*;
+    use crate::game::*;
+
+    fn load_game() -> Game {
+        let map = Map::from_json(include_str!("../../data/maps/third_party.json")).unwrap();
+        let mut players: Vec<_> = map.player_numbers().into_iter().map(|p| (p, 0)).collect();
+        players.sort();
+        let mut game = Game::new(map, &players);
+        action::start(&mut game, &mut |_| ()).unwrap();
+        game
+    }
+
+    #[test]
+    fn candidate_actions_can_be_performed() {
+        let game = load_game();
+        let candidates = candidate_actions(&game);
+        assert!(candidates.len() > 1);
+        for candidate in candidates {
+            let mut copy = game.clone();
+            assert!(
+                action::perform(&mut copy, candidate.clone(), &mut |_| ()).is_ok(),
+                "{candidate:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn plans_a_playable_turn_with_luck() {
+        let map = Map::from_json(include_str!("../../data/maps/third_party.json")).unwrap();
+        let mut players: Vec<_> = map.player_numbers().into_iter().map(|p| (p, 0)).collect();
+        players.sort();
+        let rules = Rules {
+            luck: 50,
+            ..Rules::default()
+        };
+        let mut game = Game::with_rules(map, &players, rules);
+        action::start(&mut game, &mut |_| ()).unwrap();
+        for _ in 0..4 {
+            let player_number = game.in_turn_number().unwrap();
+            let mut bot = MctsBot::with_seed(Budget::Iterations(30), 2).rollout_turns(0);
+            for planned in bot.plan_turn(&game, player_number) {
+                if action::perform(&mut game, planned, &mut |_| ()).is_err() {
+                    break;
+                }
+            }
+            if game.in_turn_number() == Some(player_number) {
+                action::end_turn(&mut game, &mut |_| ()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn plans_a_playable_turn() {
+        let mut game = load_game();
+        let player_number = game.in_turn_number().unwrap();
+        let mut bot = MctsBot::with_seed(Budget::Iterations(50), 1).rollout_turns(0);
+        let actions = bot.plan_turn(&game, player_number);
+        assert_eq!(actions.last(), Some(&Action::EndTurn));
+        for planned in actions {
+            action::perform(&mut game, planned, &mut |_| ()).unwrap();
+        }
+        assert_ne!(game.in_turn_number(), Some(player_number));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::game::{Action, ActionError, ActionResult, Event, Game, PlayerNumber, action};
+
+mod heuristic;
+mod mcts;
+mod random;
+pub use self::heuristic::{Difficulty, HeuristicBot};
+pub use self::mcts::{Budget, MctsBot};
+pub use self::random::RandomBot;
+
+/// Bot used when a bot player slot doesn't name a registered bot
+pub const DEFAULT_BOT: &str = "random";
+
+/// An AI player. Bots plan a whole turn at once: given the game and the
+/// player they play as, they return the actions to perform in order.
+pub trait Bot: Send {
+    fn plan_turn(&mut self, game: &Game, player_number: PlayerNumber) -> Vec<Action>;
+}
+
+/// A turn being planned on a copy of the game. Planned actions are performed
+/// on the copy right away so that later choices see their effects.
+pub(crate) struct TurnPlan {
+    pub game: Game,
+    pub actions: Vec<Action>,
+}
+
+impl TurnPlan {
+    pub fn new(game: &Game) -> Self {
+        Self {
+            game: game.clone(),
+            actions: Vec::new(),
+        }
+    }
+    /// Add the action to the plan if it can be performed
+    pub fn perform(&mut self, planned_action: Action) -> bool {
+        let result = action::perform(&mut self.game, planned_action.clone(), &mut |_| ());
+        if result.is_ok() {
+            self.actions.push(planned_action);
+        }
+        result.is_ok()
+    }
+    pub fn finish(mut self) -> Vec<Action> {
+        self.actions.push(Action::EndTurn);
+        self.actions
+    }
+}
+
+type BotConstructor = fn(u64) -> Box<dyn Bot>;
+
+const BOTS: &[(&str, BotConstructor)] = &[
+    ("random", |seed| Box::new(RandomBot::with_seed(seed))),
+    ("heuristic-easy", |seed| {
+        Box::new(HeuristicBot::with_seed(Difficulty::Easy, seed))
+    }),
+    ("heuristic", |seed| {
+        Box::new(HeuristicBot::with_seed(Difficulty::Normal, seed))
+    }),
+    ("heuristic-hard", |seed| {
+        Box::new(HeuristicBot::with_seed(Difficulty::Hard, seed))
+    }),
+    ("mcts", |seed| {
+        Box::new(MctsBot::with_seed(Budget::Iterations(2000), seed))
+    }),
+];
+
+/// Names of all registered bots, usable in `PlayerSlotType::Bot`
+pub fn names() -> impl Iterator<Item = &'static str> {
+    BOTS.iter().map(|&(name, _)| name)
+}
+pub fn by_name(name: &str) -> Option<Box<dyn Bot>> {
+    seeded_by_name(name, fastrand::u64(..))
+}
+/// The bot with its random choices seeded, for reproducible games
+pub fn seeded_by_name(name: &str, seed: u64) -> Option<Box<dyn Bot>> {
+    BOTS.iter()
+        .find(|&&(bot_name, _)| bot_name == name)
+        .map(|(_, constructor)| constructor(seed))
+}
+
+/// Let the bot play the in-turn player's turn. The turn is ended after the
+/// planned actions unless one of them already ended it.
+pub fn play_turn(
+    bot: &mut dyn Bot,
+    game: &mut Game,
+    emit: &mut dyn FnMut(Event),
+) -> ActionResult<()> {
+    let player_number = game
+        .in_turn_number()
+        .ok_or(ActionError::GameNotInProgress)?;
+
+    // Bots plan without the game's luck state so that they can't foresee the
+    // rolls
+    let view = game.clone().without_luck_state();
+    for planned_action in bot.plan_turn(&view, player_number) {
+        action::perform(game, planned_action, emit)?;
+        if game.in_turn_number() != Some(player_number) {
+            return Ok(());
+        }
+    }
+    action::end_turn(game, emit)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bot;
+    use crate::game::*;
+    const THIRD_PARTY_MAP: &str = include_str!("../../data/maps/third_party.json");
+
+    #[test]
+    fn registered_bots_play_turns() {
+        for name in bot::names() {
+            let map = Map::from_json(THIRD_PARTY_MAP).unwrap();
+            let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+            action::start(&mut game, &mut |_| ()).unwrap();
+
+            let mut bot = bot::by_name(name).unwrap();
+            for _ in 0..4 {
+                let player_number = game.in_turn_number();
+                bot::play_turn(bot.as_mut(), &mut game, &mut |_| ())
+                    .unwrap_or_else(|e| panic!("Bot {name} failed: {e}"));
+                assert_ne!(game.in_turn_number(), player_number);
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::bot::{Bot, TurnPlan};
+use crate::game::{Action, Game, PlayerNumber, Position, UnitId};
+use std::collections::HashSet;
+
+/// Moves every unit to a random destination, picks a random follow-up there
+/// and builds random affordable units
+pub struct RandomBot {
+    rng: fastrand::Rng,
+}
+
+impl RandomBot {
+    pub fn new() -> Self {
+        Self {
+            rng: fastrand::Rng::new(),
+        }
+    }
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+}
+
+impl Default for RandomBot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bot for RandomBot {
+    fn plan_turn(&mut self, game: &Game, player_number: PlayerNumber) -> Vec<Action> {
+        let rng = &mut self.rng;
+        let mut plan = TurnPlan::new(game);
+
+        let being_carried: HashSet<UnitId> = game
+            .units
+            .owned_by_player(player_number)
+            .flat_map(|(_, unit)| unit.carried.iter().copied())
+            .collect();
+        let mut my_units: Vec<UnitId> = game
+            .units
+            .owned_by_player(player_number)
+            .map(|(unit_id, _)| unit_id)
+            .filter(|unit_id| !being_carried.contains(unit_id))
+            .collect();
+
+        my_units.sort();
+        rng.shuffle(&mut my_units);
+
+        for unit_id in my_units {
+            // Earlier actions may have destroyed the unit
+            let mut destinations: Vec<_> = plan.game.unit_actions(unit_id).into_iter().collect();
+            // Hash map order would make a seeded bot play differently on
+            // every run
+            destinations.sort_by_key(|(destination, _)| *destination);
+            let Some((_, actions)) = rng.choice(destinations) else {
+                continue;
+            };
+
+            let of_kind = |matches: fn(&Action) -> bool| -> Vec<Action> {
+                actions.iter().filter(|a| matches(a)).cloned().collect()
+            };
+            let attacks = of_kind(|a| matches!(a, Action::MoveAndAttack(..)));
+            let captures = of_kind(|a| matches!(a, Action::MoveAndCapture(..)));
+            let deploys = of_kind(|a| matches!(a, Action::MoveAndDeploy(..) | Action::Undeploy(_)));
+            let unloads = of_kind(|a| matches!(a, Action::MoveAndUnload(..)));
+            let others =
+                of_kind(|a| matches!(a, Action::MoveAndWait(..) | Action::MoveAndLoadInto(..)));
+
+            let planned_action = if !attacks.is_empty() && rng.bool() {
+                rng.choice(attacks)
+            } else if !captures.is_empty() && rng.bool() {
+                rng.choice(captures)
+            } else if !deploys.is_empty() && rng.bool() {
+                rng.choice(deploys)
+            } else if !unloads.is_empty() {
+                rng.choice(unloads)
+            } else {
+                rng.choice(others)
+            };
+            if let Some(planned_action) = planned_action {
+                plan.perform(planned_action);
+            }
+        }
+
+        let mut my_bases: Vec<Position> = plan
+            .game
+            .build_actions(player_number)
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::Build(position, _) => Some(position),
+                _ => None,
+            })
+            .collect();
+        my_bases.dedup();
+        rng.shuffle(&mut my_bases);
+        for base in my_bases {
+            let build_options: Vec<_> = plan
+                .game
+                .build_actions(player_number)
+                .into_iter()
+                .filter(|a| matches!(a, Action::Build(position, _) if *position == base))
+                .collect();
+            if let Some(build) = rng.choice(build_options) {
+                plan.perform(build);
+            }
+        }
+
+        plan.finish()
+    }
+}
@@ -24,7 +24,10 @@ pub fn perform(game: &mut Game, action: Action, emit: &mut dyn FnMut(Event)) ->
 pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
     match event {
         &Event::StartTurn(player_number) => {
-            game.set_player_in_turn(player_number)?;
+            if game.state == GameState::Pregame {
+                game.set_state(GameState::InProgress)?;
+            }
+            game.advance_turn(player_number)?;
             game.units
                 .owned_by_player(player_number)
                 .filter(|(_, unit)| unit.capturing)
@@ -57,6 +60,10 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
                 .collect::<Vec<_>>()
                 .into_iter()
                 .try_for_each(|(unit_id, unit)| game.units.update(unit_id, unit))?;
+            game.update_alive_players()?;
+            if game.next_player_number().is_none() {
+                game.set_state(GameState::Finished)?;
+            }
         }
         &Event::Funds(player_number, amount) => {
             let mut player = game
@@ -66,45 +73,15 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
             game.players.update(player)?;
         }
         &Event::UnitRepair(unit_id, amount) => {
-            game.units
-                .get(unit_id)
-                .ok_or(ActionError::UnitNotFound)?
-                .health = amount;
+            let mut unit = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
+            unit.health = amount;
+            game.update_tiles_and_units([], [(unit_id, unit)])?;
         }
-        &Event::WinGame(_player_number) => {
+        &Event::WinGame(_) | &Event::Draw => {
             game.state = GameState::Finished;
         }
-        &Event::Surrender(player_number) => {
-            game.tiles
-                .owned_by_player(player_number)
-                .map(|(tile_id, tile)| {
-                    (
-                        tile_id,
-                        Tile {
-                            owner: None,
-                            ..*tile
-                        },
-                    )
-                })
-                .collect::<Vec<_>>()
-                .into_iter()
-                .try_for_each(|(tile_id, tile)| game.tiles.update(tile_id, tile))?;
-
-            // Neutralize owned units
-            game.units
-                .owned_by_player(player_number)
-                .map(|(unit_id, unit)| {
-                    (
-                        unit_id,
-                        Unit {
-                            owner: None,
-                            ..unit.clone()
-                        },
-                    )
-                })
-                .collect::<Vec<_>>()
-                .into_iter()
-                .try_for_each(|(unit_id, unit)| game.units.update(unit_id, unit))?;
+        &Event::Surrender(player_number) | &Event::Defeated(player_number) => {
+            neutralize_player(game, player_number)?;
         }
         &Event::Move(unit_id, ref path) => {
             let (src_tile_id, mut src_tile) = game
@@ -140,14 +117,13 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
             game.update_tiles_and_units([], [(attacker_id, attacker), (target_id, target)])?;
         }
         &Event::Counterattack(attacker_id, target_id, damage) => {
-            let mut attacker = game
+            let _attacker = game
                 .units
                 .get(attacker_id)
                 .ok_or(ActionError::UnitNotFound)?;
             let mut target = game.units.get(target_id).ok_or(ActionError::UnitNotFound)?;
-            attacker.moved = true;
             target.health -= target.health.min(damage);
-            game.update_tiles_and_units([], [(attacker_id, attacker), (target_id, target)])?;
+            game.update_tiles_and_units([], [(target_id, target)])?;
         }
         &Event::Destroyed(_attacker_id, target_id) => {
             let _target = game.units.get(target_id).ok_or(ActionError::UnitNotFound)?;
@@ -166,16 +142,18 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
         &Event::Undeploy(unit_id) => {
             let mut unit = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
             unit.moved = true;
-            unit.deployed = true;
+            unit.deployed = false;
             game.update_tiles_and_units([], [(unit_id, unit)])?;
         }
         &Event::Load(unit_id, carrier_id) => {
+            let mut unit = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
             let mut carrier = game
                 .units
                 .get(carrier_id)
                 .ok_or(ActionError::UnitNotFound)?;
+            unit.moved = true;
             carrier.carried.push(unit_id);
-            game.update_tiles_and_units([], [(carrier_id, carrier)])?;
+            game.update_tiles_and_units([], [(unit_id, unit), (carrier_id, carrier)])?;
         }
         &Event::Unload(carrier_id, unit_id, position) => {
             let (dst_tile_id, mut dst_tile) = game.tiles.get_at(&position)?;
@@ -188,10 +166,15 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
                 .iter()
                 .position(|uid| *uid == unit_id)
                 .ok_or(ActionError::CannotUnload)?;
+            let mut carried = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
             dst_tile.unit = Some(unit_id);
             carrier.carried.remove(carried_index);
             carrier.moved = true;
-            game.update_tiles_and_units([(dst_tile_id, dst_tile)], [(carrier_id, carrier)])?;
+            carried.moved = true;
+            game.update_tiles_and_units(
+                [(dst_tile_id, dst_tile)],
+                [(carrier_id, carrier), (unit_id, carried)],
+            )?;
         }
         &Event::Capture(unit_id, tile_id, capture_points) => {
             let mut unit = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
@@ -204,13 +187,16 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
             }
             tile.capture_points = capture_points;
             unit.moved = true;
+            unit.capturing = true;
             game.update_tiles_and_units([(tile_id, tile)], [(unit_id, unit)])?;
         }
         &Event::Captured(unit_id, tile_id, player_number) => {
             let mut unit = game.units.get(unit_id).ok_or(ActionError::UnitNotFound)?;
             let mut tile = game.tiles.get(tile_id).ok_or(ActionError::TileNotFound)?;
             unit.moved = true;
+            unit.capturing = true;
             tile.owner = player_number;
+            tile.capture_points = 1;
             game.update_tiles_and_units([(tile_id, tile)], [(unit_id, unit)])?;
         }
         &Event::Build(tile_id, unit_id, unit_type, price) => {
@@ -222,16 +208,16 @@ pub fn process(game: &mut Game, event: &Event) -> ActionResult<()> {
                 return Err(ActionError::IntegrityError);
             }
             player.funds -= price;
+            if unit_id != game.next_unit_id {
+                return Err(ActionError::IntegrityError);
+            }
             let unit = Unit {
                 unit_type: unit_type,
                 moved: true,
                 owner: tile.owner,
                 ..Unit::default()
             };
-            let new_unit_id = game.units.insert(unit);
-            if new_unit_id != unit_id {
-                return Err(ActionError::IntegrityError);
-            }
+            game.add_unit(unit)?;
             tile.unit = Some(unit_id);
             game.players.update(player)?;
             game.update_tiles_and_units([(tile_id, tile)], [])?;
@@ -248,6 +234,7 @@ pub fn start(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<()> {
     game.set_state(GameState::InProgress)
         .map_err(|_| ActionError::GameAlreadyStarted)?;
     let in_turn_number = game.in_turn_number().ok_or(ActionError::InternalError)?;
+    game.advance_turn(in_turn_number)?;
     start_turn(game, in_turn_number, emit)?;
     Ok(())
 }
@@ -267,7 +254,7 @@ fn start_turn(
     let generated_funds = game
         .tiles
         .owned_by_player(player_number)
-        .map(|(_, tile)| tile.generated_funds())
+        .map(|(_, tile)| tile.generated_funds(game.rules.funds_per_property))
         .sum();
 
     player.funds += generated_funds;
@@ -369,26 +356,7 @@ pub fn end_turn(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<()
     finish_turn(game, in_turn_number, emit)?;
 
     // Update player alive statuses
-    let players_with_units = game.players_with_units();
-    let players_with_build_tiles = game.players_with_build_tiles();
-
-    let updated_players: Vec<_> = game
-        .players
-        .iter()
-        .filter_map(|p| {
-            let alive = players_with_units.contains(&p.number)
-                || players_with_build_tiles.contains(&p.number);
-            if p.alive != alive {
-                Some(Player { alive, ..*p })
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    updated_players
-        .into_iter()
-        .try_for_each(|p| game.players.update(p))?;
+    game.update_alive_players()?;
 
     // Check win condition
     if let Some(winner) = game.winner() {
@@ -397,11 +365,22 @@ pub fn end_turn(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<()
         return Ok(());
     }
 
-    // Set next player in turn
-    let in_turn_number = game
-        .next_player_number()
-        .ok_or(ActionError::InternalError)?;
-    game.set_player_in_turn(in_turn_number)?;
+    // Set next player in turn. With nobody left the game ends in a draw.
+    let Some(in_turn_number) = game.next_player_number() else {
+        game.set_state(GameState::Finished)?;
+        return Ok(());
+    };
+
+    // Past the round limit the player with the most properties wins
+    if game.round_limit_reached(in_turn_number) {
+        emit(match game.property_leader() {
+            Some(leader) => Event::WinGame(leader),
+            None => Event::Draw,
+        });
+        game.set_state(GameState::Finished)?;
+        return Ok(());
+    }
+    game.advance_turn(in_turn_number)?;
 
     start_turn(game, in_turn_number, emit)?;
 
@@ -413,9 +392,18 @@ pub fn surrender(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<(
         .in_turn_number()
         .ok_or(ActionError::GameNotInProgress)?;
 
+    neutralize_player(game, in_turn_number)?;
+
+    emit(Event::Surrender(in_turn_number));
+
+    end_turn(game, emit)
+}
+
+/// Take away the player's tiles and units
+fn neutralize_player(game: &mut Game, player_number: PlayerNumber) -> ActionResult<()> {
     // Neutralize owned tiles
     game.tiles
-        .owned_by_player(in_turn_number)
+        .owned_by_player(player_number)
         .map(|(tile_id, tile)| {
             (
                 tile_id,
@@ -431,7 +419,7 @@ pub fn surrender(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<(
 
     // Neutralize owned units
     game.units
-        .owned_by_player(in_turn_number)
+        .owned_by_player(player_number)
         .map(|(unit_id, unit)| {
             (
                 unit_id,
@@ -444,10 +432,7 @@ pub fn surrender(game: &mut Game, emit: &mut dyn FnMut(Event)) -> ActionResult<(
         .collect::<Vec<_>>()
         .into_iter()
         .try_for_each(|(unit_id, unit)| game.units.update(unit_id, unit))?;
-
-    emit(Event::Surrender(in_turn_number));
-
-    end_turn(game, emit)
+    Ok(())
 }
 
 pub fn build(
@@ -467,6 +452,9 @@ pub fn build(
     if !tile.can_build(build_type) || tile.unit.is_some() {
         return Err(ActionError::CannotBuild);
     }
+    if game.rules.is_banned(build_type) {
+        return Err(ActionError::UnitTypeBanned);
+    }
     let price = unit_type(build_type).price;
     if in_turn_player.funds < price {
         return Err(ActionError::InsufficientFunds);
@@ -478,7 +466,7 @@ pub fn build(
         owner: tile.owner,
         ..Unit::default()
     };
-    let unit_id = game.units.insert(unit);
+    let unit_id = game.add_unit(unit)?;
     tile.unit = Some(unit_id);
     in_turn_player.funds -= price;
 
@@ -541,6 +529,15 @@ pub fn calculate_attack_damage(
     distance: u32,
     target_terrain: Terrain,
 ) -> Option<u32> {
+    select_attack_weapon(attacker, target, distance, target_terrain).map(|(_, damage)| damage)
+}
+/// Weapon the attacker would use against the target and the damage it deals
+pub fn select_attack_weapon(
+    attacker: &Unit,
+    target: &Unit,
+    distance: u32,
+    target_terrain: Terrain,
+) -> Option<(Weapon, u32)> {
     if attacker.owner == target.owner {
         return None;
     }
@@ -550,16 +547,19 @@ pub fn calculate_attack_damage(
         .unit_type_data()
         .weapons
         .iter()
-        .map(|&w| weapon(w))
-        .filter(|w| !w.require_deployed || attacker.deployed)
-        .filter_map(|w| (w.range_map)(distance).map(|efficiency| (w, efficiency)))
-        .filter_map(|(w, efficiency)| (w.power_map)(target_armor).map(|power| (efficiency, power)))
-        .map(|(efficiency, power)| {
-            attacker.health * power * efficiency * (100 - defense * target.health / 100)
-                / (100_00_00)
+        .map(|&w| (w, weapon(w)))
+        .filter(|(_, w)| !w.require_deployed || attacker.deployed)
+        .filter_map(|(w, data)| (data.range_map)(distance).map(|efficiency| (w, data, efficiency)))
+        .filter_map(|(w, data, efficiency)| {
+            (data.power_map)(target_armor).map(|power| (w, efficiency, power))
         })
-        .max()
-        .map(|damage| damage.max(1))
+        .map(|(w, efficiency, power)| {
+            let damage =
+                attacker.health * power * efficiency * (100 - defense * target.health / 100)
+                    / (100_00_00);
+            (w, damage.max(1))
+        })
+        .max_by_key(|(_, damage)| *damage)
 }
 pub fn move_and_attack(
     game: &mut Game,
@@ -580,6 +580,7 @@ pub fn move_and_attack(
     let distance =
         Position(dst_tile.x, dst_tile.y).distance_to(&Position(target_tile.x, target_tile.y));
     let damage = calculate_attack_damage(&unit, &target, distance, target_tile.terrain)
+        .map(|damage| game.roll_luck(damage))
         .ok_or(ActionError::CannotAttack)?;
 
     emit(Event::Move(unit_id, path.into()));
@@ -600,6 +601,7 @@ pub fn move_and_attack(
 
         if let Some(counter_damage) =
             calculate_attack_damage(&target, &unit, distance, dst_tile.terrain)
+                .map(|damage| game.roll_luck(damage))
         {
             emit(Event::Counterattack(target_id, unit_id, counter_damage));
             if counter_damage >= unit.health {
@@ -649,7 +651,9 @@ pub fn move_and_capture(
 
     emit(Event::Move(unit_id, path.into()));
 
+    let mut previous_owner = None;
     if unit.health >= dst_tile.capture_points {
+        previous_owner = dst_tile.owner;
         dst_tile.capture_points = 1;
         dst_tile.owner = unit.owner;
         emit(Event::Captured(unit_id, dst_tile_id, unit.owner));
@@ -663,11 +667,26 @@ pub fn move_and_capture(
         ));
     }
 
+    let captured_hq = dst_tile.has_terrain_flag(TerrainFlag::HQ);
     game.update_tiles_and_units(
         [(src_tile_id, src_tile), (dst_tile_id, dst_tile)],
         [(unit_id, unit)],
     )?;
 
+    // Losing the last HQ defeats the player when HQ capture is a victory
+    // condition
+    if let Some(previous_owner) = previous_owner
+        && captured_hq
+        && game.rules.victory_conditions.hq_capture
+        && !game
+            .tiles
+            .owned_by_player(previous_owner)
+            .any(|(_, tile)| tile.has_terrain_flag(TerrainFlag::HQ))
+    {
+        emit(Event::Defeated(previous_owner));
+        neutralize_player(game, previous_owner)?;
+    }
+
     Ok(())
 }
 
@@ -779,6 +798,7 @@ pub fn move_and_unload(
 
     if !carrier.carried.contains(&carried_id)
         || unload_position.distance_to(path.last().ok_or(ActionError::InvalidPath)?) != 1
+        || !carried.can_move_on_terrain(dst_tile.terrain)
         || !carried.can_move_on_terrain(unload_tile.terrain)
         || unload_tile.unit.is_some()
     {
@@ -859,6 +879,25 @@ mod test {
         );
     }
     #[test]
+    fn test_round_count() {
+        let map = Map::from_json(THIRD_PARTY_MAP).unwrap();
+        let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+        start(&mut game, &mut |_| ()).unwrap();
+        assert_eq!((game.round_count, game.turn_count), (1, 1));
+
+        let mut replayed = game.clone();
+        let mut events = Vec::new();
+        end_turn(&mut game, &mut |e| events.push(e)).unwrap();
+        assert_eq!((game.round_count, game.turn_count), (1, 2));
+        end_turn(&mut game, &mut |e| events.push(e)).unwrap();
+        assert_eq!((game.round_count, game.turn_count), (2, 3));
+
+        for event in events.iter() {
+            process(&mut replayed, event).unwrap();
+        }
+        assert_eq!((replayed.round_count, replayed.turn_count), (2, 3));
+    }
+    #[test]
     fn test_capture() {
         let base = Tile {
             terrain: model::Terrain::Base,
@@ -1136,6 +1175,60 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_build_does_not_reuse_unit_ids() {
+        let base = Tile {
+            terrain: model::Terrain::Base,
+            ..Tile::default()
+        };
+        let units = [Unit {
+            owner: Some(2),
+            unit_type: UnitType::Infantry,
+            ..Unit::default()
+        }]
+        .iter()
+        .cloned()
+        .enumerate()
+        .collect();
+        let tiles = tiles_from_array(&[&[
+            Tile {
+                owner: Some(1),
+                ..base
+            },
+            Tile {
+                owner: Some(2),
+                unit: Some(0),
+                ..base
+            },
+        ]]);
+        let map = Map {
+            name: "Test".into(),
+            units,
+            tiles,
+            funds: 100,
+        };
+        let mut game = Game::new(map, &[(1, 1), (2, 2)]);
+        start(&mut game, &mut |_| ()).unwrap();
+        let mut replayed = game.clone();
+
+        // Unit 0 is the highest-numbered unit, its id must not be handed out again
+        let mut events = vec![Event::Destroyed(0, 0)];
+        process(&mut game, &events[0]).unwrap();
+        build(&mut game, Position(0, 0), UnitType::Infantry, &mut |e| {
+            events.push(e)
+        })
+        .unwrap();
+
+        assert_eq!(events[1], Event::Build(0, 1, UnitType::Infantry, 100));
+        assert_eq!(game.next_unit_id, 2);
+
+        for event in events.iter() {
+            process(&mut replayed, event).unwrap();
+        }
+        assert_eq!(replayed.next_unit_id, 2);
+        assert!(replayed.units.get(1).is_some());
+    }
+
     #[test]
     fn test_attack() {
         let base = Tile {
@@ -1207,4 +1300,177 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_rules_funds_and_banned_unit_types() {
+        let base = Tile {
+            terrain: model::Terrain::Base,
+            ..Tile::default()
+        };
+        let tiles = tiles_from_array(&[&[
+            Tile {
+                owner: Some(1),
+                ..base
+            },
+            Tile {
+                owner: Some(2),
+                ..base
+            },
+        ]]);
+        let map = Map {
+            name: "Test".into(),
+            units: HashMap::new(),
+            tiles,
+            funds: 0,
+        };
+        let rules = Rules {
+            starting_funds: Some(500),
+            funds_per_property: 50,
+            banned_unit_types: vec![UnitType::Infantry],
+            ..Rules::default()
+        };
+        let mut game = Game::with_rules(map, &[(1, 1), (2, 2)], rules);
+        start(&mut game, &mut |_| ()).unwrap();
+        assert_eq!(game.in_turn_player().unwrap().funds, 550);
+
+        let emit = &mut |_| ();
+        assert_eq!(
+            build(&mut game, Position(0, 0), UnitType::Infantry, emit),
+            Err(ActionError::UnitTypeBanned)
+        );
+        assert!(
+            !game
+                .legal_actions(1)
+                .contains(&Action::Build(Position(0, 0), UnitType::Infantry))
+        );
+        build(&mut game, Position(0, 0), UnitType::ATInfantry, emit).unwrap();
+    }
+
+    #[test]
+    fn test_hq_capture() {
+        let hq = Tile {
+            terrain: model::Terrain::HQ,
+            ..Tile::default()
+        };
+        let units = [
+            Unit {
+                owner: Some(1),
+                unit_type: UnitType::Infantry,
+                ..Unit::default()
+            },
+            Unit {
+                owner: Some(2),
+                unit_type: UnitType::Infantry,
+                ..Unit::default()
+            },
+        ]
+        .iter()
+        .cloned()
+        .enumerate()
+        .collect();
+        let tiles = tiles_from_array(&[&[
+            Tile {
+                owner: Some(1),
+                unit: Some(0),
+                ..hq
+            },
+            Tile {
+                owner: Some(2),
+                capture_points: 100,
+                ..hq
+            },
+            Tile {
+                unit: Some(1),
+                ..Tile::default()
+            },
+        ]]);
+        let map = Map {
+            name: "Test".into(),
+            units,
+            tiles,
+            funds: 0,
+        };
+        let rules = Rules {
+            victory_conditions: VictoryConditions {
+                hq_capture: true,
+                ..VictoryConditions::default()
+            },
+            ..Rules::default()
+        };
+        let mut game = Game::with_rules(map, &[(1, 1), (2, 2)], rules);
+        start(&mut game, &mut |_| ()).unwrap();
+        let mut replayed = game.clone();
+
+        let mut events = Vec::new();
+        let emit = &mut |e| events.push(e);
+        move_and_capture(&mut game, 0, &path(&[(0, 0), (1, 0)]), emit).unwrap();
+        end_turn(&mut game, emit).unwrap();
+
+        assert!(events.contains(&Event::Defeated(2)));
+        assert_eq!(game.units.get(1).unwrap().owner, None);
+        assert_eq!(game.state, GameState::Finished);
+        assert_eq!(events.last(), Some(&Event::WinGame(1)));
+
+        for event in events.iter() {
+            process(&mut replayed, event).unwrap();
+        }
+        assert!(replayed == game);
+    }
+
+    #[test]
+    fn test_round_limit() {
+        let city = Tile {
+            terrain: model::Terrain::City,
+            ..Tile::default()
+        };
+        let base = Tile {
+            terrain: model::Terrain::Base,
+            ..Tile::default()
+        };
+        let tiles = tiles_from_array(&[&[
+            Tile {
+                owner: Some(1),
+                ..base
+            },
+            Tile {
+                owner: Some(1),
+                ..city
+            },
+            Tile {
+                owner: Some(2),
+                ..base
+            },
+        ]]);
+        let map = Map {
+            name: "Test".into(),
+            units: HashMap::new(),
+            tiles,
+            funds: 0,
+        };
+        let rules = Rules {
+            victory_conditions: VictoryConditions {
+                round_limit: Some(2),
+                ..VictoryConditions::default()
+            },
+            ..Rules::default()
+        };
+        let mut game = Game::with_rules(map, &[(1, 1), (2, 2)], rules);
+        start(&mut game, &mut |_| ()).unwrap();
+        let mut replayed = game.clone();
+
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            end_turn(&mut game, &mut |e| events.push(e)).unwrap();
+        }
+        assert_eq!(game.state, GameState::InProgress);
+        end_turn(&mut game, &mut |e| events.push(e)).unwrap();
+        assert_eq!(game.state, GameState::Finished);
+        assert_eq!(game.round_count, 2);
+        assert_eq!(events.last(), Some(&Event::WinGame(1)));
+
+        for event in events.iter() {
+            process(&mut replayed, event).unwrap();
+        }
+        assert!(replayed == game);
+    }
 }
@@ -0,0 +1,319 @@
+//! Property tests that throw random maps and random action sequences, mostly
+//! invalid ones, at the rules engine. Any panic inside `action::perform`
+//! fails the test case, after which proptest shrinks it to a minimal one.
+use crate::auth;
+use crate::game::*;
+use crate::model::*;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+const TERRAINS: &[Terrain] = &[
+    Terrain::Road,
+    Terrain::Plains,
+    Terrain::Forest,
+    Terrain::Mountains,
+    Terrain::Water,
+    Terrain::City,
+    Terrain::Base,
+    Terrain::Fort,
+    Terrain::Airport,
+    Terrain::Port,
+    Terrain::Beach,
+    Terrain::Bridge,
+    Terrain::HQ,
+];
+
+/// Tile contents before they are laid out on the map
+#[derive(Debug, Clone)]
+struct TileSpec {
+    terrain: Terrain,
+    owner: Option<PlayerNumber>,
+    capture_points: CapturePoints,
+    unit: Option<UnitSpec>,
+}
+
+#[derive(Debug, Clone)]
+struct UnitSpec {
+    unit_type: UnitType,
+    owner: Option<PlayerNumber>,
+    health: Health,
+    deployed: bool,
+    moved: bool,
+    capturing: bool,
+    carried: Option<UnitType>,
+}
+
+fn owner(player_count: u32) -> impl Strategy<Value = Option<PlayerNumber>> {
+    // One past the player count so that maps also name players not in the game
+    proptest::option::of(1..=player_count + 1)
+}
+
+fn unit_spec(player_count: u32) -> impl Strategy<Value = UnitSpec> {
+    let unit_types: Vec<_> = enum_iterator::all::<UnitType>().collect();
+    (
+        select(unit_types.clone()),
+        owner(player_count),
+        1..=UNIT_MAX_HEALTH,
+        any::<(bool, bool, bool)>(),
+        proptest::option::weighted(0.3, select(unit_types)),
+    )
+        .prop_map(
+            |(unit_type, owner, health, (deployed, moved, capturing), carried)| UnitSpec {
+                unit_type,
+                owner,
+                health,
+                deployed,
+                moved,
+                capturing,
+                carried,
+            },
+        )
+}
+
+fn tile_spec(player_count: u32) -> impl Strategy<Value = TileSpec> {
+    (
+        select(TERRAINS),
+        owner(player_count),
+        1..=MAX_CAPTURE_POINTS,
+        proptest::option::weighted(0.4, unit_spec(player_count)),
+    )
+        .prop_map(|(terrain, owner, capture_points, unit)| TileSpec {
+            terrain,
+            owner,
+            capture_points,
+            unit,
+        })
+}
+
+/// A random rectangular map and two to four players for it. Units are only
+/// placed where they could move to, as on real maps.
+fn random_map() -> impl Strategy<Value = (Map, Vec<(PlayerNumber, auth::UserId)>)> {
+    (2u32..=4, 2i32..=6, 2i32..=6, 0u32..=20000)
+        .prop_flat_map(|(player_count, width, height, funds)| {
+            let tile_count = (width * height) as usize;
+            (
+                Just((player_count, width, funds)),
+                proptest::collection::vec(tile_spec(player_count), tile_count),
+            )
+        })
+        .prop_map(|((player_count, width, funds), specs)| {
+            let mut map = Map {
+                name: "Random".to_string(),
+                units: HashMap::new(),
+                tiles: HashMap::new(),
+                funds,
+            };
+            for (tile_id, spec) in specs.into_iter().enumerate() {
+                let mut tile = Tile {
+                    terrain: spec.terrain,
+                    x: tile_id as i32 % width,
+                    y: tile_id as i32 / width,
+                    capture_points: spec.capture_points,
+                    ..Tile::default()
+                };
+                if tile.is_capturable() {
+                    tile.owner = spec.owner;
+                }
+                if let Some(unit_spec) = spec.unit {
+                    let mut unit = Unit {
+                        unit_type: unit_spec.unit_type,
+                        owner: unit_spec.owner,
+                        health: unit_spec.health,
+                        moved: unit_spec.moved,
+                        capturing: unit_spec.capturing,
+                        ..Unit::default()
+                    };
+                    unit.deployed = unit_spec.deployed && unit.can_deploy();
+                    if unit.can_move_on_terrain(tile.terrain) {
+                        let unit_id = map.units.len();
+                        if let Some(carried_type) = unit_spec.carried {
+                            let carried = Unit {
+                                unit_type: carried_type,
+                                owner: unit.owner,
+                                ..Unit::default()
+                            };
+                            if unit.can_carry(&carried) {
+                                unit.carried.push(unit_id + 1);
+                                map.units.insert(unit_id + 1, carried);
+                            }
+                        }
+                        tile.unit = Some(unit_id);
+                        map.units.insert(unit_id, unit);
+                    }
+                }
+                map.tiles.insert(tile_id, tile);
+            }
+            let players = (1..=player_count).map(|n| (n, n.into())).collect();
+            (map, players)
+        })
+}
+
+/// A way to pick an action in whatever state the game is in. Most steps pick
+/// a legal action to move the game forward, the rest are made up of random
+/// numbers and usually fail.
+#[derive(Debug, Clone)]
+enum Step {
+    Legal(usize),
+    Arbitrary {
+        kind: u8,
+        unit: usize,
+        target: usize,
+        directions: Vec<usize>,
+        position: (i32, i32),
+        build_type: UnitType,
+    },
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    let arbitrary = (
+        any::<u8>(),
+        any::<usize>(),
+        any::<usize>(),
+        proptest::collection::vec(0usize..6, 0..6),
+        (-1i32..8, -1i32..8),
+        select(enum_iterator::all::<UnitType>().collect::<Vec<_>>()),
+    )
+        .prop_map(
+            |(kind, unit, target, directions, position, build_type)| Step::Arbitrary {
+                kind,
+                unit,
+                target,
+                directions,
+                position,
+                build_type,
+            },
+        );
+    prop_oneof![
+        3 => any::<usize>().prop_map(Step::Legal),
+        2 => arbitrary,
+    ]
+}
+
+fn choose_action(game: &Game, step: &Step) -> Action {
+    match step {
+        Step::Legal(index) => {
+            let player_number = game
+                .in_turn_number()
+                .or_else(|| game.players.iter().next().map(|p| p.number))
+                .unwrap_or(0);
+            // Leave surrendering to arbitrary steps, as it ends games quickly
+            let legal: Vec<_> = game
+                .legal_actions(player_number)
+                .into_iter()
+                .filter(|a| *a != Action::Surrender)
+                .collect();
+            if legal.is_empty() {
+                Action::EndTurn
+            } else {
+                legal[index % legal.len()].clone()
+            }
+        }
+        Step::Arbitrary {
+            kind,
+            unit,
+            target,
+            directions,
+            position,
+            build_type,
+        } => {
+            // Ids up to two past the last one so that missing units come up too
+            let unit_id = unit % (game.next_unit_id + 2);
+            let target_id = target % (game.next_unit_id + 2);
+            let start = game
+                .tiles
+                .get_unit_tile(unit_id)
+                .map(|(_, tile)| tile.position())
+                .unwrap_or(Position::from(position));
+            let mut path = vec![start];
+            for &direction in directions {
+                let next = path.last().unwrap().adjacent().nth(direction).unwrap();
+                path.push(next);
+            }
+            let position = Position::from(position);
+            match kind % 11 {
+                0 => Action::StartGame,
+                1 => Action::EndTurn,
+                2 => Action::Surrender,
+                3 => Action::Build(position, *build_type),
+                4 => Action::MoveAndWait(unit_id, path),
+                5 => Action::MoveAndAttack(unit_id, path, target_id),
+                6 => Action::MoveAndCapture(unit_id, path),
+                7 => Action::MoveAndDeploy(unit_id, path),
+                8 => Action::Undeploy(unit_id),
+                9 => Action::MoveAndLoadInto(unit_id, path),
+                _ => Action::MoveAndUnload(unit_id, path, target_id, position),
+            }
+        }
+    }
+}
+
+/// Paths to the values that differ between two serialized games
+fn differences(a: &serde_json::Value, b: &serde_json::Value, path: &str) -> Vec<String> {
+    use serde_json::Value;
+    match (a, b) {
+        (Value::Object(a_fields), Value::Object(b_fields)) => {
+            let mut keys: Vec<_> = a_fields.keys().chain(b_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            keys.into_iter()
+                .flat_map(|key| {
+                    let a = a_fields.get(key).unwrap_or(&Value::Null);
+                    let b = b_fields.get(key).unwrap_or(&Value::Null);
+                    differences(a, b, &format!("{path}.{key}"))
+                })
+                .collect()
+        }
+        (a, b) if a != b => vec![format!("{path}: {a} != {b}")],
+        _ => Vec::new(),
+    }
+}
+fn describe_differences(a: &Game, b: &Game) -> String {
+    let a = serde_json::to_value(a).unwrap();
+    let b = serde_json::to_value(b).unwrap();
+    differences(&a, &b, "game").join("\n")
+}
+
+proptest! {
+    #[test]
+    fn random_actions_keep_game_consistent(
+        (map, players) in random_map(),
+        steps in proptest::collection::vec(step(), 1..60),
+    ) {
+        let mut game = Game::new(map, &players);
+        let violations = game.integrity_violations();
+        prop_assert!(violations.is_empty(), "Initial game: {violations:?}");
+
+        for step in &steps {
+            let action = choose_action(&game, step);
+            let before = game.clone();
+            let mut events = Vec::new();
+            let result = action::perform(&mut game, action.clone(), &mut |e| events.push(e));
+
+            match result {
+                Ok(()) => {
+                    let mut replayed = before.clone();
+                    for event in &events {
+                        let processed = action::process(&mut replayed, event);
+                        prop_assert!(processed.is_ok(), "{action:?}: {event:?} failed: {processed:?}");
+                    }
+                    prop_assert!(
+                        replayed == game,
+                        "Replaying {action:?} as {events:?} differs:\n{}",
+                        describe_differences(&replayed, &game)
+                    );
+                }
+                Err(error) => {
+                    prop_assert!(events.is_empty(), "{action:?} failed with {error} after {events:?}");
+                    prop_assert!(
+                        before == game,
+                        "{action:?} failed with {error} but changed the game:\n{}",
+                        describe_differences(&before, &game)
+                    );
+                }
+            }
+
+            let violations = game.integrity_violations();
+            prop_assert!(violations.is_empty(), "After {action:?}: {violations:?}");
+        }
+    }
+}
@@ -40,45 +40,69 @@ impl From<GameUpdateError> for ActionError {
     }
 }
 
+impl Default for Rules {
+    fn default() -> Rules {
+        Rules {
+            starting_funds: None,
+            funds_per_property: FUNDS_PER_PROPERTY,
+            fog: false,
+            luck: 0,
+            victory_conditions: VictoryConditions::default(),
+            banned_unit_types: Vec::new(),
+        }
+    }
+}
+
+impl Rules {
+    /// Vary the damage randomly by up to `luck` percent, keeping it at least 1
+    pub fn apply_luck(&self, damage: Health, rng: &mut fastrand::Rng) -> Health {
+        if self.luck == 0 {
+            return damage;
+        }
+        let luck = self.luck.min(100) as i64;
+        let percent = 100 + rng.i64(-luck..=luck);
+        (damage as i64 * percent / 100).max(1) as Health
+    }
+    pub fn is_banned(&self, unit_type: UnitType) -> bool {
+        self.banned_unit_types.contains(&unit_type)
+    }
+}
+
 impl Tiles {
     pub fn rect(&self) -> Option<Rect> {
-        self.0.values().fold(None, |x, t| match x {
+        self.tiles.values().fold(None, |x, t| match x {
             Some((x0, y0, x1, y1)) => Some((x0.min(t.x), y0.min(t.y), x1.max(t.x), y1.max(t.y))),
             None => Some((t.x, t.y, t.x, t.y)),
         })
     }
     pub fn get(&self, tile_id: TileId) -> Option<Tile> {
-        self.0.get(&tile_id).cloned()
+        self.tiles.get(&tile_id).cloned()
     }
     pub fn get_unit_tile(&self, unit_id: UnitId) -> Option<(TileId, Tile)> {
-        self.0
-            .iter()
-            .filter(|&(_, t)| t.unit == Some(unit_id))
-            .only()
-            .map(|(&id, t)| (id, t.clone()))
+        let tile_id = *self.by_unit.get(&unit_id)?;
+        self.get(tile_id).map(|tile| (tile_id, tile))
     }
-    pub fn get_at(&self, &Position(x, y): &Position) -> ActionResult<(TileId, Tile)> {
-        self.0
-            .iter()
-            .filter(|&(_, t)| t.x == x && t.y == y)
-            .only()
-            .map(|(&id, t)| (id, t.clone()))
-            .ok_or(ActionError::InvalidPath)
+    pub fn get_at(&self, position: &Position) -> ActionResult<(TileId, Tile)> {
+        let tile_id = *self
+            .by_position
+            .get(position)
+            .ok_or(ActionError::InvalidPath)?;
+        let tile = self.get(tile_id).ok_or(ActionError::InvalidPath)?;
+        Ok((tile_id, tile))
     }
     pub fn get_path_tiles(&self, path: &[Position]) -> ActionResult<Vec<Tile>> {
         path.iter()
-            .map(|&Position(x, y)| self.iter().filter(|t| t.x == x && t.y == y).only().cloned())
-            .collect::<Option<Vec<Tile>>>()
-            .ok_or(ActionError::InvalidPath)
+            .map(|position| self.get_at(position).map(|(_, tile)| tile))
+            .collect()
     }
     pub fn iter(&self) -> impl Iterator<Item = &Tile> {
-        self.0.values()
+        self.tiles.values()
     }
     pub fn iter_with_ids(&self) -> impl Iterator<Item = (&TileId, &Tile)> {
-        self.0.iter()
+        self.tiles.iter()
     }
     pub fn iter_ids(&self) -> impl Iterator<Item = &TileId> {
-        self.0.keys()
+        self.tiles.keys()
     }
     pub fn owned_by_player(
         &self,
@@ -89,12 +113,52 @@ impl Tiles {
             .map(|(tile_id, tile)| (*tile_id, tile))
     }
     pub fn update(&mut self, id: TileId, tile: Tile) -> GameUpdateResult<()> {
-        let current = self.0.get_mut(&id).ok_or(GameUpdateError::InvalidTileId)?;
+        let current = self
+            .tiles
+            .get_mut(&id)
+            .ok_or(GameUpdateError::InvalidTileId)?;
+        if current.position() != tile.position() {
+            return Err(GameUpdateError::InvalidTileId);
+        }
+        // Units move by updating two tiles in either order, so only drop the
+        // index entry if it still points to this tile
+        if let Some(unit_id) = current.unit
+            && self.by_unit.get(&unit_id) == Some(&id)
+        {
+            self.by_unit.remove(&unit_id);
+        }
+        if let Some(unit_id) = tile.unit {
+            self.by_unit.insert(unit_id, id);
+        }
         *current = tile;
         Ok(())
     }
 }
 
+impl From<TileMap> for Tiles {
+    fn from(TileMap(tiles): TileMap) -> Self {
+        let by_position = tiles
+            .iter()
+            .map(|(&tile_id, tile)| (tile.position(), tile_id))
+            .collect();
+        let by_unit = tiles
+            .iter()
+            .filter_map(|(&tile_id, tile)| tile.unit.map(|unit_id| (unit_id, tile_id)))
+            .collect();
+        Tiles {
+            tiles,
+            by_position,
+            by_unit,
+        }
+    }
+}
+
+impl serde::Serialize for Tiles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("Tiles", &self.tiles)
+    }
+}
+
 impl Units {
     pub fn iter(&self) -> impl Iterator<Item = &Unit> {
         self.0.values()
@@ -127,17 +191,22 @@ impl Units {
             Err(GameUpdateError::InvalidUnitId)
         }
     }
-    pub fn insert(&mut self, unit: Unit) -> UnitId {
-        // FIXME: smarter id system
-        let unit_id = self.iter_ids().max().map(|x| x + 1).unwrap_or(0);
+    pub fn insert(&mut self, unit_id: UnitId, unit: Unit) -> GameUpdateResult<()> {
+        if self.0.contains_key(&unit_id) {
+            return Err(GameUpdateError::InvalidUnitId);
+        }
         self.0.insert(unit_id, unit);
-        unit_id
+        Ok(())
     }
+    /// Remove the unit along with any units it carries
     pub fn remove(&mut self, unit_id: UnitId) -> GameUpdateResult<()> {
-        self.0
+        let unit = self
+            .0
             .remove(&unit_id)
             .ok_or(GameUpdateError::InvalidUnitId)?;
-        Ok(())
+        unit.carried
+            .into_iter()
+            .try_for_each(|carried_id| self.remove(carried_id))
     }
 }
 
@@ -158,7 +227,10 @@ impl Players {
 }
 impl Game {
     pub fn new(map: Map, players: &[(PlayerNumber, auth::UserId)]) -> Game {
-        let &max_unit_id = { map.units.iter().map(|(id, _)| id).max().unwrap_or(&0) };
+        Game::with_rules(map, players, Rules::default())
+    }
+    pub fn with_rules(map: Map, players: &[(PlayerNumber, auth::UserId)], rules: Rules) -> Game {
+        let next_unit_id = map.units.keys().max().map(|id| id + 1).unwrap_or(0);
 
         let players = Players(
             players
@@ -166,7 +238,7 @@ impl Game {
                 .map(|(number, uid)| Player {
                     user_id: *uid,
                     number: *number,
-                    funds: map.funds,
+                    funds: rules.starting_funds.unwrap_or(map.funds),
                     score: 0,
                     alive: true,
                 })
@@ -196,14 +268,30 @@ impl Game {
         Game {
             state: GameState::Pregame,
             units: Units(units),
-            tiles: Tiles(tiles),
+            tiles: TileMap(tiles).into(),
             players,
             in_turn_index: 0,
             round_count: 0,
             turn_count: 0,
-            next_unit_id: max_unit_id + 1,
+            next_unit_id,
+            rules,
+            luck_state: random_luck_state(),
         }
     }
+    /// Apply the rules' luck to the damage with the game's own random number
+    /// generator, so that the rolls only depend on the game
+    pub fn roll_luck(&mut self, damage: Health) -> Health {
+        let mut rng = fastrand::Rng::with_seed(self.luck_state);
+        let damage = self.rules.apply_luck(damage, &mut rng);
+        self.luck_state = rng.get_seed();
+        damage
+    }
+    /// The game without the state of its luck, to show it to those who
+    /// shouldn't foresee the rolls
+    pub fn without_luck_state(mut self) -> Game {
+        self.luck_state = 0;
+        self
+    }
 
     // Mutators
 
@@ -219,6 +307,17 @@ impl Game {
             .ok_or(GameUpdateError::InvalidPlayerNumber)?;
         Ok(())
     }
+    /// Give the turn to `player_number`, counting a new round whenever the
+    /// turn order wraps around.
+    pub fn advance_turn(&mut self, player_number: PlayerNumber) -> GameUpdateResult<()> {
+        let previous_in_turn_index = self.in_turn_index;
+        self.set_player_in_turn(player_number)?;
+        if self.turn_count == 0 || self.in_turn_index <= previous_in_turn_index {
+            self.round_count += 1;
+        }
+        self.turn_count += 1;
+        Ok(())
+    }
     pub fn set_state(&mut self, state: GameState) -> GameUpdateResult<()> {
         match (&self.state, &state) {
             (GameState::Pregame, GameState::InProgress) => Ok(()),
@@ -229,6 +328,47 @@ impl Game {
         self.state = state;
         Ok(())
     }
+    /// Add a new unit using the next free unit id. Ids are never reused, even
+    /// after the unit holding the highest id has been destroyed.
+    pub fn add_unit(&mut self, unit: Unit) -> GameUpdateResult<UnitId> {
+        let unit_id = self.next_unit_id;
+        self.units.insert(unit_id, unit)?;
+        self.next_unit_id += 1;
+        Ok(unit_id)
+    }
+    /// Make sure `next_unit_id` is past every current unit and every id in
+    /// `used`. Needed for games saved before unit ids were allocated from
+    /// `next_unit_id`, where the ids of destroyed units are only known from
+    /// the event log.
+    pub fn reserve_unit_ids(&mut self, used: impl IntoIterator<Item = UnitId>) {
+        let max_unit_id = self.units.iter_ids().copied().chain(used).max();
+        if let Some(max_unit_id) = max_unit_id {
+            self.next_unit_id = self.next_unit_id.max(max_unit_id + 1);
+        }
+    }
+    /// Players stay alive as long as they have units or tiles to build on
+    pub fn update_alive_players(&mut self) -> GameUpdateResult<()> {
+        let players_with_units = self.players_with_units();
+        let players_with_build_tiles = self.players_with_build_tiles();
+
+        let updated_players: Vec<_> = self
+            .players
+            .iter()
+            .filter_map(|p| {
+                let alive = players_with_units.contains(&p.number)
+                    || players_with_build_tiles.contains(&p.number);
+                if p.alive != alive {
+                    Some(Player { alive, ..*p })
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        updated_players
+            .into_iter()
+            .try_for_each(|p| self.players.update(p))
+    }
     pub fn update_tiles_and_units(
         &mut self,
         tiles: impl IntoIterator<Item = (TileId, Tile)>,
@@ -517,15 +657,136 @@ impl Game {
 
         Ok(())
     }
+    /// Every action the unit can take, by the position the unit ends up in.
+    /// Undeploying is listed under the unit's current position and loading
+    /// into a carrier under the carrier's position.
+    pub fn unit_actions(&self, unit_id: UnitId) -> HashMap<Position, Vec<Action>> {
+        let mut result: HashMap<Position, Vec<Action>> = HashMap::new();
+        let Some(unit) = self.units.get_ref(&unit_id) else {
+            return result;
+        };
+        if self.unit_has_turn(unit).is_err() {
+            return result;
+        }
+        let (Some((_, unit_tile)), Some(move_options)) = (
+            self.tiles.get_unit_tile(unit_id),
+            self.unit_move_options(unit_id),
+        ) else {
+            return result;
+        };
+
+        for (destination, path) in move_options {
+            let mut actions = Vec::new();
+            if self.unit_can_stay_at(unit_id, &destination).is_ok() {
+                let Ok((tile_id, _)) = self.tiles.get_at(&destination) else {
+                    continue;
+                };
+                actions.push(Action::MoveAndWait(unit_id, path.clone()));
+
+                let mut target_ids: Vec<_> = self
+                    .unit_attack_options(unit_id, &destination)
+                    .into_keys()
+                    .collect();
+                target_ids.sort();
+                actions.extend(
+                    target_ids
+                        .into_iter()
+                        .map(|target_id| Action::MoveAndAttack(unit_id, path.clone(), target_id)),
+                );
+
+                if self.unit_can_capture_tile(unit_id, tile_id).is_ok() {
+                    actions.push(Action::MoveAndCapture(unit_id, path.clone()));
+                }
+                if unit.can_deploy() && !unit.deployed {
+                    actions.push(Action::MoveAndDeploy(unit_id, path.clone()));
+                }
+                for &carried_id in unit.carried.iter() {
+                    let mut unload_positions: Vec<_> = self
+                        .unit_unload_options(unit_id, &destination, carried_id)
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    unload_positions.sort();
+                    actions.extend(unload_positions.into_iter().map(|unload_position| {
+                        Action::MoveAndUnload(unit_id, path.clone(), carried_id, unload_position)
+                    }));
+                }
+            } else if self.unit_can_load_into_carrier_at(unit_id, &destination) {
+                actions.push(Action::MoveAndLoadInto(unit_id, path));
+            }
+            result.entry(destination).or_default().extend(actions);
+        }
+
+        if unit.deployed {
+            result
+                .entry(unit_tile.position())
+                .or_default()
+                .push(Action::Undeploy(unit_id));
+        }
+        result
+    }
+    /// Every unit the player can build, on every base they can build it on
+    pub fn build_actions(&self, player_number: PlayerNumber) -> Vec<Action> {
+        if self.in_turn_number() != Some(player_number) {
+            return Vec::new();
+        }
+        let funds = self.get_player(player_number).map(|p| p.funds).unwrap_or(0);
+        let mut bases: Vec<_> = self
+            .tiles
+            .owned_by_player(player_number)
+            .filter(|(_, tile)| tile.unit.is_none())
+            .map(|(_, tile)| tile)
+            .collect();
+        bases.sort_by_key(|tile| tile.position());
+        bases
+            .into_iter()
+            .flat_map(|tile| {
+                enum_iterator::all::<UnitType>()
+                    .filter(|&build_type| tile.can_build(build_type))
+                    .filter(|&build_type| !self.rules.is_banned(build_type))
+                    .filter(move |&build_type| unit_type(build_type).price <= funds)
+                    .map(|build_type| Action::Build(tile.position(), build_type))
+            })
+            .collect()
+    }
+    /// Every action the player can perform. Unit actions come first, by unit
+    /// id and destination, then builds, then ending the turn and surrendering.
+    pub fn legal_actions(&self, player_number: PlayerNumber) -> Vec<Action> {
+        match self.state {
+            GameState::Pregame => return vec![Action::StartGame],
+            GameState::Finished => return Vec::new(),
+            GameState::InProgress if self.in_turn_number() != Some(player_number) => {
+                return Vec::new();
+            }
+            GameState::InProgress => (),
+        }
+
+        let mut unit_ids: Vec<_> = self
+            .units
+            .owned_by_player(player_number)
+            .map(|(unit_id, _)| unit_id)
+            .collect();
+        unit_ids.sort();
+
+        let mut actions = Vec::new();
+        for unit_id in unit_ids {
+            let mut unit_actions: Vec<_> = self.unit_actions(unit_id).into_iter().collect();
+            unit_actions.sort_by_key(|(destination, _)| *destination);
+            actions.extend(unit_actions.into_iter().flat_map(|(_, actions)| actions));
+        }
+        actions.extend(self.build_actions(player_number));
+        actions.extend([Action::EndTurn, Action::Surrender]);
+        actions
+    }
     pub fn in_turn_number(&self) -> Option<PlayerNumber> {
         match self.state {
-            GameState::InProgress => Some(self.players.0[self.in_turn_index].number),
+            GameState::InProgress => Some(self.players.0.get(self.in_turn_index)?.number),
             _ => None,
         }
     }
     pub fn in_turn_player(&self) -> Option<Player> {
         if self.state == GameState::InProgress {
-            Some(self.players.0.get(self.in_turn_index).unwrap().clone())
+            self.players.0.get(self.in_turn_index).cloned()
         } else {
             None
         }
@@ -539,12 +800,11 @@ impl Game {
             .next()
     }
     pub fn next_player_number(&self) -> Option<PlayerNumber> {
-        let next_in_turn_index = (0..self.players.0.len())
-            .map(|i| (i + 1 + self.in_turn_index) % self.players.0.len())
-            .filter(|i| self.players.0.get(*i).unwrap().alive)
-            .next()?;
-
-        Some(self.players.0.get(next_in_turn_index).unwrap().number)
+        let player_count = self.players.0.len();
+        (0..player_count)
+            .map(|i| &self.players.0[(i + 1 + self.in_turn_index) % player_count])
+            .find(|p| p.alive)
+            .map(|p| p.number)
     }
     pub fn players_with_units(&self) -> HashSet<PlayerNumber> {
         self.units.iter().filter_map(|u| u.owner).collect()
@@ -577,12 +837,205 @@ impl Game {
             None
         }
     }
+    /// Whether giving the turn to `player_number` would start a round past
+    /// the round limit
+    pub fn round_limit_reached(&self, player_number: PlayerNumber) -> bool {
+        let Some(round_limit) = self.rules.victory_conditions.round_limit else {
+            return false;
+        };
+        let starts_round = self
+            .players
+            .iter()
+            .position(|p| p.number == player_number)
+            .is_some_and(|index| index <= self.in_turn_index);
+        starts_round && self.round_count >= round_limit
+    }
+    /// The alive player holding the most properties, unless there is a tie
+    pub fn property_leader(&self) -> Option<PlayerNumber> {
+        let mut property_counts: Vec<_> = self
+            .players
+            .iter()
+            .filter(|p| p.alive)
+            .map(|p| {
+                let count = self
+                    .tiles
+                    .owned_by_player(p.number)
+                    .filter(|(_, tile)| tile.is_capturable())
+                    .count();
+                (count, p.number)
+            })
+            .collect();
+        property_counts.sort();
+        match property_counts.as_slice() {
+            [.., (second, _), (first, _)] if first == second => None,
+            [.., (_, leader)] => Some(*leader),
+            [] => None,
+        }
+    }
+    /// Positions within sight of the player's units and properties
+    pub fn visible_positions(&self, player_number: PlayerNumber) -> HashSet<Position> {
+        let unit_positions = self
+            .units
+            .owned_by_player(player_number)
+            .filter_map(|(unit_id, _)| self.tiles.get_unit_tile(unit_id))
+            .map(|(_, tile)| (tile.position(), UNIT_VISION_RANGE));
+        let property_positions = self
+            .tiles
+            .owned_by_player(player_number)
+            .map(|(_, tile)| (tile.position(), PROPERTY_VISION_RANGE));
+        let sights: Vec<_> = unit_positions.chain(property_positions).collect();
+        self.tiles
+            .iter()
+            .map(|tile| tile.position())
+            .filter(|position| {
+                sights
+                    .iter()
+                    .any(|(sight, range)| sight.distance_to(position) <= *range)
+            })
+            .collect()
+    }
+    /// The game as the player sees it, or as an outsider sees it with `None`.
+    /// In fog of war, other players' units out of sight are left out.
+    pub fn view_for(&self, player_number: Option<PlayerNumber>) -> Game {
+        let mut game = self.clone().without_luck_state();
+        if !self.rules.fog {
+            return game;
+        }
+        let visible_positions = player_number
+            .map(|pn| self.visible_positions(pn))
+            .unwrap_or_default();
+        let hidden: Vec<_> = self
+            .tiles
+            .iter_with_ids()
+            .filter(|(_, tile)| !visible_positions.contains(&tile.position()))
+            .filter_map(|(&tile_id, tile)| Some((tile_id, tile, tile.unit?)))
+            .filter(|(_, _, unit_id)| {
+                self.units
+                    .get_ref(unit_id)
+                    .is_some_and(|unit| unit.owner.is_some() && unit.owner != player_number)
+            })
+            .map(|(tile_id, tile, unit_id)| {
+                (
+                    tile_id,
+                    Tile {
+                        unit: None,
+                        ..*tile
+                    },
+                    unit_id,
+                )
+            })
+            .collect();
+        for (tile_id, tile, unit_id) in hidden {
+            // Neither can fail, the ids come from the game itself
+            let _ = game.tiles.update(tile_id, tile);
+            let _ = game.units.remove(unit_id);
+        }
+        game
+    }
+    /// Descriptions of everything wrong with the game state. Actions keep a
+    /// consistent game consistent, so this is empty unless there's a bug.
+    pub fn integrity_violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        let mut placements: HashMap<UnitId, usize> = HashMap::new();
+        for (&tile_id, tile) in self.tiles.iter_with_ids() {
+            if self.tiles.by_position.get(&tile.position()) != Some(&tile_id) {
+                violations.push(format!("Tile {tile_id} is not indexed by its position"));
+            }
+            if tile.capture_points == 0 || tile.capture_points > tile.max_capture_points() {
+                violations.push(format!(
+                    "Tile {tile_id} has {} capture points",
+                    tile.capture_points
+                ));
+            }
+            if tile
+                .owner
+                .is_some_and(|owner| self.get_player(owner).is_none())
+            {
+                violations.push(format!("Tile {tile_id} is owned by a missing player"));
+            }
+            let Some(unit_id) = tile.unit else {
+                continue;
+            };
+            *placements.entry(unit_id).or_default() += 1;
+            if self.tiles.by_unit.get(&unit_id) != Some(&tile_id) {
+                violations.push(format!("Unit {unit_id} is not indexed on tile {tile_id}"));
+            }
+            match self.units.get_ref(&unit_id) {
+                Some(unit) if !unit.can_move_on_terrain(tile.terrain) => {
+                    violations.push(format!("Unit {unit_id} is on impassable tile {tile_id}"))
+                }
+                Some(_) => (),
+                None => violations.push(format!("Tile {tile_id} has missing unit {unit_id}")),
+            }
+        }
+        if self.tiles.by_position.len() != self.tiles.tiles.len()
+            || self.tiles.by_unit.len() != self.tiles.iter().filter(|t| t.unit.is_some()).count()
+        {
+            violations.push("Tile indexes have stale entries".to_string());
+        }
+
+        for (&carrier_id, carrier) in self.units.iter_with_ids() {
+            if carrier.carried.len() as u32 > carrier.unit_type_data().carry_num {
+                violations.push(format!("Unit {carrier_id} carries too many units"));
+            }
+            for carried_id in &carrier.carried {
+                *placements.entry(*carried_id).or_default() += 1;
+                match self.units.get_ref(carried_id) {
+                    Some(carried) if carried.owner != carrier.owner => violations.push(format!(
+                        "Unit {carrier_id} carries unit {carried_id} of another player"
+                    )),
+                    Some(_) => (),
+                    None => violations.push(format!(
+                        "Unit {carrier_id} carries missing unit {carried_id}"
+                    )),
+                }
+            }
+        }
+
+        for (&unit_id, unit) in self.units.iter_with_ids() {
+            match placements.get(&unit_id) {
+                Some(1) => (),
+                Some(_) => violations.push(format!("Unit {unit_id} is in several places")),
+                None => violations.push(format!("Unit {unit_id} is nowhere")),
+            }
+            if unit.health == 0 || unit.health > UNIT_MAX_HEALTH {
+                violations.push(format!("Unit {unit_id} has {} health", unit.health));
+            }
+            if unit_id >= self.next_unit_id {
+                violations.push(format!("Unit {unit_id} is past the next unit id"));
+            }
+            if unit
+                .owner
+                .is_some_and(|owner| self.get_player(owner).is_none())
+            {
+                violations.push(format!("Unit {unit_id} is owned by a missing player"));
+            }
+        }
+
+        let player_numbers: HashSet<_> = self.players.iter().map(|p| p.number).collect();
+        if player_numbers.len() != self.players.0.len() {
+            violations.push("Player numbers are not unique".to_string());
+        }
+        match self.state {
+            GameState::InProgress if self.in_turn_index >= self.players.0.len() => {
+                violations.push("Player in turn is missing".to_string())
+            }
+            GameState::Finished if self.players.iter().filter(|p| p.alive).count() > 1 => {
+                violations.push("Finished game has several players alive".to_string())
+            }
+            _ => (),
+        }
+
+        violations
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::game::*;
     const THIRD_PARTY_MAP: &str = include_str!("../../data/maps/third_party.json");
+    const U_TURN_MAP: &str = include_str!("../../data/maps/u-turn.json");
 
     #[test]
     fn third_party_map_rect() {
@@ -605,6 +1058,50 @@ mod test {
         println!("{}", game.ascii_representation());
     }
 
+    #[test]
+    fn fog_hides_units_out_of_sight() {
+        let map = Map::from_json(THIRD_PARTY_MAP).unwrap();
+        let rules = Rules {
+            fog: true,
+            ..Rules::default()
+        };
+        let game = Game::with_rules(map, &[(1, 1), (2, 2)], rules);
+        let visible_positions = game.visible_positions(1);
+
+        let view = game.view_for(Some(1));
+        assert!(view.units.owned_by_player(1).count() == game.units.owned_by_player(1).count());
+        for (unit_id, _) in game.units.owned_by_player(2) {
+            let (_, tile) = game.tiles.get_unit_tile(unit_id).unwrap();
+            let seen = visible_positions.contains(&tile.position());
+            assert_eq!(view.units.get(unit_id).is_some(), seen);
+        }
+        assert!(view.units.iter().count() < game.units.iter().count());
+        assert!(view.integrity_violations().is_empty());
+        assert!(
+            game.view_for(None)
+                .units
+                .owned_by_player(1)
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn luck_only_depends_on_the_game() {
+        let map = Map::from_json(THIRD_PARTY_MAP).unwrap();
+        let rules = Rules {
+            luck: 50,
+            ..Rules::default()
+        };
+        let mut game = Game::with_rules(map, &[(1, 1), (2, 2)], rules);
+        let mut copy = game.clone();
+        let rolls: Vec<_> = (0..10).map(|_| game.roll_luck(50)).collect();
+        let copy_rolls: Vec<_> = (0..10).map(|_| copy.roll_luck(50)).collect();
+        assert_eq!(rolls, copy_rolls);
+        assert!(rolls.iter().any(|&damage| damage != rolls[0]));
+        assert_eq!(game.view_for(Some(1)).luck_state, 0);
+    }
+
     #[test]
     fn position_distance() {
         assert!(Position(0, 0).distance_to(&Position(0, 1)) == 1);
@@ -635,4 +1132,154 @@ mod test {
         assert!(Position(0, 0).distance_to(&Position(-2, 1)) == 2);
         assert!(Position(0, 0).distance_to(&Position(-1, 2)) == 2);
     }
+
+    /// Play random turns and actions from the start of a map
+    fn random_game(map_json: &str, seed: u64, turns: u32, actions: usize) -> Game {
+        let map = Map::from_json(map_json).unwrap();
+        let mut players: Vec<_> = map.player_numbers().into_iter().map(|p| (p, 0)).collect();
+        players.sort();
+        let mut game = Game::new(map, &players);
+        action::start(&mut game, &mut |_| ()).unwrap();
+
+        let mut bot = crate::bot::RandomBot::with_seed(seed);
+        for _ in 0..turns {
+            if game.state != GameState::InProgress {
+                break;
+            }
+            crate::bot::play_turn(&mut bot, &mut game, &mut |_| ()).unwrap();
+        }
+
+        let mut rng = fastrand::Rng::with_seed(seed);
+        for _ in 0..actions {
+            let Some(player_number) = game.in_turn_number() else {
+                break;
+            };
+            let unit_actions: Vec<_> = game
+                .legal_actions(player_number)
+                .into_iter()
+                .filter(|a| !matches!(a, Action::EndTurn | Action::Surrender))
+                .collect();
+            let Some(chosen) = rng.choice(unit_actions) else {
+                break;
+            };
+            action::perform(&mut game, chosen, &mut |_| ()).unwrap();
+        }
+        game
+    }
+
+    /// Shortest path between two positions that ignores terrain and units
+    fn straight_path(from: Position, to: Position) -> Vec<Position> {
+        let mut path = vec![from];
+        while *path.last().unwrap() != to {
+            let next = path
+                .last()
+                .unwrap()
+                .adjacent()
+                .min_by_key(|p| p.distance_to(&to))
+                .unwrap();
+            path.push(next);
+        }
+        path
+    }
+
+    /// Actions that might be possible: every kind of action of every unit to
+    /// every position near it, and every build everywhere
+    fn action_space(game: &Game) -> Vec<Action> {
+        let mut actions = vec![Action::StartGame, Action::EndTurn, Action::Surrender];
+        let positions: Vec<_> = game.tiles.iter().map(|t| t.position()).collect();
+
+        for (&unit_id, unit) in game.units.iter_with_ids() {
+            actions.push(Action::Undeploy(unit_id));
+            let Some((_, tile)) = game.tiles.get_unit_tile(unit_id) else {
+                continue;
+            };
+            let reach = unit.unit_type_data().movement.min(4) + 1;
+            for &destination in positions
+                .iter()
+                .filter(|p| p.distance_to(&tile.position()) <= reach)
+            {
+                let path = straight_path(tile.position(), destination);
+                actions.push(Action::MoveAndWait(unit_id, path.clone()));
+                actions.push(Action::MoveAndCapture(unit_id, path.clone()));
+                actions.push(Action::MoveAndDeploy(unit_id, path.clone()));
+                actions.push(Action::MoveAndLoadInto(unit_id, path.clone()));
+                for (&target_id, _) in game.units.iter_with_ids() {
+                    actions.push(Action::MoveAndAttack(unit_id, path.clone(), target_id));
+                }
+                for &carried_id in unit.carried.iter() {
+                    for unload_position in destination.adjacent() {
+                        actions.push(Action::MoveAndUnload(
+                            unit_id,
+                            path.clone(),
+                            carried_id,
+                            unload_position,
+                        ));
+                    }
+                }
+            }
+        }
+        for &position in &positions {
+            actions.extend(
+                enum_iterator::all::<UnitType>()
+                    .map(|build_type| Action::Build(position, build_type)),
+            );
+        }
+        actions
+    }
+
+    /// The action with its path replaced by the path's end points, as there
+    /// are many paths to the same destination
+    fn without_path(action: &Action) -> Action {
+        let ends = |path: &Vec<Position>| -> Vec<Position> {
+            path.first()
+                .into_iter()
+                .chain(path.last())
+                .copied()
+                .collect()
+        };
+        match action {
+            Action::MoveAndWait(u, path) => Action::MoveAndWait(*u, ends(path)),
+            Action::MoveAndAttack(u, path, t) => Action::MoveAndAttack(*u, ends(path), *t),
+            Action::MoveAndCapture(u, path) => Action::MoveAndCapture(*u, ends(path)),
+            Action::MoveAndDeploy(u, path) => Action::MoveAndDeploy(*u, ends(path)),
+            Action::MoveAndLoadInto(u, path) => Action::MoveAndLoadInto(*u, ends(path)),
+            Action::MoveAndUnload(u, path, c, p) => Action::MoveAndUnload(*u, ends(path), *c, *p),
+            other => other.clone(),
+        }
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(12))]
+        #[test]
+        fn legal_actions_agree_with_perform(
+            map_json in proptest::sample::select(vec![THIRD_PARTY_MAP, U_TURN_MAP]),
+            seed: u64,
+            turns in 0u32..8,
+            actions in 0usize..6,
+        ) {
+            let game = random_game(map_json, seed, turns, actions);
+            let legal: Vec<Action> = game
+                .players
+                .iter()
+                .flat_map(|p| game.legal_actions(p.number))
+                .collect();
+
+            for legal_action in &legal {
+                let mut copy = game.clone();
+                let result = action::perform(&mut copy, legal_action.clone(), &mut |_| ());
+                proptest::prop_assert!(result.is_ok(), "{legal_action:?} failed: {result:?}");
+            }
+
+            let legal: Vec<Action> = legal.iter().map(without_path).collect();
+            for candidate in action_space(&game) {
+                let mut copy = game.clone();
+                if action::perform(&mut copy, candidate.clone(), &mut |_| ()).is_ok() {
+                    proptest::prop_assert!(
+                        legal.contains(&without_path(&candidate)),
+                        "{candidate:?} succeeded but is not listed"
+                    );
+                }
+            }
+        }
+    }
 }
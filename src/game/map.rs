@@ -3,15 +3,51 @@ use crate::util::*;
 use serde_json;
 
 impl Map {
-    pub fn from_json(data: &str) -> Result<Map, serde_json::Error> {
+    pub fn from_json(data: &str) -> Result<Map, MapError> {
         let map_data: JsonMap = serde_json::from_str(data)?;
-        Ok(map_data.into_map())
+        map_data.into_map()
     }
     pub fn player_numbers(&self) -> Vec<u32> {
         let tile_owners = self.tiles.values().filter_map(|t| t.owner);
         let unit_owners = self.units.values().filter_map(|u| u.owner);
         tile_owners.chain(unit_owners).unique().collect()
     }
+    /// Width and height of the map in tiles
+    pub fn size(&self) -> (i32, i32) {
+        Tiles::from(TileMap(self.tiles.clone()))
+            .rect()
+            .map_or((0, 0), |(x0, y0, x1, y1)| (x1 - x0 + 1, y1 - y0 + 1))
+    }
+    /// Check that a game can be played on the map
+    pub fn validate(&self) -> Result<(), MapError> {
+        if self.name.trim().is_empty() {
+            return Err(MapError::NoName);
+        }
+        if self.tiles.is_empty() {
+            return Err(MapError::NoTiles);
+        }
+        let mut positions = std::collections::HashSet::new();
+        for tile in self.tiles.values() {
+            if !positions.insert((tile.x, tile.y)) {
+                return Err(MapError::OverlappingTiles(tile.x, tile.y));
+            }
+        }
+        let (width, height) = self.size();
+        if width > MAX_MAP_SIZE || height > MAX_MAP_SIZE {
+            return Err(MapError::TooLarge);
+        }
+        let player_numbers = self.player_numbers();
+        if !(2..=MAX_PLAYERS).contains(&player_numbers.len()) {
+            return Err(MapError::InvalidPlayerCount);
+        }
+        if let Some(&player_number) = player_numbers
+            .iter()
+            .find(|&&number| number as usize > MAX_PLAYERS)
+        {
+            return Err(MapError::InvalidPlayerNumber(player_number));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,16 +71,19 @@ struct JsonMapTile {
 }
 
 impl JsonMapTile {
-    fn as_tile(&self, unit_id: UnitId) -> Tile {
+    fn as_tile(&self, unit_id: UnitId) -> Result<Tile, MapError> {
         let terrain = {
             use model::Terrain::*;
             [
                 Road, Plains, Forest, Mountains, Water, City, Base, Fort, Airport, Port, Beach,
                 Bridge, HQ,
-            ][self.tile_type as usize]
+            ]
+            .get(self.tile_type as usize)
+            .copied()
+            .ok_or(MapError::UnknownTerrain(self.tile_type))?
         };
 
-        Tile {
+        Ok(Tile {
             terrain,
             terrain_subtype_id: self.tile_subtype,
             owner: if self.owner != 0 {
@@ -56,7 +95,7 @@ impl JsonMapTile {
             y: self.y,
             unit: self.unit.as_ref().map(|_| unit_id),
             ..Tile::default()
-        }
+        })
     }
 }
 
@@ -68,7 +107,7 @@ struct JsonMapUnit {
 }
 
 impl JsonMapUnit {
-    fn as_unit(&self) -> Unit {
+    fn as_unit(&self) -> Result<Unit, MapError> {
         let unit_type = {
             use model::UnitType::*;
             [
@@ -92,9 +131,12 @@ impl JsonMapUnit {
                 GunBoat,
                 AABoat,
                 Cruiser,
-            ][self.unit_type as usize]
+            ]
+            .get(self.unit_type as usize)
+            .copied()
+            .ok_or(MapError::UnknownUnitType(self.unit_type))?
         };
-        Unit {
+        Ok(Unit {
             unit_type,
             owner: if self.owner != 0 {
                 Some(self.owner)
@@ -102,32 +144,32 @@ impl JsonMapUnit {
                 None
             },
             ..Unit::default()
-        }
+        })
     }
 }
 impl JsonMap {
-    fn into_map(self) -> Map {
+    fn into_map(self) -> Result<Map, MapError> {
         let tiles = {
             self.map_data
                 .iter()
                 .enumerate()
-                .map(|(i, t)| (i, t.as_tile(i)))
-                .collect()
+                .map(|(i, t)| Ok((i, t.as_tile(i)?)))
+                .collect::<Result<_, MapError>>()?
         };
         let units = {
             self.map_data
                 .iter()
                 .enumerate()
-                .filter_map(|(i, t)| t.unit.as_ref().map(|u| (i, u.as_unit())))
-                .collect()
+                .filter_map(|(i, t)| t.unit.as_ref().map(|u| Ok((i, u.as_unit()?))))
+                .collect::<Result<_, MapError>>()?
         };
 
-        Map {
+        Ok(Map {
             name: self.name,
             funds: self.funds,
             units,
             tiles,
-        }
+        })
     }
 }
 #[cfg(test)]
@@ -167,4 +209,57 @@ mod test {
             unit.owner
         );
     }
+
+    #[test]
+    fn bundled_maps_are_valid() {
+        let maps_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("data/maps");
+        for entry in std::fs::read_dir(maps_dir).unwrap() {
+            let path = entry.unwrap().path();
+            // Files that don't parse are skipped when the maps are loaded
+            let Ok(map) = Map::from_json(&std::fs::read_to_string(&path).unwrap()) else {
+                continue;
+            };
+            assert!(map.validate().is_ok(), "{} is invalid", path.display());
+        }
+    }
+
+    #[test]
+    fn invalid_maps() {
+        let tile = |x, terrain, owner| {
+            format!(r#"{{"x": {x}, "y": 0, "type": {terrain}, "subtype": 0, "owner": {owner}}}"#)
+        };
+        let map = |tiles: &[String]| {
+            format!(
+                r#"{{"name": "Test", "funds": 0, "mapData": [{}]}}"#,
+                tiles.join(",")
+            )
+        };
+
+        let valid = map(&[tile(0, 12, 1), tile(1, 12, 2)]);
+        assert!(Map::from_json(&valid).unwrap().validate().is_ok());
+
+        let unknown_terrain = map(&[tile(0, 99, 1), tile(1, 12, 2)]);
+        assert!(matches!(
+            Map::from_json(&unknown_terrain),
+            Err(MapError::UnknownTerrain(99))
+        ));
+
+        let one_player = map(&[tile(0, 12, 1), tile(1, 1, 0)]);
+        assert!(matches!(
+            Map::from_json(&one_player).unwrap().validate(),
+            Err(MapError::InvalidPlayerCount)
+        ));
+
+        let overlapping = map(&[tile(0, 12, 1), tile(0, 12, 2)]);
+        assert!(matches!(
+            Map::from_json(&overlapping).unwrap().validate(),
+            Err(MapError::OverlappingTiles(0, 0))
+        ));
+
+        let too_wide = map(&[tile(0, 12, 1), tile(MAX_MAP_SIZE, 12, 2)]);
+        assert!(matches!(
+            Map::from_json(&too_wide).unwrap().validate(),
+            Err(MapError::TooLarge)
+        ));
+    }
 }
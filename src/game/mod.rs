@@ -3,6 +3,8 @@ use crate::model;
 use std::collections::HashMap;
 
 pub mod action;
+#[cfg(test)]
+mod fuzz;
 mod game;
 mod map;
 mod tile;
@@ -14,6 +16,10 @@ pub type TileId = usize;
 pub type PlayerNumber = u32;
 pub type TerrainSubtypeId = u32;
 pub type Rect = (i32, i32, i32, i32);
+/// Most tiles a map may have across and down
+pub const MAX_MAP_SIZE: i32 = 100;
+/// Most players a map may have, numbered from 1
+pub const MAX_PLAYERS: usize = 8;
 pub type Health = u32;
 pub type Credits = u32;
 pub type CapturePoints = u32;
@@ -25,17 +31,54 @@ pub enum GameState {
     Finished,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Tiles(HashMap<TileId, Tile>);
-#[derive(Serialize, Deserialize, Clone)]
+/// Tiles by id, indexed by position and by the unit on them so that lookups
+/// stay cheap during searches. Serialized as the plain map of tiles.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(from = "TileMap")]
+pub struct Tiles {
+    tiles: HashMap<TileId, Tile>,
+    by_position: HashMap<Position, TileId>,
+    by_unit: HashMap<UnitId, TileId>,
+}
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Tiles")]
+struct TileMap(HashMap<TileId, Tile>);
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Units(HashMap<UnitId, Unit>);
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Players(pub Vec<Player>);
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Position(pub i32, pub i32);
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Rules of a game that can differ from game to game
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Rules {
+    /// Funds players start with instead of the map's funds
+    pub starting_funds: Option<Credits>,
+    /// Funds a fully held property generates each turn
+    pub funds_per_property: Credits,
+    /// Players only see enemy units near their own units and properties
+    pub fog: bool,
+    /// Attack damage varies randomly by up to this many percent either way
+    pub luck: u32,
+    pub victory_conditions: VictoryConditions,
+    /// Unit types nobody can build
+    pub banned_unit_types: Vec<UnitType>,
+}
+
+/// Ways to win besides being the last player standing
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct VictoryConditions {
+    /// Players whose HQ is captured are defeated
+    pub hq_capture: bool,
+    /// After this many rounds the player with the most properties wins
+    pub round_limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Game {
     pub state: GameState,
     pub units: Units,
@@ -45,9 +88,19 @@ pub struct Game {
     pub round_count: u32,
     pub turn_count: u32,
     pub next_unit_id: UnitId,
+    #[serde(default)]
+    pub rules: Rules,
+    /// State of the random number generator for luck, advanced on every roll.
+    /// Views of the game leave it out so that nobody can foresee the rolls.
+    #[serde(default = "random_luck_state")]
+    pub luck_state: u64,
+}
+
+fn random_luck_state() -> u64 {
+    fastrand::u64(..)
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Player {
     pub user_id: auth::UserId,
     pub number: PlayerNumber,
@@ -56,7 +109,7 @@ pub struct Player {
     pub alive: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Tile {
     pub terrain: model::Terrain,
     pub terrain_subtype_id: TerrainSubtypeId,
@@ -67,7 +120,7 @@ pub struct Tile {
     pub y: i32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Unit {
     pub unit_type: model::UnitType,
     pub health: Health,
@@ -86,7 +139,7 @@ pub struct Map {
     pub funds: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Action {
     StartGame,
     EndTurn,
@@ -132,6 +185,8 @@ pub enum ActionError {
     CannotUnload,
     #[error("Cannot build")]
     CannotBuild,
+    #[error("Unit type is banned")]
+    UnitTypeBanned,
     #[error("Insufficient funds")]
     InsufficientFunds,
     #[error("Cannot attack")]
@@ -158,6 +213,29 @@ pub enum GameUpdateError {
     InvalidTileId,
 }
 pub type GameUpdateResult<T> = Result<T, GameUpdateError>;
+
+/// Why a map can't be played on
+#[derive(thiserror::Error, Debug)]
+pub enum MapError {
+    #[error("Invalid map file: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("Unknown terrain type {0}")]
+    UnknownTerrain(u32),
+    #[error("Unknown unit type {0}")]
+    UnknownUnitType(u32),
+    #[error("Map has no name")]
+    NoName,
+    #[error("Map has no tiles")]
+    NoTiles,
+    #[error("More than one tile at ({0}, {1})")]
+    OverlappingTiles(i32, i32),
+    #[error("Map is larger than {MAX_MAP_SIZE}x{MAX_MAP_SIZE} tiles")]
+    TooLarge,
+    #[error("Map needs 2 to {MAX_PLAYERS} players")]
+    InvalidPlayerCount,
+    #[error("Invalid player number {0}")]
+    InvalidPlayerNumber(PlayerNumber),
+}
 pub type ActionResult<T> = Result<T, ActionError>;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -181,4 +259,6 @@ pub enum Event {
     Captured(UnitId, TileId, Option<PlayerNumber>),
     Build(TileId, UnitId, UnitType, Credits),
     TileCapturePointRegen(TileId, CapturePoints),
+    Draw,
+    Defeated(PlayerNumber),
 }
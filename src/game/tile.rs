@@ -33,9 +33,9 @@ impl Tile {
     pub fn repair_rate(&self) -> Health {
         UNIT_MAX_REPAIR_RATE * self.capture_points / MAX_CAPTURE_POINTS
     }
-    pub fn generated_funds(&self) -> Credits {
+    pub fn generated_funds(&self, funds_per_property: Credits) -> Credits {
         if self.has_terrain_flag(TerrainFlag::Funds) {
-            FUNDS_PER_PROPERTY * self.capture_points / MAX_CAPTURE_POINTS
+            funds_per_property * self.capture_points / MAX_CAPTURE_POINTS
         } else {
             0
         }
@@ -32,7 +32,8 @@ impl Unit {
         self.unit_type_data().weapons.iter().any(|w| weapon(*w).require_deployed)
     }
     pub fn can_carry(&self, target: &Unit) -> bool {
-        self.unit_type_data().carry_num > self.carried.len() as u32
+        self.owner == target.owner
+            && self.unit_type_data().carry_num > self.carried.len() as u32
             && self.unit_type_data().carry_classes.contains(&target.unit_type_data().unit_class)
     }
     pub fn can_move_on_terrain(&self, terrain_type: Terrain) -> bool {
@@ -4,6 +4,7 @@ extern crate serde_json;
 extern crate serde_derive;
 
 pub mod auth;
+pub mod bot;
 pub mod game;
 pub mod model;
 pub mod protocol;
@@ -5,6 +5,8 @@ pub const CAPTURE_POINT_REGEN_RATE: u32 = 50;
 pub const FUNDS_PER_PROPERTY: u32 = 100;
 pub const UNIT_MAX_HEALTH: u32 = 100;
 pub const UNIT_MAX_REPAIR_RATE: u32 = 20;
+pub const UNIT_VISION_RANGE: u32 = 3;
+pub const PROPERTY_VISION_RANGE: u32 = 1;
 
 pub fn weapon(x: Weapon) -> WeaponData<'static> {
     use model::Weapon::*;
@@ -1,47 +1,330 @@
-use crate::game::{Action, ActionError, Event, Game, Map, PlayerNumber};
+use crate::auth::UserId;
+use crate::game::{Action, ActionError, Event, Game, GameState, Map, PlayerNumber, Rules};
 use serde::{Deserialize, Serialize};
 
 pub type GameId = u32;
+pub type MapId = u32;
 pub type EventIndex = u32;
+/// Chosen by the client to tell apart the replies to its requests
+pub type RequestId = u32;
 pub const VERSION: &str = "0.1";
+/// Protocol versions are agreed on in the handshake
+pub type ProtocolVersion = u32;
+/// Newest protocol version. Version 2 added map ids and uploads, subscription
+/// management and webhooks, which moved message variants, so version 1
+/// clients can't be served.
+pub const PROTOCOL_VERSION: ProtocolVersion = 2;
+/// Oldest protocol version still served
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = 2;
+/// Longest chat message the server accepts, in characters
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 500;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PlayerSlotType {
     Empty,
     Human(Option<String>),
     Bot(String),
 }
+/// Time limits for the turns of a game. Each turn gets `turn_seconds`. With a
+/// time bank, time left over from a turn is saved for later turns like on a
+/// chess clock with an increment; the bank starts at `time_bank_seconds`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TurnTimer {
+    pub turn_seconds: u64,
+    pub time_bank_seconds: Option<u64>,
+    /// Surrender for players who run out of time this many turns in a row
+    pub surrender_after_timeouts: Option<u32>,
+}
+
+/// Everything about a game chosen when creating it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct GameSettings {
+    pub rules: Rules,
+    pub turn_timer: Option<TurnTimer>,
+    /// Public games are listed in the lobby
+    pub public: bool,
+    /// Password needed to join and follow the game, kept hashed by the server
+    pub password: Option<String>,
+    /// Spectators see the game as it was this many turns ago
+    pub spectator_delay_turns: u32,
+}
+
+impl Default for GameSettings {
+    fn default() -> Self {
+        Self {
+            rules: Rules::default(),
+            turn_timer: None,
+            public: true,
+            password: None,
+            spectator_delay_turns: 0,
+        }
+    }
+}
+
+impl GameSettings {
+    /// The settings as shown to clients
+    pub fn without_password(&self) -> Self {
+        Self {
+            password: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A map as listed for choosing one for a game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MapInfo {
+    pub map_id: MapId,
+    pub name: String,
+    /// Who uploaded the map. Maps that come with the server have no author.
+    pub author: Option<UserId>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub player_count: usize,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl MapInfo {
+    pub fn new(map_id: MapId, map: &Map, author: Option<UserId>, created_at: u64) -> Self {
+        let (width, height) = map.size();
+        Self {
+            map_id,
+            name: map.name.clone(),
+            author,
+            created_at,
+            player_count: map.player_numbers().len(),
+            width,
+            height,
+        }
+    }
+}
+
+/// A game as listed in the lobby
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameInfo {
+    pub game_id: GameId,
+    pub state: GameState,
+    pub settings: GameSettings,
+    pub password_required: bool,
+    pub players: Vec<(PlayerNumber, PlayerSlotType)>,
+}
+
+/// Optional protocol features agreed on in the handshake
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Events in postcard rather than JSON
+    Binary,
+    /// Games with fog of war
+    Fog,
+}
+
+/// The protocol version and capabilities the server and a client agreed on
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Handshake {
+    pub version: ProtocolVersion,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Handshake {
+    /// Pick the newest protocol version both sides support and the
+    /// capabilities they share. The error tells why the client can't be
+    /// served.
+    pub fn negotiate(
+        client_versions: &[ProtocolVersion],
+        client_capabilities: &[Capability],
+        server_capabilities: &[Capability],
+    ) -> Result<Self, String> {
+        let version = client_versions
+            .iter()
+            .copied()
+            .filter(|version| (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(version))
+            .max()
+            .ok_or_else(|| {
+                format!(
+                    "The server supports protocol versions {MIN_PROTOCOL_VERSION} to \
+                     {PROTOCOL_VERSION} but the client only {client_versions:?}"
+                )
+            })?;
+        let capabilities = client_capabilities
+            .iter()
+            .copied()
+            .filter(|capability| server_capabilities.contains(capability))
+            .collect();
+        Ok(Self {
+            version,
+            capabilities,
+        })
+    }
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// Who a chat message is for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+    /// Everyone connected to the server
+    Lobby,
+    /// Players and spectators of the game
+    Game(GameId),
+    /// Players of the game on the author's team
+    Team(GameId),
+    /// Spectators of the game
+    Spectators(GameId),
+}
+
+impl ChatScope {
+    pub fn game_id(&self) -> Option<GameId> {
+        match self {
+            ChatScope::Lobby => None,
+            ChatScope::Game(game_id)
+            | ChatScope::Team(game_id)
+            | ChatScope::Spectators(game_id) => Some(*game_id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub scope: ChatScope,
+    pub author: UserId,
+    /// The player the author plays as in the game, if any
+    pub player_number: Option<PlayerNumber>,
+    pub text: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum ActionMessage {
     NoOp,
+    /// Start the handshake with the protocol versions and capabilities the
+    /// client supports. Other requests are refused until the handshake is
+    /// done.
+    Hello(Vec<ProtocolVersion>, Vec<Capability>),
     Ping,
     GameAction(GameId, Action),
     SubscribeGame(GameId),
+    /// Watch the game without taking part in it. Password protected games
+    /// can only be watched by connections that created or joined them.
+    SpectateGame(GameId),
+    /// Stop receiving the events of a subscribed or spectated game
+    UnsubscribeGame(GameId),
+    GetSubscriptions,
     GetEvents(GameId, EventIndex),
     GetMaps,
-    CreateGame(String),
+    GetGames,
+    CreateGame(MapId, GameSettings),
+    /// Add a map in the JSON map format to the server's maps
+    UploadMap(String),
     SetPlayerSlotType(GameId, PlayerNumber, PlayerSlotType),
     StartGame(GameId),
-    JoinGame(GameId, PlayerNumber),
+    JoinGame(GameId, PlayerNumber, Option<String>),
+    /// Have the server post to the URL when the turn of a player this user
+    /// joined a game as starts, or stop with `None`
+    SetWebhook(Option<String>),
+    Chat(ChatScope, String),
+    /// Stop receiving chat messages from the user
+    MuteUser(UserId),
+    UnmuteUser(UserId),
     Quit,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum EventMessage {
     ServerVersion(String),
+    /// The handshake succeeded
+    Welcome(Handshake),
     Pong,
-    Maps(Vec<Map>),
-    GameState(Game, Vec<(PlayerNumber, PlayerSlotType)>, EventIndex),
+    Maps(Vec<MapInfo>),
+    MapUploaded(MapInfo),
+    /// Public games
+    Games(Vec<GameInfo>),
+    GameState(
+        Box<Game>,
+        GameSettings,
+        Vec<(PlayerNumber, PlayerSlotType)>,
+        EventIndex,
+    ),
     GameCreated(GameId),
     GameJoined(GameId, PlayerNumber, PlayerSlotType),
     GameStarted(GameId),
-    GameEvent(GameId, Event),
+    /// The webhook of this user
+    WebhookSet(Option<String>),
+    /// Games subscribed to and games spectated
+    Subscriptions(Vec<GameId>, Vec<GameId>),
+    /// An event of the game and its index, for spotting duplicate and missed
+    /// events
+    GameEvent(GameId, EventIndex, Event),
     GameActionError(GameId, ActionError),
+    /// Seconds the in-turn player has left before their turn is ended
+    TurnTimeLeft(GameId, PlayerNumber, u64),
+    Chat(ChatMessage),
+    Error(ErrorReply),
+}
+
+/// An action and the id that replies to it carry
+#[derive(Serialize, Deserialize)]
+pub struct Request {
+    pub id: RequestId,
+    pub action: ActionMessage,
+}
+
+/// An event and the id of the request it replies to. Events that aren't
+/// direct replies, such as those of subscribed games, have no request id.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Response {
+    pub request_id: Option<RequestId>,
+    pub event: EventMessage,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request could not be parsed
+    InvalidRequest,
+    /// The client and the server have no protocol version in common
+    IncompatibleVersion,
+    /// The client lacks a capability the request needs
+    Unsupported,
     NoSuchMap,
+    /// The uploaded map can't be played on
+    InvalidMap,
     NoSuchGame,
+    WrongPassword,
+    /// Spectators can't change the game and only players can talk to their
+    /// team
+    PermissionDenied,
+    /// The chat message was longer than `MAX_CHAT_MESSAGE_LENGTH`
+    ChatMessageTooLong,
+    /// The connection follows as many games as it may
+    TooManySubscriptions,
+    /// The server has as many games or connections as it takes
+    ServerFull,
     ServerError,
 }
 
+/// Why a request failed, with a message that can be shown to the user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Text parse error")]
@@ -49,7 +332,7 @@ pub enum Error {
     #[error("Binary parse error")]
     BinaryError(#[from] postcard::Error),
 }
-impl ActionMessage {
+impl Request {
     pub fn from_text(text: &str) -> Result<Self, Error> {
         Ok(serde_json::from_str(text)?)
     }
@@ -64,7 +347,7 @@ impl ActionMessage {
     }
 }
 
-impl EventMessage {
+impl Response {
     pub fn from_text(text: &str) -> Result<Self, Error> {
         Ok(serde_json::from_str(text)?)
     }
@@ -80,7 +363,46 @@ impl EventMessage {
 }
 
 pub fn version_message() -> String {
-    EventMessage::ServerVersion(VERSION.to_string())
-        .as_text()
-        .expect("Could not serialize server version message?")
+    Response {
+        request_id: None,
+        event: EventMessage::ServerVersion(VERSION.to_string()),
+    }
+    .as_text()
+    .expect("Could not serialize server version message?")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::*;
+
+    #[test]
+    fn test_handshake() {
+        let server_capabilities = [Capability::Fog];
+        let handshake = Handshake::negotiate(
+            &[0, PROTOCOL_VERSION, PROTOCOL_VERSION + 1],
+            &[Capability::Binary, Capability::Fog],
+            &server_capabilities,
+        )
+        .unwrap();
+        assert_eq!(handshake.version, PROTOCOL_VERSION);
+        assert_eq!(handshake.capabilities, vec![Capability::Fog]);
+        assert!(handshake.supports(Capability::Fog));
+        assert!(!handshake.supports(Capability::Binary));
+
+        assert!(Handshake::negotiate(&[PROTOCOL_VERSION + 1], &[], &server_capabilities).is_err());
+        assert!(Handshake::negotiate(&[], &[], &server_capabilities).is_err());
+    }
+
+    #[test]
+    fn test_event_wire_indices() {
+        use crate::game::Event;
+        // postcard encodes variants by index, so existing events must keep theirs
+        let index = |event: Event| postcard::to_allocvec(&event).unwrap()[0];
+        assert_eq!(index(Event::StartTurn(1)), 0);
+        assert_eq!(index(Event::Surrender(1)), 5);
+        assert_eq!(index(Event::Build(0, 0, crate::model::UnitType::Infantry, 0)), 17);
+        assert_eq!(index(Event::TileCapturePointRegen(0, 0)), 18);
+        assert_eq!(index(Event::Draw), 19);
+        assert_eq!(index(Event::Defeated(1)), 20);
+    }
 }
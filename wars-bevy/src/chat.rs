@@ -0,0 +1,136 @@
+use crate::{AppState, connection::Connection, resources::*};
+use bevy::prelude::*;
+use wars::protocol::{ActionMessage, ChatMessage, ChatScope, MAX_CHAT_MESSAGE_LENGTH};
+
+/// How many messages are kept in the chat log
+const CHAT_LOG_LENGTH: usize = 200;
+
+pub struct ChatPlugin;
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChatLog::default())
+            .add_systems(Update, receive_chat_system)
+            .add_systems(
+                bevy_egui::EguiPrimaryContextPass,
+                chat_window_system.run_if(
+                    in_state(AppState::SelectGame)
+                        .or(in_state(AppState::HostPreGame))
+                        .or(in_state(AppState::JoinPreGame))
+                        .or(in_state(AppState::InGame)),
+                ),
+            );
+    }
+}
+
+#[derive(Resource, Default)]
+struct ChatLog {
+    messages: Vec<ChatMessage>,
+    draft: String,
+    scope: Option<ChatScope>,
+}
+
+fn receive_chat_system(mut connection: Single<&mut Connection>, mut chat_log: ResMut<ChatLog>) {
+    chat_log.messages.extend(connection.recv_chat());
+    let overflow = chat_log.messages.len().saturating_sub(CHAT_LOG_LENGTH);
+    chat_log.messages.drain(..overflow);
+}
+
+fn scope_label(scope: &ChatScope) -> &'static str {
+    match scope {
+        ChatScope::Lobby => "Lobby",
+        ChatScope::Game(_) => "All",
+        ChatScope::Team(_) => "Team",
+        ChatScope::Spectators(_) => "Spectators",
+    }
+}
+
+fn chat_window_system(
+    mut contexts: bevy_egui::EguiContexts,
+    mut connection: Single<&mut Connection>,
+    mut chat_log: ResMut<ChatLog>,
+    game: Res<Game>,
+    spectating: Option<Res<Spectating>>,
+    app_state: Res<State<AppState>>,
+) {
+    let Ok(ctx) = contexts.ctx_mut() else {
+        return;
+    };
+    if !connection.is_up() {
+        return;
+    }
+
+    // Spectators may only talk among themselves
+    let scopes = match game.as_ref() {
+        _ if *app_state.get() == AppState::SelectGame => vec![ChatScope::Lobby],
+        Game::PreGame(_, _, Some(game_id)) | Game::InGame(_, _, Some(game_id)) => {
+            if spectating.is_some() {
+                vec![ChatScope::Spectators(*game_id)]
+            } else {
+                vec![ChatScope::Game(*game_id), ChatScope::Team(*game_id)]
+            }
+        }
+        _ => return,
+    };
+    let game_id = scopes[0].game_id();
+    let ChatLog {
+        messages,
+        draft,
+        scope,
+    } = chat_log.as_mut();
+    if !scope.is_some_and(|scope| scopes.contains(&scope)) {
+        *scope = Some(scopes[0]);
+    }
+
+    egui::Window::new("Chat")
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(8.0, -8.0))
+        .default_width(320.0)
+        .show(ctx, |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(160.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for message in messages.iter().filter(|m| m.scope.game_id() == game_id) {
+                        let author = match message.player_number {
+                            Some(player_number) => format!("Player {player_number}"),
+                            None => format!("User {}", message.author),
+                        };
+                        ui.label(format!(
+                            "[{}] {author}: {}",
+                            scope_label(&message.scope),
+                            message.text
+                        ))
+                        .context_menu(|ui| {
+                            if ui.button(format!("Mute {author}")).clicked() {
+                                connection.send(ActionMessage::MuteUser(message.author));
+                                ui.close_menu();
+                            }
+                            if ui.button(format!("Unmute {author}")).clicked() {
+                                connection.send(ActionMessage::UnmuteUser(message.author));
+                                ui.close_menu();
+                            }
+                        });
+                    }
+                });
+            ui.horizontal(|ui| {
+                if scopes.len() > 1 {
+                    egui::ComboBox::from_id_salt("chat_scope")
+                        .selected_text(scope.as_ref().map(scope_label).unwrap_or_default())
+                        .width(80.0)
+                        .show_ui(ui, |ui| {
+                            for option in scopes.iter() {
+                                ui.selectable_value(scope, Some(*option), scope_label(option));
+                            }
+                        });
+                }
+                let input =
+                    ui.add(egui::TextEdit::singleline(draft).char_limit(MAX_CHAT_MESSAGE_LENGTH));
+                let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui.button("Send").clicked() || submitted)
+                    && let Some(scope) = scope
+                    && !draft.trim().is_empty()
+                {
+                    connection.send(ActionMessage::Chat(*scope, std::mem::take(draft)));
+                }
+            });
+        });
+}
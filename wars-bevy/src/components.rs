@@ -160,6 +160,11 @@ pub enum InputEvent {
 #[derive(Event)]
 pub struct GameEvent(pub wars::game::Event);
 
+/// The game was replaced by a state from the server rather than changed by
+/// events
+#[derive(Event)]
+pub struct GameReset;
+
 #[derive(Event)]
 pub struct GameAction(pub wars::game::Action);
 
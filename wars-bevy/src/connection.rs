@@ -1,37 +1,105 @@
 use bevy::prelude::*;
 use std::collections::VecDeque;
 use wars::{
-    game::{ActionError, Game, Map, PlayerNumber},
-    protocol::{ActionMessage, EventIndex, GameId, PlayerSlotType},
+    game::{ActionError, Game, PlayerNumber},
+    protocol::{
+        ActionMessage, Capability, ChatMessage, ErrorCode, ErrorReply, EventIndex, GameId, MapInfo,
+        PROTOCOL_VERSION, PlayerSlotType, Request, RequestId,
+    },
 };
 
-use crate::bevy_nfws::NfwsHandle;
+use crate::{AppState, bevy_nfws::NfwsHandle};
+
+/// Reconnection attempts before giving up on the server
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+/// Wait before the first reconnection attempt, doubled for every further one
+const RECONNECT_DELAY_SECONDS: f64 = 0.5;
+const MAX_RECONNECT_DELAY_SECONDS: f64 = 16.0;
+
 pub struct ConnectionPlugin;
 impl Plugin for ConnectionPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Startup, setup)
-            .add_systems(Update, process_messages);
+            .add_systems(Update, process_messages)
+            .add_systems(OnExit(AppState::InGame), leave_game);
     }
 }
 
 pub enum ConnectionEvent {
     Connected,
-    Maps(Vec<Map>),
+    Maps(Vec<MapInfo>),
+    MapUploaded(MapInfo),
     GameState(Game, Vec<(PlayerNumber, PlayerSlotType)>, EventIndex),
     GameCreated(GameId),
     GameJoined(GameId, PlayerNumber, PlayerSlotType),
     GameStarted(GameId),
     GameEvent(GameId, wars::game::Event),
     GameActionError(GameId, ActionError),
+    TurnTimeLeft(GameId, PlayerNumber, u64),
+    /// The request with the id failed
+    Error(Option<RequestId>, ErrorReply),
     Disconnected,
 }
 
 #[derive(Component, Default)]
 pub struct Connection {
     events: VecDeque<ConnectionEvent>,
-    actions: VecDeque<ActionMessage>,
+    actions: VecDeque<Request>,
+    next_request_id: RequestId,
+    /// Chat is kept apart from the other events so that it can be read in any
+    /// state
+    chat: VecDeque<ChatMessage>,
     server_url: Option<String>,
     up: bool,
+    /// The server has answered since connecting to it, so a lost socket is
+    /// worth reconnecting
+    established: bool,
+    /// The server agreed on the protocol over the current socket. It takes no
+    /// other requests before that.
+    welcomed: bool,
+    /// The game followed over the connection
+    subscription: Option<Subscription>,
+    reconnect_attempts: u32,
+    /// When to try connecting again, in seconds since startup
+    reconnect_at: Option<f64>,
+    /// The socket was lost and has to be replaced
+    lost: bool,
+}
+
+/// A game followed over the connection, so that it can be picked up where it
+/// was left off after reconnecting
+struct Subscription {
+    game_id: GameId,
+    spectating: bool,
+    /// Index of the last event passed on from the game
+    last_event_index: EventIndex,
+    /// Waiting for the game state that the event indices continue from
+    loading: bool,
+    /// Missed events have been asked for since this index
+    requested_since: Option<EventIndex>,
+    /// The player and password the game was joined with, as the server only
+    /// lets players that joined on the connection play
+    joined: Option<(PlayerNumber, Option<String>)>,
+}
+
+impl Subscription {
+    fn new(game_id: GameId, spectating: bool) -> Self {
+        Self {
+            game_id,
+            spectating,
+            last_event_index: 0,
+            loading: true,
+            requested_since: None,
+            joined: None,
+        }
+    }
+    fn subscribe_action(&self) -> ActionMessage {
+        if self.spectating {
+            ActionMessage::SpectateGame(self.game_id)
+        } else {
+            ActionMessage::SubscribeGame(self.game_id)
+        }
+    }
 }
 
 impl TryFrom<wars::protocol::EventMessage> for ConnectionEvent {
@@ -40,20 +108,24 @@ impl TryFrom<wars::protocol::EventMessage> for ConnectionEvent {
     fn try_from(value: wars::protocol::EventMessage) -> std::result::Result<Self, Self::Error> {
         match value {
             wars::protocol::EventMessage::Maps(maps) => Ok(Self::Maps(maps)),
-            wars::protocol::EventMessage::GameState(game, items, players) => {
-                Ok(Self::GameState(game, items, players))
+            wars::protocol::EventMessage::MapUploaded(map) => Ok(Self::MapUploaded(map)),
+            wars::protocol::EventMessage::GameState(game, _settings, items, players) => {
+                Ok(Self::GameState(*game, items, players))
             }
             wars::protocol::EventMessage::GameCreated(game_id) => Ok(Self::GameCreated(game_id)),
             wars::protocol::EventMessage::GameJoined(game_id, player_number, player_slot_type) => {
                 Ok(Self::GameJoined(game_id, player_number, player_slot_type))
             }
             wars::protocol::EventMessage::GameStarted(game_id) => Ok(Self::GameStarted(game_id)),
-            wars::protocol::EventMessage::GameEvent(game_id, event) => {
+            wars::protocol::EventMessage::GameEvent(game_id, _index, event) => {
                 Ok(Self::GameEvent(game_id, event))
             }
             wars::protocol::EventMessage::GameActionError(game_id, action_error) => {
                 Ok(Self::GameActionError(game_id, action_error))
             }
+            wars::protocol::EventMessage::TurnTimeLeft(game_id, player_number, seconds) => {
+                Ok(Self::TurnTimeLeft(game_id, player_number, seconds))
+            }
             _ => Err(()),
         }
     }
@@ -62,22 +134,39 @@ impl TryFrom<wars::protocol::EventMessage> for ConnectionEvent {
 fn setup(mut commands: Commands) {
     commands.spawn(Connection::default());
 }
+fn leave_game(mut connection: Single<&mut Connection>) {
+    if let Some(subscription) = connection.subscription.take() {
+        connection.request(ActionMessage::UnsubscribeGame(subscription.game_id));
+    }
+}
 fn process_messages(
     mut commands: Commands,
     mut connection: Single<&mut Connection>,
     mut handles: Query<(Entity, &mut NfwsHandle)>,
+    time: Res<Time>,
 ) {
-    if connection.server_url.is_none() {
+    let now = time.elapsed_secs_f64();
+    if connection.server_url.is_none() || connection.lost {
         for (entity, _) in handles.iter() {
             commands.entity(entity).despawn();
         }
+        if connection.lost {
+            connection.lost = false;
+            connection.up = false;
+            return;
+        }
     }
 
     let Ok((_, mut handle)) = handles.single_mut() else {
         connection.up = false;
+        connection.welcomed = false;
         if handles.iter().count() > 1 {
             panic!("Multiple server connections!");
         }
+        if connection.reconnect_at.is_some_and(|at| now < at) {
+            return;
+        }
+        connection.reconnect_at = None;
 
         if let Some(server_url) = connection.server_url.as_ref() {
             commands.spawn(NfwsHandle::new(server_url.clone()));
@@ -87,29 +176,63 @@ fn process_messages(
 
     connection.up = true;
 
-    while let Some(action) = connection.actions.pop_front() {
-        handle.send_text(action.as_text().unwrap());
+    let mut waiting = VecDeque::new();
+    while let Some(request) = connection.actions.pop_front() {
+        if connection.welcomed || matches!(request.action, ActionMessage::Hello(..)) {
+            handle.send_text(request.as_text().unwrap());
+        } else {
+            waiting.push_back(request);
+        }
     }
+    connection.actions = waiting;
 
-    let mut handle_message = |event_message| match event_message {
+    let mut handle_message = |request_id, event_message| match event_message {
         wars::protocol::EventMessage::ServerVersion(version) => {
-            if version == wars::protocol::VERSION {
-                connection.events.push_back(ConnectionEvent::Connected);
-            } else {
-                error!("Server protocol version mismatch!");
-                connection.disconnect();
-            }
+            info!("Server version {version}");
+            connection.send(ActionMessage::Hello(
+                vec![PROTOCOL_VERSION],
+                vec![Capability::Binary, Capability::Fog],
+            ));
+        }
+        wars::protocol::EventMessage::Welcome(handshake) => {
+            info!(
+                "Using protocol version {} with {:?}",
+                handshake.version, handshake.capabilities
+            );
+            connection.established = true;
+            connection.welcomed = true;
+            connection.reconnect_attempts = 0;
+            connection.resume();
+            connection.events.push_back(ConnectionEvent::Connected);
+        }
+        wars::protocol::EventMessage::Error(ErrorReply {
+            code: ErrorCode::IncompatibleVersion,
+            message,
+        }) => {
+            error!("Server rejected the client: {message}");
+            connection.disconnect();
         }
         wars::protocol::EventMessage::Pong => (),
-        wars::protocol::EventMessage::NoSuchMap => {
-            warn!("No such map");
+        wars::protocol::EventMessage::Error(error) => {
+            warn!("Request {request_id:?} failed: {error}");
+            connection
+                .events
+                .push_back(ConnectionEvent::Error(request_id, error));
         }
-        wars::protocol::EventMessage::NoSuchGame => {
-            warn!("No such game");
+        wars::protocol::EventMessage::GameEvent(game_id, index, event) => {
+            connection.receive_game_event(game_id, index, event);
         }
-        wars::protocol::EventMessage::ServerError => {
-            error!("Server error!");
-            connection.disconnect();
+        wars::protocol::EventMessage::GameState(game, _settings, players, last_event_index) => {
+            if connection.receive_game_state(last_event_index) {
+                connection.events.push_back(ConnectionEvent::GameState(
+                    *game,
+                    players,
+                    last_event_index,
+                ));
+            }
+        }
+        wars::protocol::EventMessage::Chat(message) => {
+            connection.chat.push_back(message);
         }
         other => {
             let Ok(event) = ConnectionEvent::try_from(other) else {
@@ -121,15 +244,15 @@ fn process_messages(
         }
     };
     match handle.next_event() {
-        crate::bevy_nfws::NfwsPollResult::Closed => connection.disconnect(),
+        crate::bevy_nfws::NfwsPollResult::Closed => connection.connection_lost(now),
         crate::bevy_nfws::NfwsPollResult::Empty => (),
         crate::bevy_nfws::NfwsPollResult::Event(nfws_event) => match nfws_event {
             crate::bevy_nfws::NfwsEvent::Connecting => info!("Connecting..."),
             crate::bevy_nfws::NfwsEvent::Connected => info!("Connected!"),
             crate::bevy_nfws::NfwsEvent::TextMessage(text) => {
                 debug!("TextMessage: {}", text);
-                match wars::protocol::EventMessage::from_text(&text) {
-                    Ok(event_message) => handle_message(event_message),
+                match wars::protocol::Response::from_text(&text) {
+                    Ok(response) => handle_message(response.request_id, response.event),
                     Err(error) => {
                         error!("Connection error: {}", error);
                         connection.disconnect();
@@ -138,8 +261,8 @@ fn process_messages(
             }
             crate::bevy_nfws::NfwsEvent::BinaryMessage(bytes) => {
                 debug!("BinaryMessage: <binary data>");
-                match wars::protocol::EventMessage::from_bytes(&bytes) {
-                    Ok(event_message) => handle_message(event_message),
+                match wars::protocol::Response::from_bytes(&bytes) {
+                    Ok(response) => handle_message(response.request_id, response.event),
                     Err(error) => {
                         error!("Connection error: {}", error);
                         connection.disconnect();
@@ -148,19 +271,48 @@ fn process_messages(
             }
             crate::bevy_nfws::NfwsEvent::Error(nfws_err) => {
                 error!("Socket error: {nfws_err:?}");
-                connection.disconnect();
+                connection.connection_lost(now);
             }
             crate::bevy_nfws::NfwsEvent::Closed(reason) => {
                 error!("Disconnected: {reason:?}");
-                connection.disconnect();
+                connection.connection_lost(now);
             }
         },
     };
 }
 
 impl Connection {
-    pub fn send(&mut self, action_message: ActionMessage) {
-        self.actions.push_back(action_message);
+    /// Send the action to the server. Replies to it carry the returned id.
+    pub fn send(&mut self, action_message: ActionMessage) -> RequestId {
+        let followed = match action_message {
+            ActionMessage::SubscribeGame(game_id) => Some(Subscription::new(game_id, false)),
+            ActionMessage::SpectateGame(game_id) => Some(Subscription::new(game_id, true)),
+            _ => None,
+        };
+        if let ActionMessage::JoinGame(game_id, player_number, password) = &action_message
+            && let Some(subscription) = self
+                .subscription
+                .as_mut()
+                .filter(|subscription| subscription.game_id == *game_id)
+        {
+            subscription.joined = Some((*player_number, password.clone()));
+        }
+        if let Some(subscription) = followed {
+            let game_id = subscription.game_id;
+            // Only one game is followed at a time
+            if let Some(previous) = self.subscription.replace(subscription)
+                && previous.game_id != game_id
+            {
+                self.request(ActionMessage::UnsubscribeGame(previous.game_id));
+            }
+        }
+        self.request(action_message)
+    }
+    fn request(&mut self, action: ActionMessage) -> RequestId {
+        let id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.actions.push_back(Request { id, action });
+        id
     }
     pub fn recv(&mut self) -> Option<ConnectionEvent> {
         self.events.pop_front()
@@ -170,6 +322,9 @@ impl Connection {
         std::mem::swap(&mut self.events, &mut events);
         events.into_iter()
     }
+    pub fn recv_chat(&mut self) -> impl Iterator<Item = ChatMessage> {
+        std::mem::take(&mut self.chat).into_iter()
+    }
     pub fn is_up(&self) -> bool {
         self.up
     }
@@ -179,5 +334,112 @@ impl Connection {
     pub fn disconnect(&mut self) {
         self.events.push_back(ConnectionEvent::Disconnected);
         self.server_url = None;
+        self.subscription = None;
+        self.established = false;
+        self.reconnect_attempts = 0;
+        self.reconnect_at = None;
+    }
+    /// Try to connect again after a while, or give up after too many tries
+    fn connection_lost(&mut self, now: f64) {
+        if self.server_url.is_none() {
+            return;
+        }
+        if !self.established {
+            self.disconnect();
+            return;
+        }
+        if self.reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+            error!("Could not reconnect to the server");
+            self.disconnect();
+            return;
+        }
+        let delay = (RECONNECT_DELAY_SECONDS * 2f64.powi(self.reconnect_attempts as i32))
+            .min(MAX_RECONNECT_DELAY_SECONDS);
+        info!("Reconnecting in {delay} seconds");
+        self.reconnect_attempts += 1;
+        self.reconnect_at = Some(now + delay);
+        self.lost = true;
+    }
+    /// Join and follow the game again after reconnecting and ask for the events
+    /// missed in between
+    fn resume(&mut self) {
+        let Some(subscription) = self.subscription.as_mut() else {
+            return;
+        };
+        let join_action = subscription
+            .joined
+            .clone()
+            .map(|(player_number, password)| {
+                ActionMessage::JoinGame(subscription.game_id, player_number, password)
+            });
+        let subscribe_action = subscription.subscribe_action();
+        let missed_events = (!subscription.loading).then(|| {
+            subscription.requested_since = Some(subscription.last_event_index);
+            ActionMessage::GetEvents(subscription.game_id, subscription.last_event_index)
+        });
+        if let Some(join_action) = join_action {
+            self.request(join_action);
+        }
+        self.request(subscribe_action);
+        if let Some(missed_events) = missed_events {
+            self.request(missed_events);
+        }
+    }
+    /// Whether to pass on the game state. A state older than the events
+    /// already passed on is dropped as the game is picked up from where it was
+    /// left off instead. Newer ones replace the game, such as when fog of war
+    /// keeps the server from sending the events.
+    fn receive_game_state(&mut self, last_event_index: EventIndex) -> bool {
+        match self.subscription.as_mut() {
+            Some(subscription)
+                if subscription.loading || last_event_index >= subscription.last_event_index =>
+            {
+                subscription.last_event_index = last_event_index;
+                subscription.loading = false;
+                true
+            }
+            Some(_) => false,
+            None => true,
+        }
+    }
+    /// Load the followed game again from the server, such as when its events
+    /// no longer fit the game as it is shown
+    pub fn reload(&mut self) {
+        let Some(subscription) = self.subscription.as_mut() else {
+            return;
+        };
+        subscription.loading = true;
+        subscription.requested_since = None;
+        let subscribe_action = subscription.subscribe_action();
+        self.request(subscribe_action);
+    }
+    /// Pass on the game event unless it already was. Events that skip ahead
+    /// are dropped and the missed events asked for, which brings them again.
+    fn receive_game_event(&mut self, game_id: GameId, index: EventIndex, event: wars::game::Event) {
+        if let Some(subscription) = self
+            .subscription
+            .as_mut()
+            .filter(|subscription| subscription.game_id == game_id)
+        {
+            let last_event_index = subscription.last_event_index;
+            if subscription.loading || index <= last_event_index {
+                debug!("Skipping event {index} of game {game_id}");
+                return;
+            }
+            if index > last_event_index + 1 {
+                if subscription.requested_since != Some(last_event_index) {
+                    warn!(
+                        "Missed events {}..{index} of game {game_id}",
+                        last_event_index + 1
+                    );
+                    subscription.requested_since = Some(last_event_index);
+                    self.request(ActionMessage::GetEvents(game_id, last_event_index));
+                }
+                return;
+            }
+            subscription.last_event_index = index;
+        }
+        self.events
+            .push_back(ConnectionEvent::GameEvent(game_id, event));
     }
 }
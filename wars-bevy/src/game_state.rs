@@ -4,7 +4,7 @@ use bevy::prelude::*;
 
 use crate::interaction_state::InteractionState;
 
-use crate::{AppState, animation, bot, components::*, map, resources::*, theme};
+use crate::{AppState, animation, components::*, map, resources::*, theme};
 
 pub struct GameStatePlugin;
 
@@ -27,23 +27,26 @@ impl Plugin for GameStatePlugin {
             .add_event::<InputEvent>()
             .add_event::<GameAction>()
             .add_event::<GameEvent>()
+            .add_event::<GameReset>()
             .add_plugins((
                 crate::camera::CameraPlugin,
                 crate::map::MapPlugin,
                 crate::ui::UIPlugin,
                 crate::interaction_state::InteractionStatePlugin,
                 crate::animation::SpriteAnimationPlugin,
+                crate::spectator::SpectatorPlugin,
             ))
             .add_systems(Startup, setup)
             .add_systems(OnEnter(AppState::LoadGame), on_enter_load_game)
             .add_systems(
                 Update,
                 (
-                    visualizer_system,
+                    // A game replaced by the server is shown anew before any
+                    // events of the old one are
+                    (remote_game_system, game_reset_system, visualizer_system).chain(),
                     interaction_event_system,
                     interaction_state_init_system,
                     bot_system,
-                    remote_game_system,
                     action_dispatch_system,
                 )
                     .run_if(in_state(AppState::InGame)),
@@ -101,12 +104,12 @@ fn bot_system(
     visualizer: Res<Visualizer>,
     mut event_writer: EventWriter<GameEvent>,
 ) {
-    if !visualizer.state.is_none()
-        || !visualizer.queue.is_empty()
-        || game.in_turn() != Some(&Player::Bot)
-    {
+    if !visualizer.state.is_none() || !visualizer.queue.is_empty() {
         return;
     }
+    let Some(Player::Bot(bot_name)) = game.in_turn().cloned() else {
+        return;
+    };
     let Game::InGame(state, ..) = game.as_mut() else {
         return;
     };
@@ -115,7 +118,75 @@ fn bot_system(
     };
 
     info!("Running bot system");
-    bot::random_bot(state, &mut enqueue_event).expect("Bot made an ActionError");
+    let mut bot = wars::bot::by_name(&bot_name).unwrap_or_else(|| {
+        warn!("No bot named {bot_name}, using {}", wars::bot::DEFAULT_BOT);
+        wars::bot::by_name(wars::bot::DEFAULT_BOT).expect("Default bot not registered")
+    });
+    wars::bot::play_turn(bot.as_mut(), state, &mut enqueue_event).expect("Bot made an ActionError");
+}
+/// Show the game anew after it was replaced by a state from the server
+fn game_reset_system(
+    mut commands: Commands,
+    mut game_resets: EventReader<GameReset>,
+    mut visualizer: ResMut<Visualizer>,
+    mut interaction_state: ResMut<InteractionState>,
+    game: Res<Game>,
+    theme: Res<Theme>,
+    sprite_sheet: Res<SpriteSheet>,
+    units: Query<Entity, With<Unit>>,
+    mut tiles: Query<(&Tile, &mut Owner, &mut CaptureState)>,
+    mut funds: Query<&mut Funds>,
+    mut in_turn_player: ResMut<InTurnPlayer>,
+    mut top_bar_colors: Query<&mut BackgroundColor, With<MenuBar>>,
+) {
+    if game_resets.read().count() == 0 {
+        return;
+    }
+    let Game::InGame(state, ..) = game.as_ref() else {
+        return;
+    };
+
+    // Events still waiting to be shown were already part of the old game
+    visualizer.state = None;
+    visualizer.queue.clear();
+    for entity in units.iter() {
+        commands.entity(entity).despawn();
+    }
+    for (_tile_id, tile) in state.tiles.iter_with_ids() {
+        let Some((unit_id, unit)) = tile
+            .unit
+            .and_then(|unit_id| Some((unit_id, state.units.get_ref(&unit_id)?)))
+        else {
+            continue;
+        };
+        commands.spawn((
+            map::unit_bundle(unit_id, unit, &theme, &sprite_sheet),
+            Transform::from_translation(theme.unit_position(tile)),
+        ));
+    }
+    for (Tile(tile_id), mut owner, mut capture_state) in tiles.iter_mut() {
+        if let Some(tile) = state.tiles.get(*tile_id) {
+            *owner = Owner(tile.owner.unwrap_or(0));
+            *capture_state = map::capture_state(&tile);
+        }
+    }
+    *in_turn_player = InTurnPlayer(state.in_turn_number());
+    if let Some(player_color) = state
+        .in_turn_number()
+        .and_then(|pn| theme.spec.player_colors.get(pn as usize))
+    {
+        for mut top_bar_color in top_bar_colors.iter_mut() {
+            top_bar_color.0 = player_color.into();
+        }
+    }
+    if let Some(player) = state.in_turn_number().and_then(|pn| state.get_player(pn)) {
+        for mut fund in funds.iter_mut() {
+            *fund = Funds(player.funds);
+        }
+    }
+    if game.in_turn() == Some(&Player::Human) {
+        *interaction_state = InteractionState::from_game(state);
+    }
 }
 fn visualizer_system(
     mut commands: Commands,
@@ -427,6 +498,7 @@ fn interaction_event_system(
     mut tile_in_attack_ranges: Query<(&Tile, &mut InAttackRange)>,
     mut damage_indicators: Query<(&Unit, &mut DamageIndicator)>,
     mut action_menus: Query<(&mut Node, &mut Visibility), (With<ActionMenu>, Without<BuildMenu>)>,
+    spectating: Option<Res<Spectating>>,
 ) {
     let Game::InGame(game, players, _) = game_res.as_mut() else {
         return;
@@ -623,7 +695,13 @@ fn interaction_event_system(
     for event in events.read() {
         info!("Input event: {event:?}");
 
-        if game.in_turn_number().and_then(|n| players.get(&n)) == Some(&Player::Bot) {
+        if spectating.is_some() {
+            continue;
+        }
+        if matches!(
+            game.in_turn_number().and_then(|n| players.get(&n)),
+            Some(Player::Bot(_))
+        ) {
             info!("Bot in turn");
             continue;
         }
@@ -669,6 +747,7 @@ fn remote_game_system(
     mut connection: Single<&mut Connection>,
     mut game: ResMut<Game>,
     mut game_events: EventWriter<GameEvent>,
+    mut game_resets: EventWriter<GameReset>,
     mut input_events: EventWriter<InputEvent>,
 ) {
     let Game::InGame(game, _players, game_id) = game.as_mut() else {
@@ -681,9 +760,12 @@ fn remote_game_system(
         // Local game
         return;
     };
-    let mut handled_events = false;
+    let mut processed_events = Vec::new();
+    let mut reset = false;
+    let mut reloading = false;
 
-    for event in connection.recv_all() {
+    let events: Vec<_> = connection.recv_all().collect();
+    for event in events {
         match event {
             crate::connection::ConnectionEvent::GameEvent(event_game_id, game_event) => {
                 info!("GameEvent: {event_game_id}: {game_event:?}");
@@ -691,9 +773,24 @@ fn remote_game_system(
                     info!("This event is not for this game");
                     continue;
                 }
-                wars::game::action::process(game, &game_event).expect("Error processing event");
-                game_events.write(GameEvent(game_event));
-                handled_events = true;
+                if reloading {
+                    continue;
+                }
+                if let Err(e) = wars::game::action::process(game, &game_event) {
+                    warn!("Could not process {game_event:?}, reloading the game: {e}");
+                    connection.reload();
+                    reloading = true;
+                    continue;
+                }
+                processed_events.push(game_event);
+            }
+            crate::connection::ConnectionEvent::GameState(new_game, _players, last_event) => {
+                info!("Received game state, last event: {last_event}");
+                // Events received before are already part of the new game
+                *game = new_game;
+                processed_events.clear();
+                reset = true;
+                reloading = false;
             }
             crate::connection::ConnectionEvent::GameActionError(event_game_id, action_error) => {
                 if *game_id != event_game_id {
@@ -701,6 +798,16 @@ fn remote_game_system(
                 }
                 error!("GameActionError: {action_error}");
             }
+            crate::connection::ConnectionEvent::TurnTimeLeft(
+                event_game_id,
+                player_number,
+                seconds,
+            ) => {
+                if *game_id != event_game_id {
+                    continue;
+                }
+                info!("Player {player_number} has {seconds} seconds left in their turn");
+            }
             crate::connection::ConnectionEvent::Disconnected => {
                 next_state.set(AppState::MainMenu);
             }
@@ -708,7 +815,11 @@ fn remote_game_system(
         }
     }
 
-    if handled_events {
+    if reset {
+        game_resets.write(GameReset);
+    }
+    if reset || !processed_events.is_empty() {
         input_events.write(InputEvent::ReceivedEvents);
     }
+    game_events.write_batch(processed_events.into_iter().map(GameEvent));
 }
@@ -397,17 +397,22 @@ fn select_destination(
     let mut attack_options = HashMap::new();
     let mut tiles_in_range = HashSet::new();
 
-    if game.unit_can_stay_at(unit_id, &position).is_ok() {
-        action_options.insert(Action::Wait);
-
-        if unit.can_deploy() {
-            if unit.deployed {
-                action_options.insert(Action::Undeploy);
-            } else {
-                action_options.insert(Action::Deploy);
-            }
-        }
-
+    let destination_actions = game
+        .unit_actions(unit_id)
+        .remove(&position)
+        .unwrap_or_default();
+    action_options.extend(destination_actions.iter().filter_map(|a| match a {
+        wars::game::Action::MoveAndWait(..) => Some(Action::Wait),
+        wars::game::Action::MoveAndAttack(..) => Some(Action::Attack),
+        wars::game::Action::MoveAndCapture(..) => Some(Action::Capture),
+        wars::game::Action::MoveAndDeploy(..) => Some(Action::Deploy),
+        wars::game::Action::Undeploy(..) => Some(Action::Undeploy),
+        wars::game::Action::MoveAndLoadInto(..) => Some(Action::Load),
+        wars::game::Action::MoveAndUnload(..) => Some(Action::Unload),
+        _ => None,
+    }));
+
+    if action_options.contains(&Action::Wait) {
         attack_options = game.unit_attack_options(unit_id, &position);
 
         tiles_in_range = game
@@ -422,25 +427,6 @@ fn select_destination(
             })
             .map(|(tid, _)| *tid)
             .collect();
-
-        if !attack_options.is_empty() {
-            action_options.insert(Action::Attack);
-        }
-
-        if game.unit_can_capture_tile(unit_id, tile_id).is_ok() {
-            action_options.insert(Action::Capture);
-        }
-
-        if unit.carried.iter().any(|u| {
-            game.unit_unload_options(unit_id, &position, *u)
-                .is_some_and(|os| !os.is_empty())
-        }) {
-            action_options.insert(Action::Unload);
-        }
-    }
-
-    if game.unit_can_load_into_carrier_at(unit_id, &position) {
-        action_options.insert(Action::Load);
     }
 
     emit(
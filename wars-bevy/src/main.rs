@@ -1,8 +1,8 @@
 use bevy::prelude::*;
 
 mod animation;
-mod bot;
 mod camera;
+mod chat;
 mod components;
 mod connection;
 mod game_state;
@@ -10,6 +10,7 @@ mod interaction_state;
 mod main_menu_state;
 mod map;
 mod resources;
+mod spectator;
 mod theme;
 mod ui;
 
@@ -25,6 +26,7 @@ enum AppState {
     HostSelectMap,
     HostPreGame,
     JoinPreGame,
+    SpectateGame,
     LoadGame,
     InGame,
 }
@@ -36,6 +38,7 @@ fn main() {
             game_state::GameStatePlugin,
             main_menu_state::MainMenuStatePlugin,
             connection::ConnectionPlugin,
+            chat::ChatPlugin,
         ))
         .insert_state(AppState::default())
         .run();
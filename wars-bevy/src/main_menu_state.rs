@@ -1,19 +1,28 @@
 use crate::{
     AppState,
     connection::{Connection, ConnectionEvent},
-    resources::{Game, Player},
+    resources::{Game, Player, Spectating},
 };
 use bevy::prelude::*;
 use include_dir::{File, include_dir};
-use wars::protocol::{GameId, PlayerSlotType};
+use wars::protocol::{GameId, PlayerSlotType, RequestId};
 
 pub struct MainMenuStatePlugin;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone)]
 pub enum PlayerType {
     None,
     Human,
-    Bot,
+    /// Registered bot of the name
+    Bot(String),
+}
+
+fn player_type_label(player_type: &PlayerType) -> String {
+    match player_type {
+        PlayerType::Human => "Human".to_owned(),
+        PlayerType::Bot(bot_name) => format!("Bot: {bot_name}"),
+        PlayerType::None => "None".to_owned(),
+    }
 }
 
 impl Plugin for MainMenuStatePlugin {
@@ -28,6 +37,7 @@ impl Plugin for MainMenuStatePlugin {
                     select_game_menu_system.run_if(in_state(AppState::SelectGame)),
                     host_select_map_menu_system.run_if(in_state(AppState::HostSelectMap)),
                     host_pregame_menu_system.run_if(in_state(AppState::HostPreGame)),
+                    spectate_game_menu_system.run_if(in_state(AppState::SpectateGame)),
                 ),
             );
     }
@@ -107,6 +117,9 @@ fn select_game_menu_system(
         if ui.button("Host a new game").clicked() {
             next_state.set(AppState::HostSelectMap);
         }
+        if ui.button("Spectate a game").clicked() {
+            next_state.set(AppState::SpectateGame);
+        }
         if ui.button("Back").clicked() {
             connection.disconnect();
             next_state.set(AppState::ConnectToServer);
@@ -119,9 +132,12 @@ fn host_select_map_menu_system(
     mut contexts: bevy_egui::EguiContexts,
     mut next_state: ResMut<NextState<AppState>>,
     mut connection: Single<&mut Connection>,
-    mut maps: Local<Option<Vec<wars::game::Map>>>,
+    mut maps: Local<Option<Vec<wars::protocol::MapInfo>>>,
     mut map_index: Local<usize>,
     mut pregame_state: Local<HostPregameState>,
+    mut upload_path: Local<String>,
+    mut upload: Local<Option<RequestId>>,
+    mut error: Local<Option<String>>,
 ) {
     let Ok(ctx) = contexts.ctx_mut() else {
         return;
@@ -137,8 +153,20 @@ fn host_select_map_menu_system(
         *maps = Some(Vec::new());
     }
 
-    if let Some(ConnectionEvent::Maps(ms)) = connection.recv() {
-        *maps = Some(ms);
+    match connection.recv() {
+        Some(ConnectionEvent::Maps(ms)) => *maps = Some(ms),
+        Some(ConnectionEvent::MapUploaded(map)) => {
+            *upload = None;
+            *error = None;
+            let maps = maps.get_or_insert_with(Vec::new);
+            maps.push(map);
+            *map_index = maps.len() - 1;
+        }
+        Some(ConnectionEvent::Error(Some(reply_id), reply)) if Some(reply_id) == *upload => {
+            *upload = None;
+            *error = Some(reply.message);
+        }
+        _ => (),
     }
 
     egui::CentralPanel::default().show(ctx, |ui| {
@@ -156,6 +184,13 @@ fn host_select_map_menu_system(
                         ui.selectable_value(&mut *map_index, i, &map.name);
                     }
                 });
+            ui.label(format!(
+                "{} players, {}x{} tiles",
+                map.player_count, map.width, map.height
+            ));
+            if let Some(author) = map.author {
+                ui.label(format!("Uploaded by user {author}"));
+            }
         } else {
             ui.label("Loading maps...");
         }
@@ -165,10 +200,31 @@ fn host_select_map_menu_system(
         if ui.button("Create game").clicked()
             && let Some(map) = map
         {
-            connection.send(wars::protocol::ActionMessage::CreateGame(map.name.clone()));
+            connection.send(wars::protocol::ActionMessage::CreateGame(
+                map.map_id,
+                wars::protocol::GameSettings::default(),
+            ));
             *pregame_state = HostPregameState::CreatingGame;
             next_state.set(AppState::HostPreGame);
         }
+
+        ui.separator();
+        ui.label("Map file to upload");
+        ui.text_edit_singleline(&mut *upload_path);
+        if let Some(error) = error.as_ref() {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        if upload.is_some() {
+            ui.label("Uploading map...");
+        } else if ui.button("Upload map").clicked() {
+            match std::fs::read_to_string(upload_path.trim()) {
+                Ok(data) => {
+                    *upload = Some(connection.send(wars::protocol::ActionMessage::UploadMap(data)))
+                }
+                Err(e) => *error = Some(format!("Could not read map file: {e}")),
+            }
+        }
+
         if ui.button("Back").clicked() {
             next_state.set(AppState::SelectGame);
         }
@@ -185,12 +241,14 @@ enum HostPregameState {
         wars::game::Game,
         Vec<(wars::game::PlayerNumber, PlayerType, String)>,
     ),
+    Failed(String),
 }
 fn host_pregame_menu_system(
     mut contexts: bevy_egui::EguiContexts,
     mut next_state: ResMut<NextState<AppState>>,
     mut connection: Single<&mut Connection>,
     mut state: Local<HostPregameState>,
+    mut error: Local<Option<String>>,
     mut game_state: ResMut<Game>,
 ) {
     let Ok(ctx) = contexts.ctx_mut() else {
@@ -220,7 +278,9 @@ fn host_pregame_menu_system(
                     (pn, PlayerSlotType::Human(name)) => {
                         (pn, PlayerType::Human, name.unwrap_or(String::new()))
                     }
-                    (pn, PlayerSlotType::Bot(name)) => (pn, PlayerType::Bot, name.clone()),
+                    (pn, PlayerSlotType::Bot(name)) => {
+                        (pn, PlayerType::Bot(name.clone()), name.clone())
+                    }
                 })
                 .collect();
             *state = HostPregameState::PreparingGame(*game_id, game, players);
@@ -244,13 +304,20 @@ fn host_pregame_menu_system(
                             *name = n.unwrap_or_default();
                         }
                         PlayerSlotType::Bot(n) => {
-                            *player = PlayerType::Bot;
+                            *player = PlayerType::Bot(n.clone());
                             *name = n;
                         }
                     }
                 }
             }
         }
+        (
+            HostPregameState::PreparingGame(_, game, _),
+            Some(ConnectionEvent::GameState(new_game, _, _)),
+        ) => {
+            // Joining a game with fog of war shows more of it
+            *game = new_game;
+        }
         (
             HostPregameState::PreparingGame(game_id, game, players),
             Some(ConnectionEvent::GameStarted(event_game_id)),
@@ -266,7 +333,7 @@ fn host_pregame_menu_system(
                                 // TODO: Set correctly as remote or local human
                                 PlayerType::None => None,
                                 PlayerType::Human => Some((*player_number, Player::Human)),
-                                PlayerType::Bot => Some((*player_number, Player::Remote)),
+                                PlayerType::Bot(_) => Some((*player_number, Player::Remote)),
                             },
                         )
                         .collect(),
@@ -275,6 +342,15 @@ fn host_pregame_menu_system(
                 next_state.set(AppState::InGame);
             }
         }
+        (
+            HostPregameState::CreatingGame | HostPregameState::LoadingGame(_),
+            Some(ConnectionEvent::Error(_, reply)),
+        ) => {
+            *state = HostPregameState::Failed(reply.message);
+        }
+        (HostPregameState::PreparingGame(..), Some(ConnectionEvent::Error(_, reply))) => {
+            *error = Some(reply.message);
+        }
         (_, Some(connection_event)) => {
             info!("Unexpected event");
         }
@@ -300,14 +376,16 @@ fn host_pregame_menu_system(
                     .for_each(|(i, (pn, slot, name))| {
                         let previous = slot.clone();
                         egui::ComboBox::new(pn.clone(), name.as_str())
-                            .selected_text(match slot {
-                                PlayerType::Human => "Human",
-                                PlayerType::Bot => "Bot",
-                                PlayerType::None => "None",
-                            })
+                            .selected_text(player_type_label(slot))
                             .show_ui(ui, |ui| {
                                 ui.selectable_value(slot, PlayerType::Human, "Human");
-                                ui.selectable_value(slot, PlayerType::Bot, "Bot");
+                                for bot_name in wars::bot::names() {
+                                    ui.selectable_value(
+                                        slot,
+                                        PlayerType::Bot(bot_name.to_owned()),
+                                        format!("Bot: {bot_name}"),
+                                    );
+                                }
                                 ui.selectable_value(slot, PlayerType::None, "None");
                             });
 
@@ -315,7 +393,7 @@ fn host_pregame_menu_system(
                             let slot_type = match slot {
                                 PlayerType::None => PlayerSlotType::Empty,
                                 PlayerType::Human => PlayerSlotType::Human(None),
-                                PlayerType::Bot => PlayerSlotType::Bot(String::new()),
+                                PlayerType::Bot(bot_name) => PlayerSlotType::Bot(bot_name.clone()),
                             };
                             connection.send(wars::protocol::ActionMessage::SetPlayerSlotType(
                                 *game_id, *pn, slot_type,
@@ -326,12 +404,15 @@ fn host_pregame_menu_system(
                             if name.is_empty() {
                                 if ui.button("Join").clicked() {
                                     connection.send(wars::protocol::ActionMessage::JoinGame(
-                                        *game_id, *pn,
+                                        *game_id, *pn, None,
                                     ));
                                 }
                             }
                         }
                     });
+                if let Some(error) = error.as_ref() {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
                 if ui.button("Start game").clicked() {
                     connection.send(wars::protocol::ActionMessage::StartGame(*game_id));
                 }
@@ -340,7 +421,90 @@ fn host_pregame_menu_system(
                 }
             });
         }
+        HostPregameState::Failed(message) => {
+            let message = message.clone();
+            egui::CentralPanel::default().show(ctx, |ui| {
+                ui.colored_label(egui::Color32::RED, message);
+                if ui.button("Back").clicked() {
+                    *state = HostPregameState::CreatingGame;
+                    next_state.set(AppState::HostSelectMap);
+                }
+            });
+        }
+    }
+}
+fn spectate_game_menu_system(
+    mut commands: Commands,
+    mut contexts: bevy_egui::EguiContexts,
+    mut next_state: ResMut<NextState<AppState>>,
+    mut connection: Single<&mut Connection>,
+    mut game_id_text: Local<String>,
+    mut loading: Local<Option<(GameId, RequestId)>>,
+    mut error: Local<Option<String>>,
+    mut game_state: ResMut<Game>,
+) {
+    let Ok(ctx) = contexts.ctx_mut() else {
+        return;
+    };
+
+    if !connection.is_up() {
+        next_state.set(AppState::MainMenu);
+        return;
+    };
+
+    match (*loading, connection.recv()) {
+        (Some((game_id, _)), Some(ConnectionEvent::GameState(game, players, last_event))) => {
+            info!("Spectating game {game_id}, last event: {last_event}");
+            *game_state = Game::InGame(
+                game,
+                players
+                    .into_iter()
+                    .filter_map(|(player_number, slot)| match slot {
+                        PlayerSlotType::Empty => None,
+                        _ => Some((player_number, Player::Remote)),
+                    })
+                    .collect(),
+                Some(game_id),
+            );
+            commands.insert_resource(Spectating(None));
+            *loading = None;
+            *error = None;
+            next_state.set(AppState::InGame);
+            return;
+        }
+        (Some((_, request_id)), Some(ConnectionEvent::Error(Some(reply_id), reply)))
+            if reply_id == request_id =>
+        {
+            *loading = None;
+            *error = Some(reply.message);
+        }
+        _ => (),
     }
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+        if let Some((game_id, _)) = *loading {
+            ui.label(format!("Loading game #{game_id}"));
+        } else {
+            ui.label("Game number");
+            ui.text_edit_singleline(&mut *game_id_text);
+            if let Some(error) = error.as_ref() {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+            if ui.button("Spectate").clicked()
+                && let Ok(game_id) = game_id_text.trim().parse()
+            {
+                let request_id =
+                    connection.send(wars::protocol::ActionMessage::SpectateGame(game_id));
+                *loading = Some((game_id, request_id));
+                *error = None;
+            }
+        }
+        if ui.button("Back").clicked() {
+            *loading = None;
+            *error = None;
+            next_state.set(AppState::SelectGame);
+        }
+    });
 }
 fn setup_local_menu_system(
     mut contexts: bevy_egui::EguiContexts,
@@ -390,14 +554,16 @@ fn setup_local_menu_system(
             player_types.iter_mut().enumerate().for_each(|(i, slot)| {
                 let pn = i as u32 + 1;
                 egui::ComboBox::from_label(format!("Player {pn}"))
-                    .selected_text(match slot {
-                        (_, PlayerType::Human) => "Human",
-                        (_, PlayerType::Bot) => "Bot",
-                        (_, PlayerType::None) => "None",
-                    })
+                    .selected_text(player_type_label(&slot.1))
                     .show_ui(ui, |ui| {
                         ui.selectable_value(slot, (pn, PlayerType::Human), "Human");
-                        ui.selectable_value(slot, (pn, PlayerType::Bot), "Bot");
+                        for bot_name in wars::bot::names() {
+                            ui.selectable_value(
+                                slot,
+                                (pn, PlayerType::Bot(bot_name.to_owned())),
+                                format!("Bot: {bot_name}"),
+                            );
+                        }
                         ui.selectable_value(slot, (pn, PlayerType::None), "None");
                     });
             });
@@ -409,7 +575,7 @@ fn setup_local_menu_system(
                         .iter()
                         .filter_map(|(pn, pt)| match pt {
                             PlayerType::Human => Some((*pn, Player::Human)),
-                            PlayerType::Bot => Some((*pn, Player::Bot)),
+                            PlayerType::Bot(bot_name) => Some((*pn, Player::Bot(bot_name.clone()))),
                             PlayerType::None => None,
                         })
                         .collect(),
@@ -630,6 +630,14 @@ pub fn unit_bundle(
     )
 }
 
+pub fn capture_state(tile: &wars::game::Tile) -> CaptureState {
+    if tile.capture_points == tile.max_capture_points() {
+        CaptureState::Full
+    } else {
+        CaptureState::Recovering(tile.capture_points)
+    }
+}
+
 fn tile_bundle(
     tile_id: wars::game::TileId,
     tile: &wars::game::Tile,
@@ -637,17 +645,12 @@ fn tile_bundle(
     sprite_sheet: &SpriteSheet,
 ) -> impl Bundle {
     let theme_tile = theme.tile(tile).unwrap();
-    let capture_state = if tile.capture_points == tile.max_capture_points() {
-        CaptureState::Full
-    } else {
-        CaptureState::Recovering(tile.capture_points)
-    };
     (
         Tile(tile_id),
         Owner(tile.owner.unwrap_or(0)),
         InAttackRange(false),
         TileHighlight::Normal,
-        capture_state,
+        capture_state(tile),
         sprite_sheet.sprite(theme_tile.tile_index),
         children![(
             AttackRangeIndicator,
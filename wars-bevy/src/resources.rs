@@ -2,10 +2,11 @@ use bevy::prelude::*;
 use std::collections::{HashMap, HashSet, VecDeque};
 use wars::{game::PlayerNumber, protocol::GameId};
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Player {
     Human,
-    Bot,
+    /// Played locally by the registered bot of the name
+    Bot(String),
     Remote,
 }
 #[derive(Resource)]
@@ -107,3 +108,8 @@ pub enum InputLayer {
 
 #[derive(Resource)]
 pub struct InTurnPlayer(pub Option<wars::game::PlayerNumber>);
+
+/// Present while watching a game as a spectator. Holds the player whose point
+/// of view is shown, or `None` to show everything.
+#[derive(Resource)]
+pub struct Spectating(pub Option<wars::game::PlayerNumber>);
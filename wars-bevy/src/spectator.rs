@@ -0,0 +1,100 @@
+use crate::{AppState, components::Unit, resources::*};
+use bevy::prelude::*;
+use wars::game::PlayerNumber;
+
+pub struct SpectatorPlugin;
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            bevy_egui::EguiPrimaryContextPass,
+            perspective_menu_system
+                .run_if(in_state(AppState::InGame).and(resource_exists::<Spectating>)),
+        )
+        .add_systems(
+            Update,
+            perspective_visibility_system
+                .run_if(in_state(AppState::InGame).and(resource_exists::<Spectating>)),
+        )
+        .add_systems(OnExit(AppState::InGame), stop_spectating);
+    }
+}
+
+fn perspective_label(perspective: Option<PlayerNumber>) -> String {
+    match perspective {
+        Some(player_number) => format!("Player {player_number}"),
+        None => "Everyone".to_owned(),
+    }
+}
+
+fn perspective_menu_system(
+    mut contexts: bevy_egui::EguiContexts,
+    mut spectating: ResMut<Spectating>,
+    game: Res<Game>,
+) {
+    let Ok(ctx) = contexts.ctx_mut() else {
+        return;
+    };
+    let Game::InGame(state, ..) = game.as_ref() else {
+        return;
+    };
+
+    let mut perspective = spectating.0;
+    egui::Window::new("Spectating").show(ctx, |ui| {
+        egui::ComboBox::from_label("Perspective")
+            .selected_text(perspective_label(perspective))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut perspective, None, perspective_label(None));
+                for player in state.players.iter() {
+                    let player_perspective = Some(player.number);
+                    ui.selectable_value(
+                        &mut perspective,
+                        player_perspective,
+                        perspective_label(player_perspective),
+                    );
+                }
+            });
+        if let Some(player) = perspective.and_then(|pn| state.get_player(pn)) {
+            ui.label(format!("{} credits", player.funds));
+        }
+    });
+    if perspective != spectating.0 {
+        spectating.0 = perspective;
+    }
+}
+
+/// Hide the units that the followed player can't see in fog of war
+fn perspective_visibility_system(
+    spectating: Res<Spectating>,
+    game: Res<Game>,
+    mut units: Query<(&Unit, &mut Visibility)>,
+) {
+    let Game::InGame(state, ..) = game.as_ref() else {
+        return;
+    };
+    let visible_positions = match spectating.0 {
+        Some(player_number) if state.rules.fog => Some(state.visible_positions(player_number)),
+        _ => None,
+    };
+
+    for (Unit(unit_id), mut visibility) in units.iter_mut() {
+        let hidden = visible_positions.as_ref().is_some_and(|visible_positions| {
+            let owner = state.units.get_ref(unit_id).and_then(|unit| unit.owner);
+            let position = state
+                .tiles
+                .get_unit_tile(*unit_id)
+                .map(|(_, tile)| tile.position());
+            owner.is_some()
+                && owner != spectating.0
+                && position.is_some_and(|position| !visible_positions.contains(&position))
+        });
+        visibility.set_if_neq(if hidden {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        });
+    }
+}
+
+fn stop_spectating(mut commands: Commands) {
+    commands.remove_resource::<Spectating>();
+}
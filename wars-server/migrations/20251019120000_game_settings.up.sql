@@ -0,0 +1,2 @@
+-- Add up migration script here
+alter table games add column settings string;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table games drop column initial_data;
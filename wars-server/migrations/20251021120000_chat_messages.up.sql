@@ -0,0 +1,7 @@
+-- Add up migration script here
+create table chat_messages (
+    id integer primary key autoincrement,
+    game_id integer not null,
+    data string not null,
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+drop index game_events_game_id_idx;
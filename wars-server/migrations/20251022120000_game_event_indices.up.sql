@@ -0,0 +1,5 @@
+-- Add up migration script here
+delete from game_events
+where rowid not in (select min(rowid) from game_events group by game_id, idx);
+
+create unique index game_events_game_id_idx on game_events(game_id, idx);
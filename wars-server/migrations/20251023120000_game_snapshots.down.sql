@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table games drop column snapshot_event_index;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+alter table games add column snapshot_event_index integer not null default 0;
+update games set snapshot_event_index = last_event_index;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table games drop column map_id;
+drop table maps;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+create table maps (
+    id integer primary key autoincrement,
+    name string not null,
+    author integer,
+    created_at integer not null,
+    player_count integer not null,
+    width integer not null,
+    height integer not null,
+    data string not null
+);
+
+alter table games add column map_id integer references maps(id);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+create table webhooks (
+    game_id integer not null,
+    player_number integer not null,
+    url text not null,
+    primary key(game_id, player_number),
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,4 @@
+-- Add up migration script here
+create table data_migrations (
+    name string primary key
+);
@@ -0,0 +1,10 @@
+-- Add down migration script here
+alter table game_players drop column user_id;
+drop table webhooks;
+create table webhooks (
+    game_id integer not null,
+    player_number integer not null,
+    url text not null,
+    primary key(game_id, player_number),
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- Webhooks belong to users rather than to players of a game. The users who
+-- set the old ones are unknown, so they are dropped.
+drop table webhooks;
+create table webhooks (
+    user_id integer primary key,
+    url text not null
+);
+alter table game_players add column user_id integer;
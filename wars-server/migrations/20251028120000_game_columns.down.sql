@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table games drop column public;
+alter table games drop column state;
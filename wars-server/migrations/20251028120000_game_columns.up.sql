@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- Copies of the game's state and of whether its settings make it public, so
+-- that the lobby and metrics can filter and count games without parsing them.
+-- Existing games get theirs from the game_columns data migration.
+alter table games add column state string not null default 'Pregame';
+alter table games add column public boolean not null default true;
@@ -0,0 +1,499 @@
+use crate::model::{self, DatabaseError, DatabasePool, SaveError};
+use crate::{Sender, SenderId, SubscriptionId, bots, fog, secrets, spectators, timers};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use wars::game::{ActionResult, Event, Game, GameState, PlayerNumber, action};
+use wars::protocol::{EventIndex, EventMessage, GameId, GameInfo, GameSettings, PlayerSlotType};
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    pool: DatabasePool,
+    sender: Arc<Mutex<Sender>>,
+}
+
+/// Endpoints for running the server, for requests with the admin token as
+/// their bearer token
+pub fn router<S>(token: String, pool: DatabasePool, sender: Arc<Mutex<Sender>>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let state = AdminState {
+        token: token.into(),
+        pool,
+        sender,
+    };
+    Router::new()
+        .route("/games", get(list_games))
+        .route("/games/{game_id}", get(show_game).delete(delete_game))
+        .route("/games/{game_id}/events", get(list_game_events))
+        .route("/games/{game_id}/integrity", get(check_integrity))
+        .route("/games/{game_id}/end", post(end_game))
+        .route(
+            "/games/{game_id}/players/{player_number}/kick",
+            post(kick_player),
+        )
+        .route(
+            "/games/{game_id}/players/{player_number}/bot",
+            post(replace_with_bot),
+        )
+        .route("/connections", get(list_connections))
+        .route("/connections/{sender_id}/kick", post(kick_connection))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+async fn authorize(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| secrets::same_token(token, &state.token));
+    if !authorized {
+        return AdminError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong admin token".into(),
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
+
+/// Failed admin request, answered with the message in a JSON body
+struct AdminError(StatusCode, String);
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorBody { error: self.1 })).into_response()
+    }
+}
+
+impl From<DatabaseError> for AdminError {
+    fn from(error: DatabaseError) -> Self {
+        match error {
+            DatabaseError::RowNotFound => AdminError(StatusCode::NOT_FOUND, "Not found".into()),
+            e => {
+                tracing::error!("Error in admin request: {e}");
+                AdminError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error".into(),
+                )
+            }
+        }
+    }
+}
+
+impl From<SaveError> for AdminError {
+    fn from(error: SaveError) -> Self {
+        match error {
+            SaveError::Conflict => {
+                AdminError(StatusCode::CONFLICT, "Game is busy, try again".into())
+            }
+            SaveError::Database(e) => e.into(),
+        }
+    }
+}
+
+type AdminResult<T> = Result<Json<T>, AdminError>;
+
+#[derive(Serialize)]
+struct GameDetails {
+    game: Game,
+    settings: GameSettings,
+    players: Vec<(PlayerNumber, PlayerSlotType)>,
+    last_event_index: EventIndex,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    #[serde(default)]
+    since: EventIndex,
+}
+
+#[derive(Serialize)]
+struct IntegrityReport {
+    last_event_index: EventIndex,
+    /// Empty when nothing is wrong with the game
+    violations: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct BotQuery {
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ConnectionInfo {
+    connection_id: SenderId,
+    address: Option<SocketAddr>,
+    binary: bool,
+    subscribed: Vec<GameId>,
+    spectating: Vec<GameId>,
+    /// Games joined and the player joined as in each
+    players: Vec<(GameId, PlayerNumber)>,
+}
+
+/// Every game, including private and finished ones
+async fn list_games(State(state): State<AdminState>) -> AdminResult<Vec<GameInfo>> {
+    let mut games = Vec::new();
+    for game_id in model::load_game_ids(&state.pool).await? {
+        games.push(model::load_game_info(game_id, &state.pool).await?);
+    }
+    Ok(Json(games))
+}
+
+async fn show_game(
+    State(state): State<AdminState>,
+    Path(game_id): Path<GameId>,
+) -> AdminResult<GameDetails> {
+    let (game, players, last_event_index) = model::load_game(game_id, &state.pool).await?;
+    let settings = model::load_game_settings(game_id, &state.pool).await?;
+    Ok(Json(GameDetails {
+        game,
+        settings,
+        players,
+        last_event_index,
+    }))
+}
+
+async fn list_game_events(
+    State(state): State<AdminState>,
+    Path(game_id): Path<GameId>,
+    Query(query): Query<EventsQuery>,
+) -> AdminResult<Vec<(EventIndex, Event)>> {
+    // Tell a missing game apart from one without events
+    model::load_game_settings(game_id, &state.pool).await?;
+    let events = model::load_game_events(game_id, query.since, &state.pool).await?;
+    Ok(Json(events))
+}
+
+/// Check the game state for inconsistencies, and that replaying the event log
+/// from the start gives the same game
+async fn check_integrity(
+    State(state): State<AdminState>,
+    Path(game_id): Path<GameId>,
+) -> AdminResult<IntegrityReport> {
+    let (game, _players, last_event_index) = model::load_game(game_id, &state.pool).await?;
+    let mut violations = game.integrity_violations();
+
+    let events = model::load_game_events(game_id, 0, &state.pool).await?;
+    if let Some((expected, (index, _))) = (1..)
+        .zip(&events)
+        .find(|(expected, (index, _))| index != expected)
+    {
+        violations.push(format!("Event {index} found where {expected} was expected"));
+    }
+    // Games created before the initial state was stored can't be replayed
+    if let Some(mut replayed) = model::load_initial_game(game_id, &state.pool).await? {
+        let replay = events
+            .iter()
+            .take_while(|(index, _)| *index <= last_event_index)
+            .try_for_each(|(index, event)| {
+                action::process(&mut replayed, event)
+                    .map_err(|e| format!("Event {index} could not be replayed: {e}"))
+            });
+        match replay {
+            Err(violation) => violations.push(violation),
+            Ok(()) if replayed != game => {
+                violations.push("Replaying the events gives a different game".into())
+            }
+            Ok(()) => (),
+        }
+    }
+
+    Ok(Json(IntegrityReport {
+        last_event_index,
+        violations,
+    }))
+}
+
+/// End the game in a draw
+async fn end_game(
+    State(state): State<AdminState>,
+    Path(game_id): Path<GameId>,
+) -> AdminResult<Vec<(EventIndex, Event)>> {
+    let (previous, game, events, result) =
+        model::update_game(game_id, &state.pool, |game, emit| -> ActionResult<bool> {
+            if game.state == GameState::Finished {
+                return Ok(false);
+            }
+            action::process(game, &Event::Draw)?;
+            emit(Event::Draw);
+            Ok(true)
+        })
+        .await?;
+    match result {
+        Ok(true) => (),
+        Ok(false) => {
+            return Err(AdminError(
+                StatusCode::CONFLICT,
+                format!("Game {game_id} is already finished"),
+            ));
+        }
+        Err(e) => {
+            return Err(AdminError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not end game {game_id}: {e}"),
+            ));
+        }
+    }
+    timers::forget(game_id);
+
+    let messages =
+        fog::subscriber_messages(game_id, &previous, &game, &events, &state.pool).await?;
+    let released = spectators::released_events(game_id, &game.state, &events, &state.pool).await?;
+    let mut sender = state.sender.lock().await;
+    for (recipient, message) in messages {
+        sender.send_to(recipient, &message);
+    }
+    for message in released {
+        sender.send_spectators(&(game_id as usize), &message);
+    }
+    Ok(Json(events))
+}
+
+/// Delete the game and everything stored about it. Returns the game as it was
+/// listed.
+async fn delete_game(
+    State(state): State<AdminState>,
+    Path(game_id): Path<GameId>,
+) -> AdminResult<GameInfo> {
+    let info = model::load_game_info(game_id, &state.pool).await?;
+    model::delete_game(game_id, &state.pool).await?;
+    timers::forget(game_id);
+    spectators::forget(game_id);
+    state
+        .sender
+        .lock()
+        .await
+        .remove_subscription(game_id as usize);
+    tracing::info!("Deleted game {game_id}");
+    Ok(Json(info))
+}
+
+/// Empty the player's slot. Returns the connections closed.
+async fn kick_player(
+    State(state): State<AdminState>,
+    Path((game_id, player_number)): Path<(GameId, PlayerNumber)>,
+) -> AdminResult<Vec<SenderId>> {
+    set_player(&state, game_id, player_number, PlayerSlotType::Empty).await
+}
+
+/// Let a bot, the default one unless named, play for the player. Returns the
+/// connections closed.
+async fn replace_with_bot(
+    State(state): State<AdminState>,
+    Path((game_id, player_number)): Path<(GameId, PlayerNumber)>,
+    Query(query): Query<BotQuery>,
+) -> AdminResult<Vec<SenderId>> {
+    let name = query
+        .name
+        .unwrap_or_else(|| wars::bot::DEFAULT_BOT.to_owned());
+    if !wars::bot::names().any(|bot_name| bot_name == name) {
+        return Err(AdminError(
+            StatusCode::BAD_REQUEST,
+            format!("Unknown bot {name}"),
+        ));
+    }
+    let kicked = set_player(&state, game_id, player_number, PlayerSlotType::Bot(name)).await?;
+    tokio::spawn(bots::play_turns(
+        game_id,
+        state.pool.clone(),
+        Arc::clone(&state.sender),
+    ));
+    Ok(kicked)
+}
+
+/// Put the slot in place of the player and close the connections that joined
+/// the game as the player
+async fn set_player(
+    state: &AdminState,
+    game_id: GameId,
+    player_number: PlayerNumber,
+    slot: PlayerSlotType,
+) -> AdminResult<Vec<SenderId>> {
+    let players = model::load_game_players(game_id, &state.pool).await?;
+    if !players.iter().any(|(number, _)| *number == player_number) {
+        return Err(AdminError(
+            StatusCode::NOT_FOUND,
+            format!("Game {game_id} has no player {player_number}"),
+        ));
+    }
+    model::set_game_player(game_id, player_number, &slot, None, &state.pool).await?;
+    tracing::info!("Player {player_number} in game {game_id} is now {slot:?}");
+
+    let mut sender = state.sender.lock().await;
+    let sender_ids: Vec<SenderId> = sender
+        .players
+        .get(&(game_id as usize))
+        .into_iter()
+        .flatten()
+        .filter(|(_, number)| **number == player_number)
+        .map(|(sender_id, _)| *sender_id)
+        .collect();
+    let mut kicked = Vec::new();
+    for sender_id in sender_ids {
+        if sender.kick(sender_id) {
+            kicked.push(sender_id);
+        }
+    }
+
+    let message = EventMessage::GameJoined(game_id, player_number, slot);
+    sender.send_subscribers(&(game_id as usize), &message);
+    sender.send_spectators(&(game_id as usize), &message);
+    Ok(Json(kicked))
+}
+
+async fn list_connections(State(state): State<AdminState>) -> Json<Vec<ConnectionInfo>> {
+    let sender = state.sender.lock().await;
+    let mut connections: Vec<ConnectionInfo> = sender
+        .senders
+        .keys()
+        .map(|&sender_id| {
+            let mut players: Vec<(GameId, PlayerNumber)> = sender
+                .players
+                .iter()
+                .filter_map(|(subscription_id, players)| {
+                    players
+                        .get(&sender_id)
+                        .map(|player_number| (*subscription_id as GameId, *player_number))
+                })
+                .collect();
+            players.sort();
+            ConnectionInfo {
+                connection_id: sender_id,
+                address: sender.addresses.get(&sender_id).copied(),
+                binary: sender.binary_senders.contains(&sender_id),
+                subscribed: games_with(&sender.subscriptions, sender_id),
+                spectating: games_with(&sender.spectators, sender_id),
+                players,
+            }
+        })
+        .collect();
+    connections.sort_by_key(|connection| connection.connection_id);
+    Json(connections)
+}
+
+/// Games of the subscriptions the sender is in
+fn games_with(
+    subscriptions: &HashMap<SubscriptionId, HashSet<SenderId>>,
+    sender_id: SenderId,
+) -> Vec<GameId> {
+    let mut game_ids: Vec<GameId> = subscriptions
+        .iter()
+        .filter(|(_, sender_ids)| sender_ids.contains(&sender_id))
+        .map(|(subscription_id, _)| *subscription_id as GameId)
+        .collect();
+    game_ids.sort();
+    game_ids
+}
+
+async fn kick_connection(
+    State(state): State<AdminState>,
+    Path(sender_id): Path<SenderId>,
+) -> AdminResult<SenderId> {
+    if !state.sender.lock().await.kick(sender_id) {
+        return Err(AdminError(
+            StatusCode::NOT_FOUND,
+            format!("No connection {sender_id}"),
+        ));
+    }
+    tracing::info!("Kicked connection {sender_id}");
+    Ok(Json(sender_id))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::*;
+    use crate::model::test::{database_pool, start_game};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve the admin API on a local port. Returns its address.
+    async fn serve(pool: DatabasePool) -> SocketAddr {
+        let sender = Arc::new(Mutex::new(Sender::new()));
+        let router: Router = router("secret".to_owned(), pool, sender);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        address
+    }
+
+    /// Make a request with the authorization header. Returns the status and
+    /// the body of the response.
+    async fn request(
+        address: SocketAddr,
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let authorization = authorization
+            .map(|value| format!("authorization: {value}\r\n"))
+            .unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nhost: {address}\r\n{authorization}\
+             content-length: 0\r\nconnection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_admin_token() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let address = serve(pool).await;
+
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("secret"),
+            Some("Basic secret"),
+        ] {
+            let (status, _) =
+                request(address, "GET", &format!("/games/{game_id}"), authorization).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = request(address, "POST", &format!("/games/{game_id}/end"), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = request(
+            address,
+            "GET",
+            &format!("/games/{game_id}/events"),
+            Some("Bearer secret"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("[[1,"));
+
+        let (status, _) = request(
+            address,
+            "GET",
+            &format!("/games/{}", game_id + 1),
+            Some("Bearer secret"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
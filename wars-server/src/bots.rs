@@ -0,0 +1,160 @@
+use crate::Sender;
+use crate::model::{DatabasePool, SaveError, load_game, save_game};
+use crate::timers;
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use wars::game::{Event, Game, PlayerNumber, action};
+use wars::protocol::{GameId, PlayerSlotType};
+
+/// How long a bot may plan its turn before the turn is ended on its behalf.
+/// A bot that runs over keeps its blocking thread until it finishes, but its
+/// plan is thrown away.
+const TURN_TIME_LIMIT: Duration = Duration::from_secs(20);
+
+/// Games with a bot task running, so that only one task plays each game
+static PLAYING: LazyLock<std::sync::Mutex<HashSet<GameId>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashSet::new()));
+
+/// A game in `PLAYING`, removed from there when dropped even if the task
+/// panics
+struct Playing(GameId);
+
+impl Playing {
+    fn start(game_id: GameId) -> Option<Self> {
+        PLAYING
+            .lock()
+            .unwrap()
+            .insert(game_id)
+            .then_some(Playing(game_id))
+    }
+}
+
+impl Drop for Playing {
+    fn drop(&mut self) {
+        PLAYING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Play the turns of bot players until a human is in turn or the game is
+/// over. Spawned after anything that may pass the turn to a bot.
+pub async fn play_turns(game_id: GameId, pool: DatabasePool, sender: Arc<Mutex<Sender>>) {
+    loop {
+        let Some(playing) = Playing::start(game_id) else {
+            return;
+        };
+        if let Err(e) = play_bot_turns(game_id, &pool, &sender).await {
+            tracing::error!("Error playing bot turns in game {game_id}: {e}");
+            return;
+        }
+        drop(playing);
+        // The turn may have passed to a bot after the last look but before
+        // the game was let go, when a task spawned for it gave up right away
+        match load_game(game_id, &pool).await {
+            Ok((game, players, _)) if bot_in_turn(&game, &players).is_some() => (),
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Error loading game {game_id}: {e}");
+                return;
+            }
+        }
+    }
+}
+
+async fn play_bot_turns(
+    game_id: GameId,
+    pool: &DatabasePool,
+    sender: &Mutex<Sender>,
+) -> anyhow::Result<()> {
+    loop {
+        let (previous, players, last_event_index) = load_game(game_id, pool).await?;
+        let Some((player_number, bot_name)) = bot_in_turn(&previous, &players) else {
+            return Ok(());
+        };
+
+        tracing::info!("Bot {bot_name} playing player {player_number} in game {game_id}");
+        let (game, events) = play_turn(previous.clone(), bot_name).await;
+        if events.is_empty() {
+            // Nothing changed, so the bot would be in turn again
+            anyhow::bail!("Player {player_number} couldn't end their turn");
+        }
+        let events = match save_game(game_id, last_event_index, &game, events, pool).await {
+            Ok(events) => events,
+            Err(SaveError::Conflict) => {
+                // The turn may have timed out while the bot was playing
+                tracing::info!("Game {game_id} changed during bot turn, trying again");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let time_left = timers::record_events(game_id, &game, &events, pool).await?;
+        let messages =
+            crate::fog::subscriber_messages(game_id, &previous, &game, &events, pool).await?;
+        let released =
+            crate::spectators::released_events(game_id, &game.state, &events, pool).await?;
+
+        let mut sender = sender.lock().await;
+        for (recipient, message) in messages {
+            sender.send_to(recipient, &message);
+        }
+        if let Some(message) = time_left {
+            sender.send_subscribers(&(game_id as usize), &message);
+        }
+        for message in released {
+            sender.send_spectators(&(game_id as usize), &message);
+        }
+    }
+}
+
+/// The in-turn player and the bot that plays them, if a bot does
+fn bot_in_turn(
+    game: &Game,
+    players: &[(PlayerNumber, PlayerSlotType)],
+) -> Option<(PlayerNumber, String)> {
+    let player_number = game.in_turn_number()?;
+    players.iter().find_map(|(number, slot)| match slot {
+        PlayerSlotType::Bot(name) if *number == player_number => {
+            Some((player_number, name.clone()))
+        }
+        _ => None,
+    })
+}
+
+/// Let the named bot play the in-turn player's turn. If the bot fails or runs
+/// out of time, the turn is ended after whatever it managed to do.
+async fn play_turn(game: Game, bot_name: String) -> (Game, Vec<Event>) {
+    let fallback = game.clone();
+    let planning = tokio::task::spawn_blocking(move || {
+        let mut bot = wars::bot::by_name(&bot_name).unwrap_or_else(|| {
+            tracing::info!("No bot named {bot_name}, using {}", wars::bot::DEFAULT_BOT);
+            wars::bot::by_name(wars::bot::DEFAULT_BOT).expect("Default bot is registered")
+        });
+        let mut game = game;
+        let mut events = Vec::new();
+        let result = wars::bot::play_turn(bot.as_mut(), &mut game, &mut |e| events.push(e));
+        (game, events, result)
+    });
+
+    let (mut game, mut events) = match tokio::time::timeout(TURN_TIME_LIMIT, planning).await {
+        Ok(Ok((game, events, Ok(())))) => return (game, events),
+        Ok(Ok((game, events, Err(e)))) => {
+            // Failed actions leave the game as it was, so the turn can go on
+            tracing::info!("Bot failed: {e}");
+            (game, events)
+        }
+        Ok(Err(e)) => {
+            tracing::error!("Bot crashed: {e}");
+            (fallback, Vec::new())
+        }
+        Err(_) => {
+            tracing::info!("Bot ran out of time");
+            (fallback, Vec::new())
+        }
+    };
+
+    if let Err(e) = action::end_turn(&mut game, &mut |event| events.push(event)) {
+        tracing::error!("Error ending bot turn: {e}");
+    }
+    (game, events)
+}
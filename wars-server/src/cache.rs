@@ -0,0 +1,164 @@
+//! Games in play are kept in memory so that actions don't have to parse and
+//! write the whole game. The events in the database stay the source of truth:
+//! the game snapshot there is only written now and then, and a game that is
+//! not in memory is rebuilt from the snapshot and the events after it.
+use crate::model::{DatabasePool, save_snapshot};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use wars::game::{Game, GameState};
+use wars::protocol::{EventIndex, GameId};
+
+/// Games not played for this long are dropped from memory
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How often idle games are looked for
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CachedGame {
+    game: Game,
+    last_event_index: EventIndex,
+    /// Index of the last event included in the snapshot in the database
+    snapshot_event_index: EventIndex,
+    used_at: Instant,
+}
+
+static GAMES: LazyLock<Mutex<HashMap<GameId, CachedGame>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A copy of the game and the index of its last event, if it is in memory
+pub fn get(game_id: GameId) -> Option<(Game, EventIndex)> {
+    let mut games = GAMES.lock().unwrap();
+    let cached = games.get_mut(&game_id)?;
+    cached.used_at = Instant::now();
+    Some((cached.game.clone(), cached.last_event_index))
+}
+
+pub fn state(game_id: GameId) -> Option<GameState> {
+    let games = GAMES.lock().unwrap();
+    games.get(&game_id).map(|cached| cached.game.state.clone())
+}
+
+/// Keep the game in memory unless a newer version of it already is
+pub fn insert(
+    game_id: GameId,
+    game: Game,
+    last_event_index: EventIndex,
+    snapshot_event_index: EventIndex,
+) {
+    let mut games = GAMES.lock().unwrap();
+    if games
+        .get(&game_id)
+        .is_some_and(|cached| cached.last_event_index > last_event_index)
+    {
+        return;
+    }
+    games.insert(
+        game_id,
+        CachedGame {
+            game,
+            last_event_index,
+            snapshot_event_index,
+            used_at: Instant::now(),
+        },
+    );
+}
+
+/// Forget the game so that it is loaded from the database next time
+pub fn remove(game_id: GameId) {
+    GAMES.lock().unwrap().remove(&game_id);
+}
+
+/// Snapshot and drop games that are no longer played, forever
+pub async fn run(pool: DatabasePool) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let idle: Vec<_> = {
+            let mut games = GAMES.lock().unwrap();
+            let idle_ids: Vec<_> = games
+                .iter()
+                .filter(|(_, cached)| cached.used_at.elapsed() >= IDLE_TIMEOUT)
+                .map(|(game_id, _)| *game_id)
+                .collect();
+            idle_ids
+                .into_iter()
+                .filter_map(|game_id| games.remove(&game_id).map(|cached| (game_id, cached)))
+                .collect()
+        };
+        for (game_id, cached) in idle {
+            if cached.snapshot_event_index == cached.last_event_index {
+                continue;
+            }
+            tracing::debug!("Saving snapshot of idle game {game_id}");
+            if let Err(e) =
+                save_snapshot(game_id, &cached.game, cached.last_event_index, &pool).await
+            {
+                tracing::error!("Error saving snapshot of game {game_id}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::*;
+    use crate::model::test::{database_pool, start_game};
+    use crate::model::{load_game, update_game};
+    use wars::protocol::GameSettings;
+
+    #[tokio::test]
+    async fn older_versions_of_games_are_not_kept() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let (game, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+
+        let mut older = game.clone();
+        older.round_count = 0;
+        insert(game_id, older, last_event_index - 1, 0);
+        assert!(get(game_id).unwrap().0 == game);
+
+        remove(game_id);
+        assert!(get(game_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn games_are_rebuilt_from_snapshot_and_events() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let mut bot = wars::bot::RandomBot::with_seed(2);
+        let snapshot_event_index = loop {
+            let (_, game, _, result) = update_game(game_id, &pool, |game, emit| {
+                wars::bot::play_turn(&mut bot, game, emit)
+            })
+            .await
+            .unwrap();
+            result.unwrap();
+            assert!(game.state != GameState::Finished);
+            let snapshot_event_index: EventIndex =
+                sqlx::query_scalar("select snapshot_event_index from games where id = ?1")
+                    .bind(game_id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            if snapshot_event_index > 0 {
+                break snapshot_event_index;
+            }
+        };
+        // One more turn for events after the snapshot
+        update_game(game_id, &pool, |game, emit| {
+            wars::bot::play_turn(&mut bot, game, emit)
+        })
+        .await
+        .unwrap()
+        .3
+        .unwrap();
+
+        let (game, last_event_index) = get(game_id).unwrap();
+        assert!(last_event_index > snapshot_event_index);
+        remove(game_id);
+        let (rebuilt, _, rebuilt_event_index) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(rebuilt_event_index, last_event_index);
+        assert!(rebuilt == game);
+        assert!(get(game_id).is_some());
+    }
+}
@@ -0,0 +1,141 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: wars-server [options]
+
+Options:
+    --config FILE            Configuration file (default wars-server.ron if it exists)
+    --bind ADDRESS           Address to listen on (default 127.0.0.1:3000)
+    --database PATH          SQLite database file (default wars.db)
+    --assets DIR             Directory of static files to serve
+    --maps DIR               Directory of map files to load, can be repeated
+    --log FILTER             Logging filter, overridden by RUST_LOG
+    --max-games N            Games that can be in progress at once
+    --max-connections N      Clients that can be connected at once
+    --max-message-size BYTES Largest message accepted from clients
+    --max-subscriptions N    Games a client can follow at once
+    --admin-token TOKEN      Enable the admin API at /admin for requests bearing the token.
+                             Other users can see it in ps, so prefer admin_token in the
+                             configuration file.
+
+Options given on the command line override those in the configuration file.
+";
+
+/// Configuration file looked for when none is given
+const DEFAULT_CONFIG_PATH: &str = "wars-server.ron";
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub database_path: PathBuf,
+    pub assets_dir: PathBuf,
+    /// Directories of maps loaded in addition to the built-in maps
+    pub map_dirs: Vec<PathBuf>,
+    pub log_filter: String,
+    pub limits: Limits,
+    /// Bearer token of the admin API, which is disabled without one. Best set
+    /// in the configuration file, as command lines are visible to other users.
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            database_path: PathBuf::from("wars.db"),
+            assets_dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets"),
+            map_dirs: Vec::new(),
+            log_filter: format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")),
+            limits: Limits::default(),
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    /// Games not yet finished
+    pub max_games: Option<usize>,
+    pub max_connections: Option<usize>,
+    /// Bytes in a single message from a client
+    pub max_message_size: usize,
+    /// Games a connection subscribes to or spectates at once
+    pub max_subscriptions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_games: None,
+            max_connections: None,
+            max_message_size: 64 << 20,
+            max_subscriptions: 16,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration file and apply the command line options on top
+    /// of it. The error is shown to the user with the usage text.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+        let config_path = args
+            .iter()
+            .position(|arg| arg == "--config")
+            .map(|i| {
+                args.get(i + 1)
+                    .map(PathBuf::from)
+                    .ok_or("Invalid or missing value for --config")
+            })
+            .transpose()?;
+        let mut config = match config_path {
+            Some(path) => Self::read(&path)?,
+            None if std::fs::exists(DEFAULT_CONFIG_PATH).unwrap_or(false) => {
+                Self::read(DEFAULT_CONFIG_PATH.as_ref())?
+            }
+            None => Self::default(),
+        };
+
+        fn value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+            value
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| format!("Invalid or missing value for {option}"))
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    args.next();
+                }
+                "--bind" => config.bind_address = value(&arg, args.next())?,
+                "--database" => config.database_path = value(&arg, args.next())?,
+                "--assets" => config.assets_dir = value(&arg, args.next())?,
+                "--maps" => config.map_dirs.push(value(&arg, args.next())?),
+                "--log" => config.log_filter = value(&arg, args.next())?,
+                "--max-games" => config.limits.max_games = Some(value(&arg, args.next())?),
+                "--max-connections" => {
+                    config.limits.max_connections = Some(value(&arg, args.next())?)
+                }
+                "--max-message-size" => config.limits.max_message_size = value(&arg, args.next())?,
+                "--max-subscriptions" => {
+                    config.limits.max_subscriptions = value(&arg, args.next())?
+                }
+                "--admin-token" => config.admin_token = Some(value(&arg, args.next())?),
+                "--help" | "-h" => return Err(String::new()),
+                _ => return Err(format!("Unexpected argument {arg}")),
+            }
+        }
+        Ok(config)
+    }
+
+    fn read(path: &std::path::Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+        ron::from_str(&content).map_err(|e| format!("Error in {}: {e}", path.display()))
+    }
+}
@@ -0,0 +1,148 @@
+use crate::model::{DatabasePool, DatabaseResult, load_game_players, load_game_settings};
+use crate::state::{Events, Recipient};
+use wars::game::{Event, Game, PlayerNumber, action};
+use wars::protocol::{EventIndex, EventMessage, GameId};
+
+/// Whether replaying the events on what the viewer saw of the game before
+/// brings them to what they see of it now. If not, the events involve
+/// something hidden from the viewer.
+fn reveals_nothing(
+    previous: &Game,
+    game: &Game,
+    events: &[(EventIndex, Event)],
+    viewer: Option<PlayerNumber>,
+) -> bool {
+    let mut view = previous.view_for(viewer);
+    events
+        .iter()
+        .all(|(_, event)| action::process(&mut view, event).is_ok())
+        && view == game.view_for(viewer)
+}
+
+/// The game as the viewer sees it, for them to pick up from instead of the
+/// events up to `last_event_index`
+pub async fn game_state(
+    game_id: GameId,
+    game: &Game,
+    viewer: Option<PlayerNumber>,
+    last_event_index: EventIndex,
+    pool: &DatabasePool,
+) -> DatabaseResult<EventMessage> {
+    let settings = load_game_settings(game_id, pool).await?;
+    let players = load_game_players(game_id, pool).await?;
+    Ok(EventMessage::GameState(
+        Box::new(game.view_for(viewer)),
+        settings.without_password(),
+        players,
+        last_event_index,
+    ))
+}
+
+/// Messages for the subscribers of the game once `events` took it from
+/// `previous` to `game`. Without fog of war everyone gets the events. With it
+/// each player gets the events only if those show nothing hidden from the
+/// player, and otherwise the game as the player now sees it.
+pub async fn subscriber_messages(
+    game_id: GameId,
+    previous: &Game,
+    game: &Game,
+    events: &[(EventIndex, Event)],
+    pool: &DatabasePool,
+) -> DatabaseResult<Events> {
+    let event_messages = |recipient| {
+        events.iter().map(move |(index, event)| {
+            (
+                recipient,
+                EventMessage::GameEvent(game_id, *index, event.clone()),
+            )
+        })
+    };
+    if !game.rules.fog {
+        return Ok(event_messages(Recipient::Subscribers(game_id)).collect());
+    }
+    let Some((last_event_index, _)) = events.last() else {
+        return Ok(Events::new());
+    };
+
+    let viewers = game
+        .players
+        .iter()
+        .map(|player| Some(player.number))
+        .chain([None]);
+    let mut messages = Events::new();
+    for viewer in viewers {
+        let recipient = Recipient::Viewers(game_id, viewer);
+        if reveals_nothing(previous, game, events, viewer) {
+            messages.extend(event_messages(recipient));
+        } else {
+            let state = game_state(game_id, game, viewer, *last_event_index, pool).await?;
+            messages.push((recipient, state));
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fog::*;
+    use crate::model::test::{database_pool, start_game};
+    use crate::model::update_game;
+    use wars::game::{GameState, Rules};
+    use wars::protocol::GameSettings;
+
+    #[tokio::test]
+    async fn hidden_units_never_reach_other_players() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            rules: Rules {
+                fog: true,
+                ..Rules::default()
+            },
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        let (game, _players, _last_event_index) =
+            crate::model::load_game(game_id, &pool).await.unwrap();
+        let mut view = game.view_for(Some(1));
+        let mut bot = wars::bot::RandomBot::with_seed(3);
+        let mut states = 0;
+
+        for _ in 0..12 {
+            let (previous, game, events, result) = update_game(game_id, &pool, |game, emit| {
+                wars::bot::play_turn(&mut bot, game, emit)
+            })
+            .await
+            .unwrap();
+            result.unwrap();
+            let messages = subscriber_messages(game_id, &previous, &game, &events, &pool)
+                .await
+                .unwrap();
+            for (recipient, message) in messages {
+                let Recipient::Viewers(_, Some(1)) = recipient else {
+                    continue;
+                };
+                match message {
+                    EventMessage::GameEvent(_, _, event) => {
+                        action::process(&mut view, &event).unwrap()
+                    }
+                    EventMessage::GameState(state, ..) => {
+                        view = *state;
+                        states += 1;
+                    }
+                    _ => panic!("Unexpected message for a player"),
+                }
+            }
+
+            assert!(view == game.view_for(Some(1)));
+            let visible_positions = game.visible_positions(1);
+            for (unit_id, _) in view.units.owned_by_player(2) {
+                let (_, tile) = view.tiles.get_unit_tile(unit_id).unwrap();
+                assert!(visible_positions.contains(&tile.position()));
+            }
+            if game.state == GameState::Finished {
+                break;
+            }
+        }
+        assert!(states > 0);
+    }
+}
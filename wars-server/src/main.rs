@@ -2,17 +2,24 @@ use axum::{
     Router,
     extract::State,
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    http::StatusCode,
     response::IntoResponse,
     routing::any,
 };
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use wars::protocol::{self, ActionMessage, EventMessage};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+use wars::auth::UserId;
+use wars::game::PlayerNumber;
+use wars::protocol::{
+    self, ActionMessage, ErrorCode, ErrorReply, EventMessage, Request, RequestId, Response,
+};
 
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
-    path::PathBuf,
 };
 use tower_http::{
     services::ServeDir,
@@ -21,96 +28,444 @@ use tower_http::{
 
 use axum::extract::connect_info::ConnectInfo;
 use futures_util::{
-    Sink, SinkExt, Stream, StreamExt,
+    SinkExt, StreamExt,
     stream::{SplitSink, SplitStream},
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod admin;
+mod bots;
+mod cache;
+mod config;
+mod fog;
+mod maps;
+mod metrics;
 mod model;
+mod secrets;
+mod spectators;
 mod state;
+mod timers;
+mod webhooks;
 
 type SenderId = usize;
 type SubscriptionId = usize;
 type SenderSocket = SplitSink<WebSocket, Message>;
 type SenderMessage = Message;
+/// Messages waiting to be written to the socket of a connection
+type SenderQueue = mpsc::Sender<SenderMessage>;
+
+/// Clients connected at the moment
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// A place among the `CONNECTIONS`, given back when dropped
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    /// Take a place unless `max` connections already have one
+    fn take(max: Option<usize>) -> Option<Self> {
+        CONNECTIONS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                max.is_none_or(|max| count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Messages a connection may have waiting before it is disconnected as too
+/// slow to keep up
+const SENDER_QUEUE_SIZE: usize = 256;
+/// How long writing a message to a socket may take before the connection is
+/// given up on
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
 struct Sender {
-    senders: HashMap<SenderId, SenderSocket>,
+    senders: HashMap<SenderId, SenderQueue>,
+    /// Where senders are connected from
+    addresses: HashMap<SenderId, SocketAddr>,
+    /// Senders that talk in binary messages rather than text
+    binary_senders: HashSet<SenderId>,
     next_sender_id: SenderId,
     subscriptions: HashMap<SubscriptionId, HashSet<SenderId>>,
+    /// Senders watching games as spectators, who may see them with a delay
+    spectators: HashMap<SubscriptionId, HashSet<SenderId>>,
+    /// Players senders have joined games as, for team chat
+    players: HashMap<SubscriptionId, HashMap<SenderId, PlayerNumber>>,
+    /// Users whose chat messages senders don't want to see
+    muted: HashMap<SenderId, HashSet<UserId>>,
 }
 
 impl Sender {
     fn new() -> Self {
         Self {
             senders: HashMap::new(),
+            addresses: HashMap::new(),
+            binary_senders: HashSet::new(),
             next_sender_id: 1,
             subscriptions: HashMap::new(),
+            spectators: HashMap::new(),
+            players: HashMap::new(),
+            muted: HashMap::new(),
         }
     }
-    fn add_sender(&mut self, sender: SenderSocket) -> SenderId {
+    fn add_sender(&mut self, sender: SenderQueue, addr: SocketAddr) -> SenderId {
         let sender_id = self.next_sender_id;
         self.next_sender_id += 1;
         self.senders.insert(sender_id, sender);
+        self.addresses.insert(sender_id, addr);
         sender_id
     }
+    /// Forget the sender everywhere, returning its queue if it had one.
+    /// Dropping the queue ends the connection once the queued messages are
+    /// written.
+    fn remove_sender(&mut self, sender_id: SenderId) -> Option<SenderQueue> {
+        self.addresses.remove(&sender_id);
+        self.binary_senders.remove(&sender_id);
+        self.muted.remove(&sender_id);
+        self.subscriptions.retain(|_, sender_ids| {
+            sender_ids.remove(&sender_id);
+            !sender_ids.is_empty()
+        });
+        self.spectators.retain(|_, sender_ids| {
+            sender_ids.remove(&sender_id);
+            !sender_ids.is_empty()
+        });
+        self.players.retain(|_, players| {
+            players.remove(&sender_id);
+            !players.is_empty()
+        });
+        self.senders.remove(&sender_id)
+    }
+    /// Close the connection of the sender. Returns whether it was connected.
+    fn kick(&mut self, sender_id: SenderId) -> bool {
+        let Some(sender) = self.remove_sender(sender_id) else {
+            return false;
+        };
+        // A full queue is dropped without the close message
+        let _ = sender.try_send(Message::Close(None));
+        true
+    }
+    /// Forget who watches a game that no longer exists
+    fn remove_subscription(&mut self, subscription_id: SubscriptionId) {
+        self.subscriptions.remove(&subscription_id);
+        self.spectators.remove(&subscription_id);
+        self.players.remove(&subscription_id);
+    }
+    fn set_binary(&mut self, sender_id: SenderId, binary: bool) {
+        if binary {
+            self.binary_senders.insert(sender_id);
+        } else {
+            self.binary_senders.remove(&sender_id);
+        }
+    }
     fn subscribe(&mut self, sender_id: SenderId, subscription_id: SubscriptionId) {
+        if let Some(spectators) = self.spectators.get_mut(&subscription_id) {
+            spectators.remove(&sender_id);
+        }
         let subscription = self.subscriptions.entry(subscription_id).or_default();
         subscription.insert(sender_id);
     }
-    async fn send(&mut self, sender_id: &SenderId, message: Message) -> Result<(), axum::Error> {
-        let Some(sender) = self.senders.get_mut(sender_id) else {
-            return Ok(());
+    fn spectate(&mut self, sender_id: SenderId, subscription_id: SubscriptionId) {
+        if let Some(subscription) = self.subscriptions.get_mut(&subscription_id) {
+            subscription.remove(&sender_id);
+        }
+        let spectators = self.spectators.entry(subscription_id).or_default();
+        spectators.insert(sender_id);
+    }
+    fn join(
+        &mut self,
+        sender_id: SenderId,
+        subscription_id: SubscriptionId,
+        player_number: PlayerNumber,
+    ) {
+        let players = self.players.entry(subscription_id).or_default();
+        players.insert(sender_id, player_number);
+    }
+    /// Stop sending the sender the events of the game, including its team chat
+    fn unsubscribe(&mut self, sender_id: SenderId, subscription_id: SubscriptionId) {
+        for sender_ids in [&mut self.subscriptions, &mut self.spectators] {
+            if let Some(subscription) = sender_ids.get_mut(&subscription_id) {
+                subscription.remove(&sender_id);
+                if subscription.is_empty() {
+                    sender_ids.remove(&subscription_id);
+                }
+            }
+        }
+        if let Some(players) = self.players.get_mut(&subscription_id) {
+            players.remove(&sender_id);
+            if players.is_empty() {
+                self.players.remove(&subscription_id);
+            }
+        }
+    }
+    fn mute(&mut self, sender_id: SenderId, user_id: UserId, muted: bool) {
+        let muted_users = self.muted.entry(sender_id).or_default();
+        if muted {
+            muted_users.insert(user_id);
+        } else {
+            muted_users.remove(&user_id);
+        }
+    }
+    /// Whether the sender has muted the author of the event
+    fn is_muted(
+        muted: &HashMap<SenderId, HashSet<UserId>>,
+        sender_id: &SenderId,
+        event: &EventMessage,
+    ) -> bool {
+        let EventMessage::Chat(message) = event else {
+            return false;
         };
-        sender.send(message).await
+        muted
+            .get(sender_id)
+            .is_some_and(|muted_users| muted_users.contains(&message.author))
     }
-    async fn send_subscribers(
+    /// Send the reply to the request unless the sender has muted its author.
+    /// Returns whether the sender is still connected.
+    fn send_event(
         &mut self,
-        subscription_id: &SubscriptionId,
-        message: Message,
-    ) -> Result<(), axum::Error> {
+        sender_id: &SenderId,
+        event: &EventMessage,
+        request_id: Option<RequestId>,
+        binary: bool,
+    ) -> bool {
+        if Self::is_muted(&self.muted, sender_id, event) {
+            return self.senders.contains_key(sender_id);
+        }
+        self.send(sender_id, serialize_event(event, request_id, binary))
+    }
+    /// Queue the message for the sender. Returns whether the sender is still
+    /// connected.
+    fn send(&mut self, sender_id: &SenderId, message: Message) -> bool {
+        let Some(sender) = self.senders.get(sender_id) else {
+            return false;
+        };
+        if Self::queue(sender_id, sender, message) {
+            return true;
+        }
+        self.remove_sender(*sender_id);
+        false
+    }
+    /// Queue the message unless the sender is gone or too far behind to catch
+    /// up. Returns whether the message was queued.
+    fn queue(sender_id: &SenderId, sender: &SenderQueue, message: Message) -> bool {
+        match sender.try_send(message) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::info!("Disconnecting {sender_id}, too slow to keep up");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+    /// Send the event to every subscriber in the format they use
+    fn send_subscribers(&mut self, subscription_id: &SubscriptionId, event: &EventMessage) {
         let Some(sender_ids) = self.subscriptions.get(subscription_id) else {
-            return Ok(());
+            return;
+        };
+        let disconnected = Self::send_all(
+            &self.senders,
+            &self.binary_senders,
+            &self.muted,
+            sender_ids,
+            event,
+        );
+        self.remove_senders(disconnected);
+    }
+    /// Send the event to every spectator in the format they use
+    fn send_spectators(&mut self, subscription_id: &SubscriptionId, event: &EventMessage) {
+        let Some(sender_ids) = self.spectators.get(subscription_id) else {
+            return;
+        };
+        let disconnected = Self::send_all(
+            &self.senders,
+            &self.binary_senders,
+            &self.muted,
+            sender_ids,
+            event,
+        );
+        self.remove_senders(disconnected);
+    }
+    /// Send the event to every sender that joined the game as the player
+    fn send_team(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        player_number: PlayerNumber,
+        event: &EventMessage,
+    ) {
+        let Some(players) = self.players.get(subscription_id) else {
+            return;
         };
-        let mut result = Ok(());
+        let sender_ids = players
+            .iter()
+            .filter(|(_, pn)| **pn == player_number)
+            .map(|(sender_id, _)| *sender_id)
+            .collect();
+        let disconnected = Self::send_all(
+            &self.senders,
+            &self.binary_senders,
+            &self.muted,
+            &sender_ids,
+            event,
+        );
+        self.remove_senders(disconnected);
+    }
+    /// Send the event to every subscriber that joined the game as the player,
+    /// or that hasn't joined it when `player_number` is `None`
+    fn send_viewers(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        player_number: Option<PlayerNumber>,
+        event: &EventMessage,
+    ) {
+        let Some(subscribers) = self.subscriptions.get(subscription_id) else {
+            return;
+        };
+        let players = self.players.get(subscription_id);
+        let sender_ids = subscribers
+            .iter()
+            .filter(|sender_id| {
+                players.and_then(|players| players.get(sender_id)).copied() == player_number
+            })
+            .copied()
+            .collect();
+        let disconnected = Self::send_all(
+            &self.senders,
+            &self.binary_senders,
+            &self.muted,
+            &sender_ids,
+            event,
+        );
+        self.remove_senders(disconnected);
+    }
+    /// Send the event to every connected sender
+    fn send_everyone(&mut self, event: &EventMessage) {
+        let sender_ids = self.senders.keys().copied().collect();
+        let disconnected = Self::send_all(
+            &self.senders,
+            &self.binary_senders,
+            &self.muted,
+            &sender_ids,
+            event,
+        );
+        self.remove_senders(disconnected);
+    }
+    /// Queue the event for each of the senders without waiting for any of
+    /// them. Returns the senders that are gone or too slow to keep up.
+    fn send_all(
+        senders: &HashMap<SenderId, SenderQueue>,
+        binary_senders: &HashSet<SenderId>,
+        muted: &HashMap<SenderId, HashSet<UserId>>,
+        sender_ids: &HashSet<SenderId>,
+        event: &EventMessage,
+    ) -> Vec<SenderId> {
+        let mut disconnected = Vec::new();
         for sender_id in sender_ids {
-            let Some(sender) = self.senders.get_mut(sender_id) else {
-                return Ok(());
+            let Some(sender) = senders.get(sender_id) else {
+                continue;
             };
+            if Self::is_muted(muted, sender_id, event) {
+                continue;
+            }
 
-            if let Err(e) = sender.send(message.clone()).await {
-                result = Err(e);
+            let message = serialize_event(event, None, binary_senders.contains(sender_id));
+            if !Self::queue(sender_id, sender, message) {
+                disconnected.push(*sender_id);
             }
         }
-        result
+        disconnected
+    }
+    /// Send the event to its recipients. Replies to the actor of a request are
+    /// sent with `send_event` instead.
+    fn send_to(&mut self, recipient: state::Recipient, event: &EventMessage) {
+        match recipient {
+            state::Recipient::Actor => tracing::error!("No request to reply to"),
+            state::Recipient::Everyone => self.send_everyone(event),
+            state::Recipient::Subscribers(game_id) => {
+                self.send_subscribers(&(game_id as usize), event)
+            }
+            state::Recipient::Spectators(game_id) => {
+                self.send_spectators(&(game_id as usize), event)
+            }
+            state::Recipient::Team(game_id, player_number) => {
+                self.send_team(&(game_id as usize), player_number, event)
+            }
+            state::Recipient::Viewers(game_id, player_number) => {
+                self.send_viewers(&(game_id as usize), player_number, event)
+            }
+        }
+    }
+    fn remove_senders(&mut self, sender_ids: Vec<SenderId>) {
+        for sender_id in sender_ids {
+            self.remove_sender(sender_id);
+        }
     }
 }
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let config = match config::Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{message}\n");
+            }
+            eprint!("{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
+
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
-            }),
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| config.log_filter.clone().into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
-
-    let database_pool = model::new_database_pool("wars.db").await?;
+    let database_pool = model::new_database_pool(&config.database_path).await?;
+    maps::import(&config.map_dirs, &database_pool).await?;
+    model::migrate_next_unit_ids(&database_pool).await?;
+    model::migrate_game_columns(&database_pool).await?;
     let sender = Arc::new(Mutex::new(Sender::new()));
 
-    let app = Router::new()
-        .fallback_service(ServeDir::new(assets_dir).append_index_html_on_directories(true))
+    // Pick up turn clocks and bot turns that were interrupted by a restart
+    for game_id in model::load_game_ids(&database_pool).await? {
+        timers::restore(game_id, &database_pool).await?;
+        tokio::spawn(bots::play_turns(
+            game_id,
+            database_pool.clone(),
+            Arc::clone(&sender),
+        ));
+    }
+    tokio::spawn(timers::run(database_pool.clone(), Arc::clone(&sender)));
+    tokio::spawn(cache::run(database_pool.clone()));
+
+    let mut app = Router::new()
+        .fallback_service(ServeDir::new(&config.assets_dir).append_index_html_on_directories(true))
         .route("/ws", any(ws_handler))
-        .with_state((database_pool, sender))
+        .merge(metrics::router(database_pool.clone(), Arc::clone(&sender)));
+    match config.admin_token {
+        Some(token) => {
+            app = app.nest(
+                "/admin",
+                admin::router(token, database_pool.clone(), Arc::clone(&sender)),
+            )
+        }
+        None => tracing::info!("Admin API disabled, no admin token configured"),
+    }
+    let app = app
+        .with_state((database_pool, sender, config.limits))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         );
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.bind_address)
         .await
         .unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
@@ -125,94 +480,321 @@ async fn main() -> anyhow::Result<()> {
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    State((pool, sender)): State<(model::DatabasePool, Arc<Mutex<Sender>>)>,
+    State((pool, sender, limits)): State<(model::DatabasePool, Arc<Mutex<Sender>>, config::Limits)>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
+) -> axum::response::Response {
+    // The place is given back when the connection ends, or when the closure
+    // is dropped if the upgrade fails
+    let Some(slot) = ConnectionSlot::take(limits.max_connections) else {
+        tracing::info!("Turning away {addr}, too many connections");
+        return (StatusCode::SERVICE_UNAVAILABLE, "Too many connections").into_response();
+    };
     let sender = Arc::clone(&sender);
-    ws.on_upgrade(async move |socket| {
-        let (write, read) = socket.split();
-        let sender_id = sender.lock().await.add_sender(write);
-        let _ = handle_socket(read, sender, sender_id, addr, pool).await;
-    })
+    ws.max_message_size(limits.max_message_size)
+        .on_upgrade(async move |socket| {
+            let _slot = slot;
+            let (write, read) = socket.split();
+            let (queue, queued) = mpsc::channel(SENDER_QUEUE_SIZE);
+            let sender_id = sender.lock().await.add_sender(queue, addr);
+            let mut writer = tokio::spawn(write_messages(write, queued, sender_id));
+            handle_socket(
+                read,
+                &mut writer,
+                Arc::clone(&sender),
+                sender_id,
+                addr,
+                pool,
+                limits,
+            )
+            .await;
+            sender.lock().await.remove_sender(sender_id);
+            writer.abort();
+        })
+        .into_response()
+}
+
+/// Write the queued messages to the socket until the queue is dropped, the
+/// connection is closed or the client stops taking messages
+async fn write_messages(
+    mut socket: SenderSocket,
+    mut queue: mpsc::Receiver<SenderMessage>,
+    sender_id: SenderId,
+) {
+    while let Some(message) = queue.recv().await {
+        let close = matches!(message, Message::Close(_));
+        match tokio::time::timeout(WRITE_TIMEOUT, socket.send(message)).await {
+            Ok(Ok(())) if !close => (),
+            Ok(Ok(())) => break,
+            Ok(Err(e)) => {
+                tracing::info!("Error writing to {sender_id}: {e}");
+                break;
+            }
+            Err(_) => {
+                tracing::info!("Disconnecting {sender_id}, writing timed out");
+                break;
+            }
+        }
+    }
 }
 
+/// Process requests from the client until it disconnects or its messages
+/// stop being written
 async fn handle_socket(
     mut read: SplitStream<WebSocket>,
+    writer: &mut JoinHandle<()>,
     sender: Arc<Mutex<Sender>>,
     sender_id: SenderId,
     who: SocketAddr,
     pool: model::DatabasePool,
-) -> Result<(), axum::Error> {
-    let mut state = state::State::new();
+    limits: config::Limits,
+) {
+    // There are no user accounts, so users are told apart by their connection
+    let mut state = state::State::new(sender_id as UserId, limits);
 
-    sender
-        .lock()
-        .await
-        .send(
-            &sender_id,
-            serialize_event(
-                &EventMessage::ServerVersion(protocol::VERSION.to_owned()),
-                false,
-            ),
-        )
-        .await?;
+    sender.lock().await.send(
+        &sender_id,
+        serialize_event(
+            &EventMessage::ServerVersion(protocol::VERSION.to_owned()),
+            None,
+            false,
+        ),
+    );
+
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = &mut *writer => break,
+        };
+        // Clients that agreed on binary get it, others are answered in kind
+        let binary = matches!(msg, Message::Binary(_))
+            || state
+                .handshake()
+                .is_some_and(|handshake| handshake.supports(protocol::Capability::Binary));
+        sender.lock().await.set_binary(sender_id, binary);
 
-    while let Some(Ok(msg)) = read.next().await {
-        let binary = matches!(msg, Message::Binary(_));
+        let Request {
+            id: request_id,
+            action,
+        } = match parse_request(msg, who) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = EventMessage::Error(ErrorReply::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Could not parse request: {e}"),
+                ));
+                if !sender
+                    .lock()
+                    .await
+                    .send_event(&sender_id, &error, None, binary)
+                {
+                    break;
+                }
+                continue;
+            }
+        };
 
-        let action = parse_action(msg, who);
+        // Actions that can pass the turn to a bot
+        let bot_game_id = match &action {
+            ActionMessage::GameAction(game_id, _)
+            | ActionMessage::StartGame(game_id)
+            | ActionMessage::SetPlayerSlotType(game_id, _, _) => Some(*game_id),
+            _ => None,
+        };
+        let joined_game_id = match &action {
+            ActionMessage::JoinGame(game_id, _, _) => Some(*game_id),
+            _ => None,
+        };
+        let followed_game_id = match &action {
+            ActionMessage::SubscribeGame(game_id) | ActionMessage::SpectateGame(game_id) => {
+                Some(*game_id)
+            }
+            _ => None,
+        };
 
         // Protocol level processing
         match action {
-            Ok(ActionMessage::Quit) => break,
-            Ok(ActionMessage::SubscribeGame(game_id)) => {
+            ActionMessage::Quit => break,
+            // Only players get the events as they happen, others spectate
+            ActionMessage::SubscribeGame(game_id) if state.player_number(game_id).is_some() => {
                 sender.lock().await.subscribe(sender_id, game_id as usize)
             }
-            Err(_) => break,
+            ActionMessage::SubscribeGame(game_id) | ActionMessage::SpectateGame(game_id) => {
+                sender.lock().await.spectate(sender_id, game_id as usize)
+            }
+            // Spectators that join the game become subscribers
+            ActionMessage::JoinGame(game_id, _, _) if state.is_spectating(game_id) => {
+                sender.lock().await.subscribe(sender_id, game_id as usize)
+            }
+            ActionMessage::UnsubscribeGame(game_id) => {
+                sender.lock().await.unsubscribe(sender_id, game_id as usize)
+            }
+            ActionMessage::MuteUser(user_id) => sender.lock().await.mute(sender_id, user_id, true),
+            ActionMessage::UnmuteUser(user_id) => {
+                sender.lock().await.mute(sender_id, user_id, false)
+            }
             _ => (),
         };
 
         // State level processing
-        if let Ok(action) = action {
-            let events = state.action(action, &pool).await;
-
-            for (recipient, event) in events {
-                match recipient {
-                    state::Recipient::Actor => {
-                        sender
-                            .lock()
-                            .await
-                            .send(&sender_id, serialize_event(&event, binary))
-                            .await?
-                    }
-                    state::Recipient::Subscribers(game_id) => {
-                        sender
-                            .lock()
-                            .await
-                            .send_subscribers(&(game_id as usize), serialize_event(&event, binary))
-                            .await?
-                    }
+        let events = state.action(action, &pool).await;
+        let incompatible = events.iter().any(|(_, event)| {
+            matches!(
+                event,
+                EventMessage::Error(ErrorReply {
+                    code: ErrorCode::IncompatibleVersion,
+                    ..
+                })
+            )
+        });
+
+        // Subscribing before the state level processing keeps events saved in
+        // the meantime from being missed, so undo it if the game was refused
+        if let Some(game_id) = followed_game_id
+            && !state.is_following(game_id)
+        {
+            sender.lock().await.unsubscribe(sender_id, game_id as usize);
+        }
+        if let Some(game_id) = joined_game_id
+            && state.is_spectating(game_id)
+        {
+            sender.lock().await.spectate(sender_id, game_id as usize);
+        }
+        if let Some(game_id) = joined_game_id.or(followed_game_id)
+            && let Some(player_number) = state.player_number(game_id)
+        {
+            sender
+                .lock()
+                .await
+                .join(sender_id, game_id as usize, player_number);
+        }
+
+        let mut connected = true;
+        for (recipient, event) in events {
+            let mut sender = sender.lock().await;
+            match recipient {
+                state::Recipient::Actor => {
+                    connected &= sender.send_event(&sender_id, &event, Some(request_id), binary)
                 }
+                recipient => sender.send_to(recipient, &event),
             }
         }
-    }
 
-    Ok(())
+        if let Some(game_id) = bot_game_id {
+            tokio::spawn(bots::play_turns(game_id, pool.clone(), Arc::clone(&sender)));
+        }
+        if incompatible || !connected {
+            break;
+        }
+    }
 }
 
-fn parse_action(msg: Message, _who: SocketAddr) -> Result<ActionMessage, protocol::Error> {
-    match msg {
-        Message::Text(t) => ActionMessage::from_text(&t),
-        Message::Binary(d) => ActionMessage::from_bytes(&d),
-        Message::Close(_) => Ok(ActionMessage::Quit),
-        _ => Ok(ActionMessage::NoOp),
-    }
+fn parse_request(msg: Message, _who: SocketAddr) -> Result<Request, protocol::Error> {
+    let action = match msg {
+        Message::Text(t) => return Request::from_text(&t),
+        Message::Binary(d) => return Request::from_bytes(&d),
+        Message::Close(_) => ActionMessage::Quit,
+        _ => ActionMessage::NoOp,
+    };
+    Ok(Request { id: 0, action })
 }
 
-fn serialize_event(event: &EventMessage, binary: bool) -> Message {
+fn serialize_event(event: &EventMessage, request_id: Option<RequestId>, binary: bool) -> Message {
+    let response = Response {
+        request_id,
+        event: event.clone(),
+    };
     if binary {
-        Message::Binary(event.as_bytes().unwrap().into())
+        Message::Binary(response.as_bytes().unwrap().into())
     } else {
-        Message::Text(event.as_text().unwrap().into())
+        Message::Text(response.as_text().unwrap().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use wars::protocol::{ChatMessage, ChatScope};
+
+    fn connect(sender: &mut Sender, queue_size: usize) -> (SenderId, mpsc::Receiver<Message>) {
+        let (queue, messages) = mpsc::channel(queue_size);
+        let sender_id = sender.add_sender(queue, "127.0.0.1:1234".parse().unwrap());
+        (sender_id, messages)
+    }
+
+    fn chat(author: UserId) -> EventMessage {
+        EventMessage::Chat(ChatMessage {
+            scope: ChatScope::Game(1),
+            author,
+            player_number: None,
+            text: "Hello".to_owned(),
+        })
+    }
+
+    #[test]
+    fn muted_users_are_not_heard() {
+        let mut sender = Sender::new();
+        let (listener, listener_messages) = connect(&mut sender, 8);
+        let (other, other_messages) = connect(&mut sender, 8);
+        sender.subscribe(listener, 1);
+        sender.subscribe(other, 1);
+        sender.mute(listener, 7, true);
+
+        sender.send_subscribers(&1, &chat(7));
+        sender.send_subscribers(&1, &chat(8));
+        sender.send_subscribers(&1, &EventMessage::GameStarted(1));
+        assert!(sender.send_event(&listener, &chat(7), None, false));
+        assert_eq!(listener_messages.len(), 2);
+        assert_eq!(other_messages.len(), 3);
+
+        sender.mute(listener, 7, false);
+        sender.send_subscribers(&1, &chat(7));
+        assert_eq!(listener_messages.len(), 3);
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_turned_away() {
+        let max = Some(CONNECTIONS.load(Ordering::Relaxed) + 1);
+        let slot = ConnectionSlot::take(max);
+        assert!(slot.is_some());
+        assert!(ConnectionSlot::take(max).is_none());
+        drop(slot);
+        assert!(ConnectionSlot::take(max).is_some());
+    }
+
+    #[test]
+    fn senders_too_slow_to_keep_up_are_dropped() {
+        let mut sender = Sender::new();
+        let (slow, slow_messages) = connect(&mut sender, 2);
+        let (fast, fast_messages) = connect(&mut sender, 8);
+        sender.subscribe(slow, 1);
+        sender.subscribe(fast, 1);
+        sender.join(slow, 1, 1);
+
+        for _ in 0..3 {
+            sender.send_subscribers(&1, &EventMessage::GameStarted(1));
+        }
+        assert_eq!(slow_messages.len(), 2);
+        assert_eq!(fast_messages.len(), 3);
+        assert!(!sender.senders.contains_key(&slow));
+        assert!(!sender.subscriptions[&1].contains(&slow));
+        assert!(!sender.players.contains_key(&1));
+        assert!(!sender.send_event(&slow, &EventMessage::Pong, None, false));
+        assert!(sender.send_event(&fast, &EventMessage::Pong, None, false));
+    }
+
+    #[test]
+    fn closed_connections_are_forgotten() {
+        let mut sender = Sender::new();
+        let (closed, closed_messages) = connect(&mut sender, 8);
+        sender.spectate(closed, 1);
+        drop(closed_messages);
+
+        sender.send_spectators(&1, &EventMessage::GameStarted(1));
+        assert!(!sender.senders.contains_key(&closed));
+        assert!(!sender.spectators.contains_key(&1));
+        assert!(!sender.kick(closed));
     }
 }
@@ -0,0 +1,52 @@
+use crate::model::{DatabasePool, save_server_map};
+use crate::timers;
+use include_dir::{File, include_dir};
+use std::path::PathBuf;
+use wars::game::Map;
+
+fn builtin() -> Vec<Map> {
+    include_dir!("$CARGO_MANIFEST_DIR/../data/maps")
+        .entries()
+        .iter()
+        .filter_map(|e| {
+            e.as_file()
+                .and_then(File::contents_utf8)
+                .and_then(|content| Map::from_json(content).ok())
+        })
+        .collect()
+}
+
+/// Store the built-in maps and the maps in the directories as the maps that
+/// come with the server. A map in the directories replaces a built-in map of
+/// the same name.
+pub async fn import(map_dirs: &[PathBuf], pool: &DatabasePool) -> anyhow::Result<()> {
+    let mut maps = builtin();
+    for dir in map_dirs {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let map = match Map::from_json(&std::fs::read_to_string(&path)?) {
+                Ok(map) => map,
+                Err(e) => {
+                    tracing::warn!("Skipping map {}: {e}", path.display());
+                    continue;
+                }
+            };
+            tracing::info!("Loaded map {} from {}", map.name, path.display());
+            maps.retain(|m| m.name != map.name);
+            maps.push(map);
+        }
+    }
+
+    let now = timers::now();
+    for map in maps {
+        if let Err(e) = map.validate() {
+            tracing::warn!("Skipping map {}: {e}", map.name);
+            continue;
+        }
+        save_server_map(&map, now, pool).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use crate::Sender;
+use crate::model::{DatabasePool, count_games_by_state};
+use axum::{
+    Router, extract::State, http::StatusCode, http::header, response::IntoResponse, routing::get,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use wars::game::{Action, ActionError};
+
+/// Upper bounds of the query duration histogram buckets in seconds
+const QUERY_DURATION_BUCKETS: [f64; 8] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations in each bucket and its smaller buckets
+    buckets: [u64; QUERY_DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(QUERY_DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Game actions performed for clients by `Action` variant
+static ACTIONS: LazyLock<std::sync::Mutex<BTreeMap<String, u64>>> =
+    LazyLock::new(|| std::sync::Mutex::new(BTreeMap::new()));
+/// Game actions that failed by `ActionError` variant
+static ACTION_ERRORS: LazyLock<std::sync::Mutex<BTreeMap<String, u64>>> =
+    LazyLock::new(|| std::sync::Mutex::new(BTreeMap::new()));
+/// Durations of the timed database queries by query name
+static QUERY_DURATIONS: LazyLock<std::sync::Mutex<BTreeMap<&'static str, Histogram>>> =
+    LazyLock::new(|| std::sync::Mutex::new(BTreeMap::new()));
+
+/// Name of the enum variant from its debug formatting
+fn variant_name(value: &impl std::fmt::Debug) -> String {
+    let debug = format!("{value:?}");
+    match debug.find(['(', ' ', '{']) {
+        Some(end) => debug[..end].to_owned(),
+        None => debug,
+    }
+}
+
+pub fn record_action(action: &Action) {
+    *ACTIONS
+        .lock()
+        .unwrap()
+        .entry(variant_name(action))
+        .or_default() += 1;
+}
+
+pub fn record_action_error(error: &ActionError) {
+    *ACTION_ERRORS
+        .lock()
+        .unwrap()
+        .entry(variant_name(error))
+        .or_default() += 1;
+}
+
+/// Run the query and record how long it took
+pub async fn time_query<T>(query: &'static str, future: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = future.await;
+    observe_query(query, started.elapsed());
+    result
+}
+
+fn observe_query(query: &'static str, duration: Duration) {
+    QUERY_DURATIONS
+        .lock()
+        .unwrap()
+        .entry(query)
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+/// Prometheus metrics at `/metrics` and a health check of the database at
+/// `/healthz`
+pub fn router<S>(pool: DatabasePool, sender: Arc<Mutex<Sender>>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(health))
+        .with_state((pool, sender))
+}
+
+async fn health(State((pool, _)): State<(DatabasePool, Arc<Mutex<Sender>>)>) -> impl IntoResponse {
+    match sqlx::query("select 1").execute(&pool).await {
+        Ok(_) => (StatusCode::OK, "ok".to_owned()),
+        Err(e) => {
+            tracing::error!("Health check failed: {e}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Database unavailable: {e}"),
+            )
+        }
+    }
+}
+
+async fn metrics(
+    State((pool, sender)): State<(DatabasePool, Arc<Mutex<Sender>>)>,
+) -> impl IntoResponse {
+    let mut games = BTreeMap::new();
+    match count_games_by_state(&pool).await {
+        Ok(counts) => {
+            for (state, count) in counts {
+                *games.entry(variant_name(&state)).or_insert(0) += count;
+            }
+        }
+        Err(e) => tracing::error!("Error counting games for metrics: {e}"),
+    }
+    let (connections, subscribers) = {
+        let sender = sender.lock().await;
+        let subscribers: BTreeMap<_, _> = sender
+            .subscriptions
+            .iter()
+            .map(|(subscription_id, sender_ids)| (*subscription_id, sender_ids.len()))
+            .collect();
+        (sender.senders.len(), subscribers)
+    };
+
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "wars_connections",
+        "gauge",
+        "Open websocket connections",
+        [(String::new(), connections)],
+    );
+    write_metric(
+        &mut out,
+        "wars_game_subscribers",
+        "gauge",
+        "Connections subscribed to each game",
+        subscribers
+            .into_iter()
+            .map(|(game_id, count)| (format!("game_id=\"{game_id}\""), count)),
+    );
+    write_metric(
+        &mut out,
+        "wars_games",
+        "gauge",
+        "Stored games by state",
+        games
+            .into_iter()
+            .map(|(state, count)| (format!("state=\"{state}\""), count)),
+    );
+    write_metric(
+        &mut out,
+        "wars_actions_total",
+        "counter",
+        "Game actions performed for clients",
+        ACTIONS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(action, count)| (format!("action=\"{action}\""), *count)),
+    );
+    write_metric(
+        &mut out,
+        "wars_action_errors_total",
+        "counter",
+        "Game actions that failed",
+        ACTION_ERRORS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(error, count)| (format!("error=\"{error}\""), *count)),
+    );
+
+    let name = "wars_query_duration_seconds";
+    writeln!(out, "# HELP {name} Duration of database queries").unwrap();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+    for (query, histogram) in QUERY_DURATIONS.lock().unwrap().iter() {
+        for (count, bound) in histogram.buckets.iter().zip(QUERY_DURATION_BUCKETS) {
+            writeln!(
+                out,
+                "{name}_bucket{{query=\"{query}\",le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        let count = histogram.count;
+        writeln!(
+            out,
+            "{name}_bucket{{query=\"{query}\",le=\"+Inf\"}} {count}"
+        )
+        .unwrap();
+        writeln!(out, "{name}_sum{{query=\"{query}\"}} {}", histogram.sum).unwrap();
+        writeln!(out, "{name}_count{{query=\"{query}\"}} {count}").unwrap();
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+/// Write the samples of a metric in the Prometheus text format. Each sample
+/// is its labels and value.
+fn write_metric<T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, T)>,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{name} {value}").unwrap();
+        } else {
+            writeln!(out, "{name}{{{labels}}} {value}").unwrap();
+        }
+    }
+}
@@ -1,8 +1,16 @@
+use crate::cache;
+use crate::metrics;
+use crate::secrets;
+use crate::timers::TurnClock;
+use crate::webhooks;
 use sqlx::prelude::*;
 
 use wars::{
-    game::PlayerNumber,
-    protocol::{EventIndex, GameId, PlayerSlotType},
+    auth::UserId,
+    game::{GameState, Map, PlayerNumber},
+    protocol::{
+        ChatMessage, EventIndex, GameId, GameInfo, GameSettings, MapId, MapInfo, PlayerSlotType,
+    },
 };
 
 pub type DatabasePool = sqlx::Pool<sqlx::Sqlite>;
@@ -13,23 +21,58 @@ pub type DatabaseResult<T> = Result<T, DatabaseError>;
 pub struct Game {
     pub id: GameId,
     pub last_event_index: EventIndex,
+    /// Snapshot of the game as of `snapshot_event_index`
     pub data: String,
+    pub snapshot_event_index: EventIndex,
+}
+
+#[derive(FromRow)]
+pub struct MapRow {
+    pub id: MapId,
+    pub name: String,
+    pub author: Option<UserId>,
+    pub created_at: i64,
+    pub player_count: i64,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl From<MapRow> for MapInfo {
+    fn from(row: MapRow) -> Self {
+        MapInfo {
+            map_id: row.id,
+            name: row.name,
+            author: row.author,
+            created_at: row.created_at as u64,
+            player_count: row.player_count as usize,
+            width: row.width,
+            height: row.height,
+        }
+    }
 }
 
 #[derive(FromRow)]
 pub struct GameEvent {
-    pub game_id: GameId,
+    #[sqlx(rename = "idx")]
     pub index: EventIndex,
     pub data: String,
 }
 
-pub async fn new_database_pool(connection_string: &str) -> anyhow::Result<DatabasePool> {
+/// Open the database and bring its schema up to date with the migrations
+/// built into the server
+pub async fn new_database_pool(path: &std::path::Path) -> anyhow::Result<DatabasePool> {
     let sqlite_opts = sqlx::sqlite::SqliteConnectOptions::new()
-        .filename(connection_string)
+        .filename(path)
         .create_if_missing(true);
-    Ok(sqlx::sqlite::SqlitePool::connect_with(sqlite_opts).await?)
+    let pool = sqlx::sqlite::SqlitePool::connect_with(sqlite_opts).await?;
+    sqlx::migrate!().run(&pool).await?;
+    Ok(pool)
 }
 
+/// Snapshots of games in play are written after this many events
+const SNAPSHOT_INTERVAL: EventIndex = 50;
+
+/// The game as of its last event, from memory if it is in play
 pub async fn load_game(
     game_id: GameId,
     pool: &DatabasePool,
@@ -38,82 +81,277 @@ pub async fn load_game(
     Vec<(PlayerNumber, PlayerSlotType)>,
     EventIndex,
 )> {
-    let (game, last_event_index) = sqlx::query_as("select * from games where id = (?1)")
+    metrics::time_query("load_game", async {
+        let (game, last_event_index) = match cache::get(game_id) {
+            Some(cached) => cached,
+            None => rebuild_game(game_id, pool).await?,
+        };
+        let players = load_game_players(game_id, pool).await?;
+        Ok((game, players, last_event_index))
+    })
+    .await
+}
+/// Replay the events after the snapshot of the game and keep the result in
+/// memory
+async fn rebuild_game(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<(wars::game::Game, EventIndex)> {
+    let row: Game = sqlx::query_as("select * from games where id = ?1")
         .bind(game_id)
         .fetch_one(pool)
-        .await
-        .map(|game: Game| (ron::from_str(&game.data).unwrap(), game.last_event_index))?;
-
-    let data = ron::to_string(&game).unwrap();
-    // tracing::info!("Loaded {data}");
-    let players = sqlx::query_as("select player_number, data from game_players where game_id = ?1")
-        .bind(game_id)
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|(pn, data): (PlayerNumber, String)| (pn, ron::from_str(&data).unwrap()))
-        .collect();
-    Ok((game, players, last_event_index))
+        .await?;
+    let mut game: wars::game::Game = ron::from_str(&row.data).unwrap();
+    // Events saved after the row was read are left for the next save to find
+    let events = load_game_events(game_id, row.snapshot_event_index, pool).await?;
+    for (_, event) in events
+        .iter()
+        .take_while(|(index, _)| *index <= row.last_event_index)
+    {
+        wars::game::action::process(&mut game, event)
+            .map_err(|e| DatabaseError::Decode(Box::new(e)))?;
+    }
+    cache::insert(
+        game_id,
+        game.clone(),
+        row.last_event_index,
+        row.snapshot_event_index,
+    );
+    Ok((game, row.last_event_index))
 }
-pub async fn save_game(
+pub async fn load_game_players(
     game_id: GameId,
-    game: wars::game::Game,
-    new_events: impl IntoIterator<Item = wars::game::Event>,
     pool: &DatabasePool,
-) -> DatabaseResult<EventIndex> {
-    let _transaction = pool.begin().await?;
-
-    let mut last_event_index: EventIndex =
-        sqlx::query_scalar("select last_event_index from games where id = ?1")
+) -> DatabaseResult<Vec<(PlayerNumber, PlayerSlotType)>> {
+    Ok(
+        sqlx::query_as("select player_number, data from game_players where game_id = ?1")
             .bind(game_id)
-            .fetch_one(pool)
-            .await?;
-    for event in new_events.into_iter() {
-        let data = ron::to_string(&event).unwrap();
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|(pn, data): (PlayerNumber, String)| (pn, ron::from_str(&data).unwrap()))
+            .collect(),
+    )
+}
+/// Why a game could not be saved
+#[derive(Debug)]
+pub enum SaveError {
+    /// The game was saved by someone else after it was loaded
+    Conflict,
+    Database(DatabaseError),
+}
 
-        last_event_index += 1;
-        sqlx::query("insert into game_events(game_id, idx, data) values (?1, ?2, ?3)")
-            .bind(game_id)
-            .bind(last_event_index)
-            .bind(data)
-            .execute(pool)
-            .await?;
+impl From<DatabaseError> for SaveError {
+    fn from(error: DatabaseError) -> Self {
+        SaveError::Database(error)
     }
+}
 
-    let data = ron::to_string(&game).unwrap();
-    // tracing::info!("Saving {data}");
-    sqlx::query("update games set data = ?1, last_event_index = ?2 where id = ?3")
-        .bind(data)
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Conflict => write!(f, "Game was changed while saving"),
+            SaveError::Database(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+/// How many times a change to a game is tried when other writers keep saving
+/// the game first
+const SAVE_ATTEMPTS: usize = 5;
+
+/// Load the game, change it and save the events emitted. When someone else
+/// saved the game in between, the change is made again to their version.
+/// Returns the game as it was loaded and as it was saved.
+pub async fn update_game<T>(
+    game_id: GameId,
+    pool: &DatabasePool,
+    mut change: impl FnMut(&mut wars::game::Game, &mut dyn FnMut(wars::game::Event)) -> T,
+) -> Result<
+    (
+        wars::game::Game,
+        wars::game::Game,
+        Vec<(EventIndex, wars::game::Event)>,
+        T,
+    ),
+    SaveError,
+> {
+    for _ in 0..SAVE_ATTEMPTS {
+        let (previous, _players, last_event_index) = load_game(game_id, pool).await?;
+        let mut game = previous.clone();
+        let mut new_events = Vec::new();
+        let result = change(&mut game, &mut |event| new_events.push(event));
+        match save_game(game_id, last_event_index, &game, new_events, pool).await {
+            Ok(saved_events) => return Ok((previous, game, saved_events, result)),
+            Err(SaveError::Conflict) => {
+                tracing::info!("Game {game_id} changed while saving, trying again");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(SaveError::Conflict)
+}
+/// Save the new events of the game, unless someone else has saved the game
+/// since it was loaded at `loaded_event_index`. Returns the events with the
+/// indices they were saved at.
+pub async fn save_game(
+    game_id: GameId,
+    loaded_event_index: EventIndex,
+    game: &wars::game::Game,
+    new_events: impl IntoIterator<Item = wars::game::Event>,
+    pool: &DatabasePool,
+) -> Result<Vec<(EventIndex, wars::game::Event)>, SaveError> {
+    metrics::time_query("save_game", async move {
+        let saved_events: Vec<_> = (loaded_event_index + 1..).zip(new_events).collect();
+        let last_event_index = loaded_event_index + saved_events.len() as EventIndex;
+
+        let mut transaction = pool.begin().await?;
+        // Updating the game first makes concurrent writers wait for each other
+        let snapshot_event_index: Option<EventIndex> = sqlx::query_scalar(
+            "update games set last_event_index = ?1 where id = ?2 and last_event_index = ?3 \
+             returning snapshot_event_index",
+        )
         .bind(last_event_index)
         .bind(game_id)
-        .execute(pool)
+        .bind(loaded_event_index)
+        .fetch_optional(&mut *transaction)
         .await?;
-    Ok(last_event_index)
+        let Some(mut snapshot_event_index) = snapshot_event_index else {
+            cache::remove(game_id);
+            return Err(SaveError::Conflict);
+        };
+
+        for (index, event) in saved_events.iter() {
+            let data = ron::to_string(event).unwrap();
+            sqlx::query("insert into game_events(game_id, idx, data) values (?1, ?2, ?3)")
+                .bind(game_id)
+                .bind(index)
+                .bind(data)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        // The lobby reads whether games have started or ended from the state
+        // saved with the snapshot
+        if last_event_index - snapshot_event_index >= SNAPSHOT_INTERVAL
+            || cache::state(game_id).is_none_or(|state| state != game.state)
+        {
+            save_snapshot(game_id, game, last_event_index, &mut *transaction).await?;
+            snapshot_event_index = last_event_index;
+        }
+        transaction.commit().await?;
+
+        cache::insert(
+            game_id,
+            game.clone(),
+            last_event_index,
+            snapshot_event_index,
+        );
+        webhooks::notify_turn_start(game_id, game, &saved_events, pool);
+        Ok(saved_events)
+    })
+    .await
+}
+/// Store the game as of the event so that loading it doesn't need to replay
+/// the events before
+pub async fn save_snapshot<'e>(
+    game_id: GameId,
+    game: &wars::game::Game,
+    event_index: EventIndex,
+    executor: impl sqlx::SqliteExecutor<'e>,
+) -> DatabaseResult<()> {
+    sqlx::query(
+        "update games set data = ?1, snapshot_event_index = ?2, state = ?4 \
+         where id = ?3 and snapshot_event_index < ?2",
+    )
+    .bind(ron::to_string(game).unwrap())
+    .bind(event_index)
+    .bind(game_id)
+    .bind(ron::to_string(&game.state).unwrap())
+    .execute(executor)
+    .await
+    .map(|_| ())
 }
+/// Put the slot in place of the player, played by the user if a user joined
+/// as them. Turn starts of the player are posted to that user's webhook.
 pub async fn set_game_player(
     game_id: GameId,
     player_number: PlayerNumber,
     slot: &PlayerSlotType,
+    user_id: Option<UserId>,
     pool: &DatabasePool,
 ) -> DatabaseResult<()> {
     let data = ron::to_string(&slot).unwrap();
-    sqlx::query("update game_players set data = ?1 where game_id = ?2 and player_number = ?3")
-        .bind(data)
-        .bind(game_id)
-        .bind(player_number)
-        .execute(pool)
-        .await
-        .map(|_| ())
+    sqlx::query(
+        "update game_players set data = ?1, user_id = ?2 \
+         where game_id = ?3 and player_number = ?4",
+    )
+    .bind(data)
+    .bind(user_id)
+    .bind(game_id)
+    .bind(player_number)
+    .execute(pool)
+    .await
+    .map(|_| ())
 }
-pub async fn create_game(game: wars::game::Game, pool: &DatabasePool) -> DatabaseResult<GameId> {
-    let _transaction = pool.begin().await?;
+/// Put the slot in place of the player for the user, unless someone else
+/// holds the player or it is already taken. Returns whether the user got it.
+pub async fn claim_game_player(
+    game_id: GameId,
+    player_number: PlayerNumber,
+    slot: &PlayerSlotType,
+    user_id: UserId,
+    pool: &DatabasePool,
+) -> DatabaseResult<bool> {
+    let data = ron::to_string(&slot).unwrap();
+    let empty = ron::to_string(&PlayerSlotType::Empty).unwrap();
+    let open = ron::to_string(&PlayerSlotType::Human(None)).unwrap();
+    sqlx::query(
+        "update game_players set data = ?1, user_id = ?2 \
+         where game_id = ?3 and player_number = ?4 \
+         and (user_id = ?2 or (user_id is null and data in (?5, ?6)))",
+    )
+    .bind(data)
+    .bind(user_id)
+    .bind(game_id)
+    .bind(player_number)
+    .bind(empty)
+    .bind(open)
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+pub async fn create_game(
+    map_id: MapId,
+    game: wars::game::Game,
+    settings: &GameSettings,
+    turn_clock: Option<TurnClock>,
+    pool: &DatabasePool,
+) -> DatabaseResult<GameId> {
+    let mut transaction = pool.begin().await?;
     let data = ron::to_string(&game).unwrap();
+    let state = ron::to_string(&game.state).unwrap();
+    let public = settings.public;
+    let settings = GameSettings {
+        password: settings.password.as_deref().map(secrets::hash_password),
+        ..settings.clone()
+    };
+    let settings = ron::to_string(&settings).unwrap();
+    let turn_clock = turn_clock.map(|clock| ron::to_string(&clock).unwrap());
 
-    let game_id =
-        sqlx::query_scalar("insert into games(data, last_event_index) values (?1, 0) returning id")
-            .bind(data)
-            .fetch_one(pool)
-            .await?;
+    let game_id = sqlx::query_scalar(
+        "insert into games(data, initial_data, last_event_index, settings, turn_clock, map_id, \
+         state, public) values (?1, ?1, 0, ?2, ?3, ?4, ?5, ?6) returning id",
+    )
+    .bind(data)
+    .bind(settings)
+    .bind(turn_clock)
+    .bind(map_id)
+    .bind(state)
+    .bind(public)
+    .fetch_one(&mut *transaction)
+    .await?;
 
     for player in game.players.0 {
         let slot = wars::protocol::PlayerSlotType::Human(None);
@@ -122,29 +360,631 @@ pub async fn create_game(game: wars::game::Game, pool: &DatabasePool) -> Databas
             .bind(game_id)
             .bind(player.number)
             .bind(data)
-            .execute(pool)
+            .execute(&mut *transaction)
             .await?;
     }
 
+    transaction.commit().await?;
     Ok(game_id)
 }
+/// The game as it was created, for replaying its events. Missing for games
+/// created before it was stored.
+pub async fn load_initial_game(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<wars::game::Game>> {
+    let data: Option<String> = sqlx::query_scalar("select initial_data from games where id = ?1")
+        .bind(game_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(data.map(|data| ron::from_str(&data).unwrap()))
+}
+/// Settings of the game. Games created before settings were stored get the
+/// defaults.
+pub async fn load_game_settings(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<GameSettings> {
+    let data: Option<String> = sqlx::query_scalar("select settings from games where id = ?1")
+        .bind(game_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(data
+        .map(|data| ron::from_str(&data).unwrap())
+        .unwrap_or_default())
+}
+pub async fn count_unfinished_games(pool: &DatabasePool) -> DatabaseResult<usize> {
+    let count: i64 = sqlx::query_scalar("select count(*) from games where state != ?1")
+        .bind(ron::to_string(&GameState::Finished).unwrap())
+        .fetch_one(pool)
+        .await?;
+    Ok(count as usize)
+}
+/// How many stored games there are in each state
+pub async fn count_games_by_state(pool: &DatabasePool) -> DatabaseResult<Vec<(GameState, usize)>> {
+    let counts: Vec<(String, i64)> =
+        sqlx::query_as("select state, count(*) from games group by state")
+            .fetch_all(pool)
+            .await?;
+    counts
+        .into_iter()
+        .map(|(state, count)| Ok((decode(&state)?, count as usize)))
+        .collect()
+}
+/// Listing of the game as shown in the lobby
+pub async fn load_game_info(game_id: GameId, pool: &DatabasePool) -> DatabaseResult<GameInfo> {
+    let (settings, state): (Option<String>, String) =
+        sqlx::query_as("select settings, state from games where id = ?1")
+            .bind(game_id)
+            .fetch_one(pool)
+            .await?;
+    game_info(game_id, settings, state, pool).await
+}
+async fn game_info(
+    game_id: GameId,
+    settings: Option<String>,
+    state: String,
+    pool: &DatabasePool,
+) -> DatabaseResult<GameInfo> {
+    let settings: GameSettings = settings
+        .as_deref()
+        .map(decode)
+        .transpose()?
+        .unwrap_or_default();
+    let players = load_game_players(game_id, pool).await?;
+    Ok(GameInfo {
+        game_id,
+        state: decode(&state)?,
+        password_required: settings.password.is_some(),
+        settings: settings.without_password(),
+        players,
+    })
+}
+/// Lobby listing of the public games
+pub async fn load_public_games(pool: &DatabasePool) -> DatabaseResult<Vec<GameInfo>> {
+    let rows: Vec<(GameId, Option<String>, String)> =
+        sqlx::query_as("select id, settings, state from games where public order by id")
+            .fetch_all(pool)
+            .await?;
+    let mut games = Vec::new();
+    for (game_id, settings, state) in rows {
+        games.push(game_info(game_id, settings, state, pool).await?);
+    }
+    Ok(games)
+}
+/// Remove the game with its players, events and chat
+pub async fn delete_game(game_id: GameId, pool: &DatabasePool) -> DatabaseResult<()> {
+    let mut transaction = pool.begin().await?;
+    for query in [
+        "delete from game_events where game_id = ?1",
+        "delete from game_players where game_id = ?1",
+        "delete from chat_messages where game_id = ?1",
+    ] {
+        sqlx::query(query)
+            .bind(game_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    let deleted = sqlx::query("delete from games where id = ?1")
+        .bind(game_id)
+        .execute(&mut *transaction)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(DatabaseError::RowNotFound);
+    }
+    transaction.commit().await?;
+    cache::remove(game_id);
+    Ok(())
+}
+pub async fn load_turn_clock(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<TurnClock>> {
+    let data: Option<String> = sqlx::query_scalar("select turn_clock from games where id = ?1")
+        .bind(game_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(data.map(|data| ron::from_str(&data).unwrap()))
+}
+pub async fn save_turn_clock(
+    game_id: GameId,
+    turn_clock: &TurnClock,
+    pool: &DatabasePool,
+) -> DatabaseResult<()> {
+    sqlx::query("update games set turn_clock = ?1 where id = ?2")
+        .bind(ron::to_string(turn_clock).unwrap())
+        .bind(game_id)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+pub async fn load_game_ids(pool: &DatabasePool) -> DatabaseResult<Vec<GameId>> {
+    sqlx::query_scalar("select id from games")
+        .fetch_all(pool)
+        .await
+}
 pub async fn load_game_events(
     game_id: GameId,
     since: EventIndex,
     pool: &DatabasePool,
 ) -> DatabaseResult<Vec<(EventIndex, wars::game::Event)>> {
-    let result = sqlx::query_as("select * from game_events where game_id = ?1 and idx > ?2")
+    let result =
+        sqlx::query_as("select * from game_events where game_id = ?1 and idx > ?2 order by idx")
+            .bind(game_id)
+            .bind(since)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|e: GameEvent| {
+                (
+                    e.index,
+                    ron::from_str::<wars::game::Event>(&e.data).unwrap(),
+                )
+            })
+            .collect::<Vec<(EventIndex, wars::game::Event)>>();
+    Ok(result)
+}
+pub async fn save_chat_message(
+    game_id: GameId,
+    message: &ChatMessage,
+    pool: &DatabasePool,
+) -> DatabaseResult<()> {
+    sqlx::query("insert into chat_messages(game_id, data) values (?1, ?2)")
         .bind(game_id)
-        .bind(since)
-        .fetch_all(pool)
-        .await?
+        .bind(ron::to_string(message).unwrap())
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+/// Chat history of the game, oldest first
+pub async fn load_chat_messages(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<Vec<ChatMessage>> {
+    let data: Vec<String> =
+        sqlx::query_scalar("select data from chat_messages where game_id = ?1 order by id")
+            .bind(game_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(data
         .into_iter()
-        .map(|e: GameEvent| {
-            (
-                e.index,
-                ron::from_str::<wars::game::Event>(&e.data).unwrap(),
-            )
-        })
-        .collect::<Vec<(EventIndex, wars::game::Event)>>();
-    Ok(result)
+        .map(|data| ron::from_str(&data).unwrap())
+        .collect())
+}
+/// Set or remove the URL that turn starts of the players the user joined
+/// games as are posted to
+pub async fn save_webhook(
+    user_id: UserId,
+    url: Option<&str>,
+    pool: &DatabasePool,
+) -> DatabaseResult<()> {
+    let query = match url {
+        Some(url) => sqlx::query(
+            "insert into webhooks(user_id, url) values (?1, ?2) \
+             on conflict(user_id) do update set url = excluded.url",
+        )
+        .bind(user_id)
+        .bind(url),
+        None => sqlx::query("delete from webhooks where user_id = ?1").bind(user_id),
+    };
+    query.execute(pool).await.map(|_| ())
+}
+/// Webhook of the user playing the player, if any
+pub async fn load_webhook(
+    game_id: GameId,
+    player_number: PlayerNumber,
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<String>> {
+    sqlx::query_scalar(
+        "select webhooks.url from game_players \
+         join webhooks on webhooks.user_id = game_players.user_id \
+         where game_players.game_id = ?1 and game_players.player_number = ?2",
+    )
+    .bind(game_id)
+    .bind(player_number)
+    .fetch_optional(pool)
+    .await
+}
+/// Name of the map the game was created from. Missing for games created
+/// before maps were stored.
+pub async fn load_game_map_name(
+    game_id: GameId,
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<String>> {
+    sqlx::query_scalar(
+        "select maps.name from games join maps on maps.id = games.map_id where games.id = ?1",
+    )
+    .bind(game_id)
+    .fetch_optional(pool)
+    .await
+}
+/// Games saved before unit ids were allocated from `Game::next_unit_id` can
+/// have a stale counter. Bump it past every unit id found in the snapshot or
+/// in the `Build` events it includes so that destroyed units' ids are not
+/// reused. Builds after the snapshot are replayed on it when the game is
+/// loaded, which moves the counter past them.
+/// Games created since then allocate ids correctly, so this only runs once
+/// for each database.
+pub async fn migrate_next_unit_ids(pool: &DatabasePool) -> DatabaseResult<()> {
+    const MIGRATION: &str = "next_unit_ids";
+    let mut transaction = pool.begin().await?;
+    let migrated: Option<String> =
+        sqlx::query_scalar("select name from data_migrations where name = ?1")
+            .bind(MIGRATION)
+            .fetch_optional(&mut *transaction)
+            .await?;
+    if migrated.is_some() {
+        return Ok(());
+    }
+
+    let games: Vec<Game> = sqlx::query_as("select * from games")
+        .fetch_all(&mut *transaction)
+        .await?;
+    for game in games {
+        let mut data: wars::game::Game = decode(&game.data)?;
+        let events: Vec<String> =
+            sqlx::query_scalar("select data from game_events where game_id = ?1 and idx <= ?2")
+                .bind(game.id)
+                .bind(game.snapshot_event_index)
+                .fetch_all(&mut *transaction)
+                .await?;
+        let mut built_unit_ids = Vec::new();
+        for event in events {
+            if let wars::game::Event::Build(_, unit_id, _, _) = decode(&event)? {
+                built_unit_ids.push(unit_id);
+            }
+        }
+        let previous_next_unit_id = data.next_unit_id;
+        data.reserve_unit_ids(built_unit_ids);
+        if data.next_unit_id == previous_next_unit_id {
+            continue;
+        }
+
+        tracing::info!(
+            "Game {}: next unit id {} -> {}",
+            game.id,
+            previous_next_unit_id,
+            data.next_unit_id
+        );
+        sqlx::query("update games set data = ?1 where id = ?2")
+            .bind(ron::to_string(&data).unwrap())
+            .bind(game.id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    sqlx::query("insert into data_migrations(name) values (?1)")
+        .bind(MIGRATION)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await
+}
+/// Games saved before their state and publicity had columns of their own
+/// have the column defaults. Fill them in from the snapshot, which is saved
+/// whenever the state changes, and from the settings.
+pub async fn migrate_game_columns(pool: &DatabasePool) -> DatabaseResult<()> {
+    const MIGRATION: &str = "game_columns";
+    let mut transaction = pool.begin().await?;
+    let migrated: Option<String> =
+        sqlx::query_scalar("select name from data_migrations where name = ?1")
+            .bind(MIGRATION)
+            .fetch_optional(&mut *transaction)
+            .await?;
+    if migrated.is_some() {
+        return Ok(());
+    }
+
+    let games: Vec<(GameId, String, Option<String>)> =
+        sqlx::query_as("select id, data, settings from games")
+            .fetch_all(&mut *transaction)
+            .await?;
+    for (game_id, data, settings) in games {
+        let game: wars::game::Game = decode(&data)?;
+        let settings: GameSettings = settings
+            .as_deref()
+            .map(decode)
+            .transpose()?
+            .unwrap_or_default();
+        sqlx::query("update games set state = ?1, public = ?2 where id = ?3")
+            .bind(ron::to_string(&game.state).unwrap())
+            .bind(settings.public)
+            .bind(game_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    sqlx::query("insert into data_migrations(name) values (?1)")
+        .bind(MIGRATION)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await
+}
+/// Parse data stored in the database, failing rather than panicking on data
+/// that doesn't parse
+fn decode<T: serde::de::DeserializeOwned>(data: &str) -> DatabaseResult<T> {
+    ron::from_str(data).map_err(|e| DatabaseError::Decode(Box::new(e)))
+}
+/// Maps to choose from for new games, in the order they were added
+pub async fn load_map_infos(pool: &DatabasePool) -> DatabaseResult<Vec<MapInfo>> {
+    let rows: Vec<MapRow> = sqlx::query_as(
+        "select id, name, author, created_at, player_count, width, height from maps order by id",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(MapInfo::from).collect())
+}
+pub async fn load_map(map_id: MapId, pool: &DatabasePool) -> DatabaseResult<Map> {
+    let data: String = sqlx::query_scalar("select data from maps where id = ?1")
+        .bind(map_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(ron::from_str(&data).unwrap())
+}
+/// Add the map to the maps games can be created on
+pub async fn save_map(
+    map: &Map,
+    author: Option<UserId>,
+    created_at: u64,
+    pool: &DatabasePool,
+) -> DatabaseResult<MapInfo> {
+    let info = MapInfo::new(0, map, author, created_at);
+    let map_id = sqlx::query_scalar(
+        "insert into maps(name, author, created_at, player_count, width, height, data) \
+         values (?1, ?2, ?3, ?4, ?5, ?6, ?7) returning id",
+    )
+    .bind(&info.name)
+    .bind(author)
+    .bind(created_at as i64)
+    .bind(info.player_count as i64)
+    .bind(info.width)
+    .bind(info.height)
+    .bind(ron::to_string(map).unwrap())
+    .fetch_one(pool)
+    .await?;
+    Ok(MapInfo { map_id, ..info })
+}
+/// Add or update a map that comes with the server. Games keep their own copy
+/// of the map, so changing it doesn't affect games already created on it.
+pub async fn save_server_map(
+    map: &Map,
+    created_at: u64,
+    pool: &DatabasePool,
+) -> DatabaseResult<()> {
+    let map_id: Option<MapId> =
+        sqlx::query_scalar("select id from maps where author is null and name = ?1")
+            .bind(&map.name)
+            .fetch_optional(pool)
+            .await?;
+    let Some(map_id) = map_id else {
+        return save_map(map, None, created_at, pool).await.map(|_| ());
+    };
+    let info = MapInfo::new(map_id, map, None, created_at);
+    sqlx::query(
+        "update maps set player_count = ?1, width = ?2, height = ?3, data = ?4 where id = ?5",
+    )
+    .bind(info.player_count as i64)
+    .bind(info.width)
+    .bind(info.height)
+    .bind(ron::to_string(map).unwrap())
+    .bind(map_id)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::model::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    pub const THIRD_PARTY_MAP: &str = include_str!("../../data/maps/third_party.json");
+
+    /// Ids the games of each test database start from
+    static NEXT_GAME_IDS: AtomicI64 = AtomicI64::new(0);
+
+    /// Some state is kept in memory by game id, so each database numbers its
+    /// games apart from the other tests'
+    async fn number_games_apart(pool: &DatabasePool) {
+        sqlx::query("insert into sqlite_sequence(name, seq) values ('games', ?1)")
+            .bind(NEXT_GAME_IDS.fetch_add(1000, Ordering::Relaxed))
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    /// An empty database in memory
+    pub async fn database_pool() -> DatabasePool {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        number_games_apart(&pool).await;
+        pool
+    }
+
+    /// Create and start a game on the third party map
+    pub async fn start_game(settings: &GameSettings, pool: &DatabasePool) -> GameId {
+        let map = Map::from_json(THIRD_PARTY_MAP).unwrap();
+        let map_info = save_map(&map, None, 0, pool).await.unwrap();
+        let game = wars::game::Game::with_rules(map, &[(1, 0), (2, 0)], settings.rules.clone());
+        let turn_clock = settings.turn_timer.clone().map(TurnClock::new);
+        let game_id = create_game(map_info.map_id, game, settings, turn_clock, pool)
+            .await
+            .unwrap();
+        update_game(game_id, pool, wars::game::action::start)
+            .await
+            .unwrap()
+            .3
+            .unwrap();
+        game_id
+    }
+
+    #[tokio::test]
+    async fn builds_after_the_snapshot_survive_a_restart() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let (game, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+        save_snapshot(game_id, &game, last_event_index, &pool)
+            .await
+            .unwrap();
+
+        let mut bot = wars::bot::RandomBot::with_seed(1);
+        let mut built = false;
+        while !built {
+            let (_, _, events, result) = update_game(game_id, &pool, |game, emit| {
+                wars::bot::play_turn(&mut bot, game, emit)
+            })
+            .await
+            .unwrap();
+            result.unwrap();
+            built = events
+                .iter()
+                .any(|(_, event)| matches!(event, wars::game::Event::Build(..)));
+        }
+        let (game, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+        let snapshot_event_index: EventIndex =
+            sqlx::query_scalar("select snapshot_event_index from games where id = ?1")
+                .bind(game_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(snapshot_event_index < last_event_index);
+
+        // Start over as if the server was restarted before the migration ran
+        cache::remove(game_id);
+        sqlx::query("delete from data_migrations")
+            .execute(&pool)
+            .await
+            .unwrap();
+        migrate_next_unit_ids(&pool).await.unwrap();
+        let (rebuilt, _, rebuilt_event_index) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(rebuilt_event_index, last_event_index);
+        assert!(rebuilt == game);
+    }
+
+    #[tokio::test]
+    async fn failed_saves_leave_the_game_as_it_was() {
+        let path = std::env::temp_dir().join(format!("wars-test-{}.db", std::process::id()));
+        let pool = new_database_pool(&path).await.unwrap();
+        number_games_apart(&pool).await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let (game, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+        let event = |idx: EventIndex| {
+            sqlx::query("insert into game_events(game_id, idx, data) values (?1, ?2, '')")
+                .bind(game_id)
+                .bind(idx)
+                .execute(&pool)
+        };
+        assert!(event(last_event_index).await.is_err());
+
+        // The second of the new events can't be saved at the index taken
+        event(last_event_index + 2).await.unwrap();
+        let mut events = Vec::new();
+        let mut game_after = game.clone();
+        wars::game::action::end_turn(&mut game_after, &mut |event| events.push(event)).unwrap();
+        assert!(events.len() >= 2);
+        let result = save_game(game_id, last_event_index, &game_after, events, &pool).await;
+        assert!(matches!(result, Err(SaveError::Database(_))));
+        let (saved_game, _, saved_event_index) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(saved_event_index, last_event_index);
+        assert_eq!(saved_game.in_turn_number(), game.in_turn_number());
+        let events_after: i64 =
+            sqlx::query_scalar("select count(*) from game_events where game_id = ?1 and idx > ?2")
+                .bind(game_id)
+                .bind(last_event_index)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(events_after, 1);
+
+        pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_of_games_changed_since_loading_conflict() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let (loaded, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+        let end_turn = |game: &wars::game::Game| {
+            let mut game = game.clone();
+            let mut events = Vec::new();
+            wars::game::action::end_turn(&mut game, &mut |event| events.push(event)).unwrap();
+            (game, events)
+        };
+
+        let (game, events) = end_turn(&loaded);
+        let saved = save_game(game_id, last_event_index, &game, events, &pool)
+            .await
+            .unwrap();
+        let (saved_event_index, _) = *saved.last().unwrap();
+        assert!(saved_event_index > last_event_index);
+
+        // The same turn ended again by someone who loaded the game before
+        let (game, events) = end_turn(&loaded);
+        let result = save_game(game_id, last_event_index, &game, events, &pool).await;
+        assert!(matches!(result, Err(SaveError::Conflict)));
+        let (game, _, event_index) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(event_index, saved_event_index);
+        assert_eq!(game.in_turn_number(), Some(2));
+        cache::remove(game_id);
+        let (rebuilt, _, event_index) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(event_index, saved_event_index);
+        assert!(rebuilt == game);
+
+        // Updates are made to the game as it is now
+        let (previous, game, _, result) = update_game(game_id, &pool, wars::game::action::end_turn)
+            .await
+            .unwrap();
+        result.unwrap();
+        assert_eq!(previous.in_turn_number(), Some(2));
+        assert_eq!(game.in_turn_number(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn lobby_and_limits_count_games_by_their_columns() {
+        let pool = database_pool().await;
+        let public_id = start_game(&GameSettings::default(), &pool).await;
+        let private = GameSettings {
+            public: false,
+            ..GameSettings::default()
+        };
+        let private_id = start_game(&private, &pool).await;
+        update_game(private_id, &pool, wars::game::action::surrender)
+            .await
+            .unwrap()
+            .3
+            .unwrap();
+
+        let check = async || {
+            let games = load_public_games(&pool).await.unwrap();
+            assert_eq!(games.len(), 1);
+            assert_eq!(games[0].game_id, public_id);
+            assert_eq!(games[0].state, GameState::InProgress);
+            assert_eq!(count_unfinished_games(&pool).await.unwrap(), 1);
+            let mut counts = count_games_by_state(&pool).await.unwrap();
+            counts.sort_by_key(|(state, _)| state.clone() as i32);
+            assert_eq!(
+                counts,
+                [(GameState::InProgress, 1), (GameState::Finished, 1)]
+            );
+        };
+        check().await;
+
+        // Games from before the columns get theirs from the data migration
+        sqlx::query("update games set state = 'Pregame', public = true")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("delete from data_migrations")
+            .execute(&pool)
+            .await
+            .unwrap();
+        migrate_game_columns(&pool).await.unwrap();
+        check().await;
+    }
 }
@@ -0,0 +1,71 @@
+use sha2::{Digest, Sha256};
+use wars::protocol::GameSettings;
+
+/// Hash a game password with a random salt for storing it
+pub fn hash_password(password: &str) -> String {
+    let salt = format!("{:016x}", fastrand::u64(..));
+    let digest = salted_digest(&salt, password);
+    format!("{salt}:{digest}")
+}
+
+/// Whether the password is needed for the game and matches the hash stored
+/// in its settings
+pub fn accepts_password(settings: &GameSettings, password: Option<&str>) -> bool {
+    let Some(hash) = &settings.password else {
+        return true;
+    };
+    let Some((salt, digest)) = hash.split_once(':') else {
+        return false;
+    };
+    password.is_some_and(|password| {
+        same_bytes(salted_digest(salt, password).as_bytes(), digest.as_bytes())
+    })
+}
+
+fn salted_digest(salt: &str, password: &str) -> String {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Whether the token given matches the secret one. Their digests are compared
+/// so that the time taken doesn't tell the secret's length either.
+pub fn same_token(token: &str, secret: &str) -> bool {
+    same_bytes(&Sha256::digest(token), &Sha256::digest(secret))
+}
+
+/// Compare every byte so the time taken doesn't tell how much of a secret
+/// was guessed right
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn passwords_are_stored_hashed() {
+        let settings = GameSettings {
+            password: Some(hash_password("hunter2")),
+            ..GameSettings::default()
+        };
+        assert!(!settings.password.as_ref().unwrap().contains("hunter2"));
+        assert!(accepts_password(&settings, Some("hunter2")));
+        assert!(!accepts_password(&settings, Some("hunter3")));
+        assert!(!accepts_password(&settings, None));
+        assert!(accepts_password(&GameSettings::default(), None));
+    }
+
+    #[test]
+    fn tokens_match_only_themselves() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secreT", "secret"));
+        assert!(!same_token("secret ", "secret"));
+        assert!(!same_token("", "secret"));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::model::{
+    DatabasePool, DatabaseResult, load_game, load_game_events, load_game_settings,
+    load_initial_game,
+};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use wars::game::{Event, Game, GameState, action};
+use wars::protocol::{EventIndex, EventMessage, GameId, GameSettings};
+
+/// Index of the last event spectators may see. With a delay of N turns that
+/// is the last event before the turn N turns back started. Finished games
+/// have nothing left to hide.
+fn visible_until(events: &[(EventIndex, Event)], delay_turns: u32, finished: bool) -> EventIndex {
+    let last_event_index = events.last().map(|(index, _)| *index).unwrap_or(0);
+    if finished {
+        return last_event_index;
+    }
+    let turn_starts: Vec<_> = events
+        .iter()
+        .filter(|(_, event)| matches!(event, Event::StartTurn(_)))
+        .map(|(index, _)| *index)
+        .collect();
+    match turn_starts.len().checked_sub(delay_turns as usize) {
+        Some(turn) => turn_starts
+            .get(turn)
+            .map(|index| index - 1)
+            .unwrap_or(last_event_index),
+        None => 0,
+    }
+}
+
+/// The game as spectators see it and the index of the last event in it
+pub async fn load_game_state(
+    game_id: GameId,
+    settings: &GameSettings,
+    pool: &DatabasePool,
+) -> DatabaseResult<(Game, EventIndex)> {
+    let (game, _players, last_event_index) = load_game(game_id, pool).await?;
+    if settings.spectator_delay_turns == 0 || game.state == GameState::Finished {
+        return Ok((game, last_event_index));
+    }
+
+    let mut game = load_initial_game(game_id, pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    let events = load_game_events(game_id, 0, pool).await?;
+    let until = visible_until(&events, settings.spectator_delay_turns, false);
+    for (_, event) in events.iter().take_while(|(index, _)| *index <= until) {
+        if let Err(e) = action::process(&mut game, event) {
+            tracing::error!("Could not replay {event:?} in game {game_id}: {e}");
+            break;
+        }
+    }
+    Ok((game, until))
+}
+
+/// Index of the last event released to the spectators of each game with a
+/// spectator delay, so that each change only loads the events after it
+static RELEASED: LazyLock<std::sync::Mutex<HashMap<GameId, EventIndex>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Index of the last event released to the spectators of the game. For a game
+/// nothing was released of since the server started, it is worked out from
+/// the whole history of the game.
+async fn last_released(
+    game_id: GameId,
+    delay_turns: u32,
+    pool: &DatabasePool,
+) -> DatabaseResult<EventIndex> {
+    if let Some(index) = RELEASED.lock().unwrap().get(&game_id).copied() {
+        return Ok(index);
+    }
+    let (game, _players, _last_event_index) = load_game(game_id, pool).await?;
+    let events = load_game_events(game_id, 0, pool).await?;
+    Ok(visible_until(
+        &events,
+        delay_turns,
+        game.state == GameState::Finished,
+    ))
+}
+
+/// Events after `since` that spectators may see
+pub async fn load_events(
+    game_id: GameId,
+    settings: &GameSettings,
+    since: EventIndex,
+    pool: &DatabasePool,
+) -> DatabaseResult<Vec<(EventIndex, Event)>> {
+    let mut events = load_game_events(game_id, since, pool).await?;
+    if settings.spectator_delay_turns == 0 {
+        return Ok(events);
+    }
+    let until = last_released(game_id, settings.spectator_delay_turns, pool).await?;
+    events.retain(|(index, _)| *index <= until);
+    Ok(events)
+}
+
+/// Messages for spectators once `new_events` have been saved. Without a delay
+/// those are the new events themselves, otherwise whatever the new events
+/// moved past the delay.
+pub async fn released_events(
+    game_id: GameId,
+    game_state: &GameState,
+    new_events: &[(EventIndex, Event)],
+    pool: &DatabasePool,
+) -> DatabaseResult<Vec<EventMessage>> {
+    let settings = load_game_settings(game_id, pool).await?;
+    if settings.spectator_delay_turns == 0 {
+        return Ok(new_events
+            .iter()
+            .map(|(index, event)| EventMessage::GameEvent(game_id, *index, event.clone()))
+            .collect());
+    }
+    let Some((first_new_index, _)) = new_events.first() else {
+        return Ok(Vec::new());
+    };
+
+    let released_from = RELEASED.lock().unwrap().get(&game_id).copied();
+    let released_from = match released_from {
+        Some(index) => index,
+        None => {
+            let mut previous_events = load_game_events(game_id, 0, pool).await?;
+            previous_events.retain(|(index, _)| index < first_new_index);
+            visible_until(&previous_events, settings.spectator_delay_turns, false)
+        }
+    };
+    // Turns that started before the released events have been released, so
+    // the events after them tell how far the delay has moved
+    let events = load_game_events(game_id, released_from, pool).await?;
+    let released_until = released_from.max(visible_until(
+        &events,
+        settings.spectator_delay_turns,
+        *game_state == GameState::Finished,
+    ));
+    RELEASED.lock().unwrap().insert(game_id, released_until);
+    Ok(events
+        .into_iter()
+        .filter(|(index, _)| *index <= released_until)
+        .map(|(index, event)| EventMessage::GameEvent(game_id, index, event))
+        .collect())
+}
+
+/// Forget what was released of a game that was deleted
+pub fn forget(game_id: GameId) {
+    RELEASED.lock().unwrap().remove(&game_id);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::test::{database_pool, start_game};
+    use crate::model::update_game;
+    use crate::spectators::*;
+
+    #[tokio::test]
+    async fn events_are_released_as_the_delay_passes() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            spectator_delay_turns: 2,
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        let (_, last_released) = load_game_state(game_id, &settings, &pool).await.unwrap();
+        let mut last_released = last_released;
+        let mut bot = wars::bot::RandomBot::with_seed(5);
+
+        for _ in 0..8 {
+            let (_, game, events, result) = update_game(game_id, &pool, |game, emit| {
+                wars::bot::play_turn(&mut bot, game, emit)
+            })
+            .await
+            .unwrap();
+            result.unwrap();
+            for message in released_events(game_id, &game.state, &events, &pool)
+                .await
+                .unwrap()
+            {
+                let EventMessage::GameEvent(_, index, _) = message else {
+                    panic!("Unexpected message for spectators");
+                };
+                assert_eq!(index, last_released + 1);
+                last_released = index;
+            }
+
+            // Released a step at a time as much as worked out from scratch
+            let all_events = load_game_events(game_id, 0, &pool).await.unwrap();
+            let finished = game.state == GameState::Finished;
+            assert_eq!(last_released, visible_until(&all_events, 2, finished));
+            let (_, visible) = load_game_state(game_id, &settings, &pool).await.unwrap();
+            assert!(finished || visible == last_released);
+            if finished {
+                break;
+            }
+            assert!(last_released < all_events.last().unwrap().0);
+        }
+    }
+}
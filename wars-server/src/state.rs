@@ -1,161 +1,1060 @@
+use crate::config::Limits;
+use crate::fog;
+use crate::metrics;
 use crate::model::{
-    DatabasePool, create_game, load_game, load_game_events, save_game, set_game_player,
+    self, DatabasePool, SaveError, claim_game_player, count_unfinished_games, create_game,
+    load_chat_messages, load_game, load_game_events, load_game_settings, load_map, load_map_infos,
+    load_public_games, save_chat_message, save_map, save_webhook, set_game_player,
+};
+use crate::secrets;
+use crate::spectators;
+use crate::timers::{self, TurnClock};
+use crate::webhooks;
+use std::collections::{HashMap, HashSet};
+use wars::auth::UserId;
+use wars::game::{Event, Game, Map, PlayerNumber};
+use wars::protocol::{
+    ActionMessage, Capability, ChatMessage, ChatScope, ErrorCode, ErrorReply, EventIndex,
+    EventMessage, GameId, GameSettings, Handshake, MAX_CHAT_MESSAGE_LENGTH, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
 };
-use include_dir::{File, include_dir};
-use std::sync::LazyLock;
-use wars::protocol::{ActionMessage, EventMessage, GameId};
 
 #[derive(Copy, Clone)]
 pub enum Recipient {
     Actor,
+    Everyone,
     Subscribers(GameId),
+    Spectators(GameId),
+    /// Connections that joined the game as the player
+    Team(GameId, PlayerNumber),
+    /// Subscribers that joined the game as the player, or that haven't joined
+    /// it with `None`
+    Viewers(GameId, Option<PlayerNumber>),
 }
 pub type Events = Vec<(Recipient, EventMessage)>;
 
-pub struct State {}
+fn reply_error(code: ErrorCode, message: impl Into<String>) -> Events {
+    Events::from_iter([(
+        Recipient::Actor,
+        EventMessage::Error(ErrorReply::new(code, message)),
+    )])
+}
 
-static MAPS: LazyLock<Vec<wars::game::Map>> = LazyLock::new(|| {
-    include_dir!("$CARGO_MANIFEST_DIR/../data/maps")
-        .entries()
-        .into_iter()
-        .filter_map(|e| {
-            e.as_file()
-                .and_then(File::contents_utf8)
-                .and_then(|content| wars::game::Map::from_json(content).ok())
+pub struct State {
+    /// Who this connection is to other users, such as in chat
+    user_id: UserId,
+    /// Players this connection has joined games as, for fog of war
+    joined: HashMap<GameId, PlayerNumber>,
+    /// Games this connection receives the events of as a subscriber
+    subscribed: HashSet<GameId>,
+    /// Games this connection watches as a spectator and may not change
+    spectating: HashSet<GameId>,
+    /// Games this connection created or gave the password of, which it may
+    /// follow even if they're password protected
+    admitted: HashSet<GameId>,
+    /// Agreed on in the handshake that has to come before other requests
+    handshake: Option<Handshake>,
+    limits: Limits,
+}
+
+/// Load the game, change it and save the events emitted, or reply with why
+/// that failed
+async fn update_game<T>(
+    game_id: GameId,
+    pool: &DatabasePool,
+    change: impl FnMut(&mut Game, &mut dyn FnMut(Event)) -> T,
+) -> Result<(Game, Game, Vec<(EventIndex, Event)>, T), Events> {
+    model::update_game(game_id, pool, change)
+        .await
+        .map_err(|e| match e {
+            SaveError::Database(sqlx::Error::RowNotFound) => {
+                reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"))
+            }
+            SaveError::Conflict => reply_error(
+                ErrorCode::ServerError,
+                format!("Game {game_id} is busy, try again"),
+            ),
+            SaveError::Database(e) => {
+                tracing::error!("Error saving game: {e}");
+                reply_error(ErrorCode::ServerError, "Internal server error")
+            }
         })
-        .collect()
-});
+}
+
+/// Capabilities this server offers in the handshake
+const CAPABILITIES: &[Capability] = &[Capability::Binary, Capability::Fog];
 
 impl State {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(user_id: UserId, limits: Limits) -> Self {
+        Self {
+            user_id,
+            limits,
+            joined: HashMap::new(),
+            subscribed: HashSet::new(),
+            spectating: HashSet::new(),
+            admitted: HashSet::new(),
+            handshake: None,
+        }
+    }
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.handshake.as_ref()
+    }
+    /// Refuse password protected games to connections that didn't give the
+    /// password
+    fn check_admitted(&self, game_id: GameId, settings: &GameSettings) -> Option<Events> {
+        (settings.password.is_some() && !self.admitted.contains(&game_id)).then(|| {
+            reply_error(
+                ErrorCode::PermissionDenied,
+                format!("Game {game_id} is password protected, join it first"),
+            )
+        })
+    }
+    /// Refuse games this connection's client can't show
+    fn check_supported(&self, game_id: GameId, settings: &GameSettings) -> Option<Events> {
+        let fog_supported = self
+            .handshake
+            .as_ref()
+            .is_some_and(|handshake| handshake.supports(Capability::Fog));
+        (settings.rules.fog && !fog_supported).then(|| {
+            reply_error(
+                ErrorCode::Unsupported,
+                format!("Game {game_id} uses fog of war, which this client doesn't support"),
+            )
+        })
+    }
+    /// Whether this connection watches the game as a spectator
+    pub fn is_spectating(&self, game_id: GameId) -> bool {
+        self.spectating.contains(&game_id)
+    }
+    /// Whether this connection subscribes to or spectates the game
+    pub fn is_following(&self, game_id: GameId) -> bool {
+        self.subscribed.contains(&game_id) || self.spectating.contains(&game_id)
+    }
+    /// Refuse following another game when this connection follows as many as
+    /// it may
+    fn check_subscription_limit(&self, game_id: GameId) -> Option<Events> {
+        let max = self.limits.max_subscriptions;
+        (!self.is_following(game_id) && self.subscribed.len() + self.spectating.len() >= max).then(
+            || {
+                reply_error(
+                    ErrorCode::TooManySubscriptions,
+                    format!("Can't follow more than {max} games at once"),
+                )
+            },
+        )
+    }
+    fn subscriptions(&self) -> EventMessage {
+        let mut subscribed: Vec<_> = self.subscribed.iter().copied().collect();
+        let mut spectating: Vec<_> = self.spectating.iter().copied().collect();
+        subscribed.sort();
+        spectating.sort();
+        EventMessage::Subscriptions(subscribed, spectating)
+    }
+    /// The player this connection has joined the game as
+    pub fn player_number(&self, game_id: GameId) -> Option<PlayerNumber> {
+        self.joined.get(&game_id).copied()
+    }
+    /// The game as this connection sees it
+    async fn game_state(
+        &self,
+        game_id: GameId,
+        pool: &DatabasePool,
+    ) -> model::DatabaseResult<EventMessage> {
+        let (game, _players, last_event_index) = load_game(game_id, pool).await?;
+        let viewer = self.player_number(game_id);
+        fog::game_state(game_id, &game, viewer, last_event_index, pool).await
+    }
+    /// The game as this connection sees it if the game has fog of war. Its
+    /// events could show what the connection can't see, so this is sent
+    /// instead.
+    async fn fog_game_state(
+        &self,
+        game_id: GameId,
+        pool: &DatabasePool,
+    ) -> model::DatabaseResult<Option<EventMessage>> {
+        let (game, _players, last_event_index) = load_game(game_id, pool).await?;
+        if !game.rules.fog {
+            return Ok(None);
+        }
+        let viewer = self.player_number(game_id);
+        fog::game_state(game_id, &game, viewer, last_event_index, pool)
+            .await
+            .map(Some)
+    }
+    /// Whether this connection may read the chat message
+    fn can_read(&self, message: &ChatMessage) -> bool {
+        match message.scope {
+            ChatScope::Lobby | ChatScope::Game(_) => true,
+            ChatScope::Team(game_id) => {
+                !self.spectating.contains(&game_id)
+                    && message.player_number.is_some()
+                    && self.player_number(game_id) == message.player_number
+            }
+            ChatScope::Spectators(game_id) => self.spectating.contains(&game_id),
+        }
+    }
+    /// Chat history of the game that this connection may read
+    async fn chat_history(&self, game_id: GameId, pool: &DatabasePool) -> Vec<EventMessage> {
+        match load_chat_messages(game_id, pool).await {
+            Ok(messages) => messages
+                .into_iter()
+                .filter(|message| self.can_read(message))
+                .map(EventMessage::Chat)
+                .collect(),
+            Err(e) => {
+                tracing::error!("Error loading chat messages: {e}");
+                Vec::new()
+            }
+        }
+    }
+    /// Watch the game as a spectator, who may see it with a delay
+    async fn spectate(&mut self, game_id: GameId, pool: &DatabasePool) -> Events {
+        if let Some(refusal) = self.check_subscription_limit(game_id) {
+            return refusal;
+        }
+        let Ok((_, players, _)) = load_game(game_id, pool).await else {
+            return reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"));
+        };
+        let Ok(settings) = load_game_settings(game_id, pool).await else {
+            return reply_error(ErrorCode::ServerError, "Internal server error");
+        };
+        if let Some(refusal) = self
+            .check_admitted(game_id, &settings)
+            .or_else(|| self.check_supported(game_id, &settings))
+        {
+            return refusal;
+        }
+        let Ok((game, last_event_index)) =
+            spectators::load_game_state(game_id, &settings, pool).await
+        else {
+            return reply_error(ErrorCode::ServerError, "Internal server error");
+        };
+        self.subscribed.remove(&game_id);
+        self.spectating.insert(game_id);
+        let chat_history = self.chat_history(game_id, pool).await;
+        Events::from_iter(
+            [EventMessage::GameState(
+                Box::new(game),
+                settings.without_password(),
+                players,
+                last_event_index,
+            )]
+            .into_iter()
+            .chain(chat_history)
+            .map(|message| (Recipient::Actor, message)),
+        )
     }
     pub async fn action(&mut self, action: ActionMessage, pool: &DatabasePool) -> Events {
+        if self.handshake.is_none()
+            && !matches!(
+                action,
+                ActionMessage::Hello(..) | ActionMessage::NoOp | ActionMessage::Quit
+            )
+        {
+            return reply_error(
+                ErrorCode::IncompatibleVersion,
+                format!(
+                    "Send Hello with the protocol versions the client supports first, the \
+                     server supports versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+                ),
+            );
+        }
         match action {
+            ActionMessage::GameAction(game_id, _) if self.spectating.contains(&game_id) => {
+                reply_error(
+                    ErrorCode::PermissionDenied,
+                    format!("Spectators can't play game {game_id}"),
+                )
+            }
             ActionMessage::NoOp => Events::new(),
+            ActionMessage::Hello(versions, capabilities) => {
+                match Handshake::negotiate(&versions, &capabilities, CAPABILITIES) {
+                    Ok(handshake) => {
+                        self.handshake = Some(handshake.clone());
+                        Events::from_iter([(Recipient::Actor, EventMessage::Welcome(handshake))])
+                    }
+                    Err(reason) => reply_error(ErrorCode::IncompatibleVersion, reason),
+                }
+            }
             ActionMessage::Ping => Events::from_iter([(Recipient::Actor, EventMessage::Pong)]),
             ActionMessage::GameAction(game_id, action) => {
                 tracing::info!("GameAction({game_id}, {action:?})");
+                metrics::record_action(&action);
                 let mut events = Events::new();
-                let mut new_game_events = Vec::new();
-                let (mut game, _players, _last_event_index) = match load_game(game_id, pool).await {
-                    Ok(game) => game,
-                    Err(_) => {
-                        return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
-                    }
-                };
-                let mut emit = |event: wars::game::Event| {
-                    new_game_events.push(event.clone());
-                    events.push((
-                        Recipient::Subscribers(game_id),
-                        EventMessage::GameEvent(game_id, event),
-                    ));
+                let player_number = self.player_number(game_id);
+                // The turn is checked on the game being changed, as it may
+                // have passed while the action was on its way
+                let (previous, game, new_game_events, result) =
+                    match update_game(game_id, pool, |game, emit| {
+                        (player_number.is_some() && game.in_turn_number() == player_number)
+                            .then(|| wars::game::action::perform(game, action.clone(), emit))
+                    })
+                    .await
+                    {
+                        Ok(updated) => updated,
+                        Err(reply) => return reply,
+                    };
+                let Some(result) = result else {
+                    return reply_error(
+                        ErrorCode::PermissionDenied,
+                        format!("It's not your turn in game {game_id}"),
+                    );
                 };
-                if let Err(e) = wars::game::action::perform(&mut game, action, &mut emit) {
+                if let Err(e) = result {
                     tracing::info!("Error performing action: {e}");
+                    metrics::record_action_error(&e);
                     events.push((Recipient::Actor, EventMessage::GameActionError(game_id, e)));
                 }
 
-                if let Err(e) = save_game(game_id, game, new_game_events, pool).await {
-                    tracing::error!("Error saving game: {e}");
-                    events = Events::from_iter([(Recipient::Actor, EventMessage::ServerError)]);
+                let time_left = timers::record_events(game_id, &game, &new_game_events, pool)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Error updating turn clock: {e}");
+                        None
+                    });
+                match fog::subscriber_messages(game_id, &previous, &game, &new_game_events, pool)
+                    .await
+                {
+                    Ok(messages) => events.extend(messages),
+                    Err(e) => tracing::error!("Error sending events to subscribers: {e}"),
+                }
+                events.extend(time_left.map(|message| (Recipient::Subscribers(game_id), message)));
+                match spectators::released_events(game_id, &game.state, &new_game_events, pool)
+                    .await
+                {
+                    Ok(released) => events.extend(
+                        released
+                            .into_iter()
+                            .map(|message| (Recipient::Spectators(game_id), message)),
+                    ),
+                    Err(e) => tracing::error!("Error releasing events to spectators: {e}"),
                 }
 
                 events
             }
+            // Only players get the events as they happen
+            ActionMessage::SubscribeGame(game_id) if self.player_number(game_id).is_none() => {
+                self.spectate(game_id, pool).await
+            }
             ActionMessage::SubscribeGame(game_id) => {
+                if let Some(refusal) = self.check_subscription_limit(game_id) {
+                    return refusal;
+                }
+                self.spectating.remove(&game_id);
                 let Ok((game, players, last_event_index)) = load_game(game_id, pool).await else {
-                    return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
+                    return reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"));
                 };
-                Events::from_iter([(
-                    Recipient::Actor,
-                    EventMessage::GameState(game, players, last_event_index),
-                )])
-            }
-            ActionMessage::CreateGame(map_name) => {
-                tracing::info!("CreateGame {map_name}");
-                tracing::info!("Maps:");
-                MAPS.iter().for_each(|m| tracing::info!("{}", m.name));
-                let map = MAPS.iter().find(|m| m.name == map_name);
-                if let Some(map) = map {
-                    tracing::info!("Found map");
-                    let players: Vec<_> = map.player_numbers().iter().map(|pn| (*pn, 0)).collect();
-                    let game = wars::game::Game::new(map.clone(), &players);
-                    tracing::info!("Creating game");
-                    let Ok(game_id) = create_game(game, pool).await else {
-                        return Events::from_iter([(Recipient::Actor, EventMessage::ServerError)]);
-                    };
-                    Events::from_iter([(Recipient::Actor, EventMessage::GameCreated(game_id))])
-                } else {
-                    tracing::info!("Error");
-                    Events::from_iter([(Recipient::Actor, EventMessage::NoSuchMap)])
+                let Ok(settings) = load_game_settings(game_id, pool).await else {
+                    return reply_error(ErrorCode::ServerError, "Internal server error");
+                };
+                if let Some(refusal) = self.check_supported(game_id, &settings) {
+                    return refusal;
+                }
+                let time_left = timers::time_left(game_id, &game, pool)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Error loading turn clock: {e}");
+                        None
+                    });
+                self.subscribed.insert(game_id);
+                let chat_history = self.chat_history(game_id, pool).await;
+                Events::from_iter(
+                    [EventMessage::GameState(
+                        Box::new(game.view_for(self.joined.get(&game_id).copied())),
+                        settings.without_password(),
+                        players,
+                        last_event_index,
+                    )]
+                    .into_iter()
+                    .chain(time_left)
+                    .chain(chat_history)
+                    .map(|message| (Recipient::Actor, message)),
+                )
+            }
+            ActionMessage::SpectateGame(game_id) => self.spectate(game_id, pool).await,
+            ActionMessage::SetWebhook(url) => {
+                if let Some(url) = &url
+                    && let Err(message) = webhooks::check_url(url).await
+                {
+                    return reply_error(ErrorCode::InvalidRequest, message);
+                }
+                if let Err(e) = save_webhook(self.user_id, url.as_deref(), pool).await {
+                    tracing::error!("Error saving webhook: {e}");
+                    return reply_error(ErrorCode::ServerError, "Internal server error");
+                }
+                Events::from_iter([(Recipient::Actor, EventMessage::WebhookSet(url))])
+            }
+            ActionMessage::UnsubscribeGame(game_id) => {
+                self.subscribed.remove(&game_id);
+                self.spectating.remove(&game_id);
+                Events::from_iter([(Recipient::Actor, self.subscriptions())])
+            }
+            ActionMessage::GetSubscriptions => {
+                Events::from_iter([(Recipient::Actor, self.subscriptions())])
+            }
+            ActionMessage::CreateGame(map_id, settings) => {
+                tracing::info!("CreateGame {map_id}");
+                if let Some(max_games) = self.limits.max_games {
+                    match count_unfinished_games(pool).await {
+                        Ok(games) if games >= max_games => {
+                            return reply_error(
+                                ErrorCode::ServerFull,
+                                "Too many games in progress, try again later",
+                            );
+                        }
+                        Ok(_) => (),
+                        Err(e) => {
+                            tracing::error!("Error counting games: {e}");
+                            return reply_error(ErrorCode::ServerError, "Internal server error");
+                        }
+                    }
+                }
+                let map = match load_map(map_id, pool).await {
+                    Ok(map) => map,
+                    Err(sqlx::Error::RowNotFound) => {
+                        return reply_error(
+                            ErrorCode::NoSuchMap,
+                            format!("Map {map_id} not found"),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("Error loading map: {e}");
+                        return reply_error(ErrorCode::ServerError, "Internal server error");
+                    }
+                };
+                let players: Vec<_> = map.player_numbers().iter().map(|pn| (*pn, 0)).collect();
+                let game = wars::game::Game::with_rules(map, &players, settings.rules.clone());
+                tracing::info!("Creating game");
+                let turn_clock = settings.turn_timer.clone().map(TurnClock::new);
+                let Ok(game_id) = create_game(map_id, game, &settings, turn_clock, pool).await
+                else {
+                    return reply_error(ErrorCode::ServerError, "Internal server error");
+                };
+                self.admitted.insert(game_id);
+                Events::from_iter([(Recipient::Actor, EventMessage::GameCreated(game_id))])
+            }
+            ActionMessage::UploadMap(data) => {
+                let map = match Map::from_json(&data) {
+                    Ok(map) => map,
+                    Err(e) => return reply_error(ErrorCode::InvalidMap, e.to_string()),
+                };
+                if let Err(e) = map.validate() {
+                    return reply_error(ErrorCode::InvalidMap, e.to_string());
+                }
+                match save_map(&map, Some(self.user_id), timers::now(), pool).await {
+                    Ok(info) => {
+                        tracing::info!("User {} uploaded map {}", self.user_id, info.map_id);
+                        Events::from_iter([(Recipient::Actor, EventMessage::MapUploaded(info))])
+                    }
+                    Err(e) => {
+                        tracing::error!("Error saving map: {e}");
+                        reply_error(ErrorCode::ServerError, "Internal server error")
+                    }
                 }
             }
-            ActionMessage::JoinGame(game_id, player_number) => {
+            ActionMessage::JoinGame(game_id, player_number, password) => {
+                let Ok(settings) = load_game_settings(game_id, pool).await else {
+                    return reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"));
+                };
+                if !secrets::accepts_password(&settings, password.as_deref()) {
+                    return reply_error(
+                        ErrorCode::WrongPassword,
+                        format!("Wrong password for game {game_id}"),
+                    );
+                }
+                self.admitted.insert(game_id);
+                if let Some(refusal) = self.check_supported(game_id, &settings) {
+                    return refusal;
+                }
                 //TODO: use user data
                 let slot = wars::protocol::PlayerSlotType::Human(Some("It's-a-meee!".to_string()));
-                let Ok(_) = set_game_player(game_id, player_number, &slot, pool).await else {
-                    return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
+                match claim_game_player(game_id, player_number, &slot, self.user_id, pool).await {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        return reply_error(
+                            ErrorCode::PermissionDenied,
+                            format!("Player {player_number} of game {game_id} is taken"),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("Error joining game: {e}");
+                        return reply_error(ErrorCode::ServerError, "Internal server error");
+                    }
+                }
+                self.joined.insert(game_id, player_number);
+                let message = EventMessage::GameJoined(game_id, player_number, slot);
+                let mut events = Events::from_iter([
+                    (Recipient::Subscribers(game_id), message.clone()),
+                    (Recipient::Spectators(game_id), message),
+                ]);
+                // Players see the game as it is rather than with the spectator
+                // delay, and in fog of war more than before joining
+                let state = if self.spectating.remove(&game_id) {
+                    self.subscribed.insert(game_id);
+                    self.game_state(game_id, pool).await.map(Some)
+                } else if self.subscribed.contains(&game_id) {
+                    self.fog_game_state(game_id, pool).await
+                } else {
+                    Ok(None)
                 };
-                Events::from_iter([(
-                    Recipient::Subscribers(game_id),
-                    EventMessage::GameJoined(game_id, player_number, slot),
-                )])
+                match state {
+                    Ok(state) => events.extend(state.map(|state| (Recipient::Actor, state))),
+                    Err(e) => tracing::error!("Error loading game state: {e}"),
+                }
+                events
             }
             ActionMessage::StartGame(game_id) => {
                 tracing::info!("Starting game {game_id}");
-                let mut events = vec![(
-                    Recipient::Subscribers(game_id),
-                    EventMessage::GameStarted(game_id),
-                )];
-                let mut new_game_events = Vec::new();
-                let (mut game, _players, _last_event_index) = match load_game(game_id, pool).await {
-                    Ok(game) => game,
-                    Err(_) => {
-                        return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
-                    }
-                };
-                let mut emit = |event: wars::game::Event| {
-                    new_game_events.push(event.clone());
-                    events.push((
+                let mut events = vec![
+                    (
                         Recipient::Subscribers(game_id),
-                        EventMessage::GameEvent(game_id, event),
-                    ));
-                };
-                if let Err(e) = wars::game::action::start(&mut game, &mut emit) {
+                        EventMessage::GameStarted(game_id),
+                    ),
+                    (
+                        Recipient::Spectators(game_id),
+                        EventMessage::GameStarted(game_id),
+                    ),
+                ];
+                let (previous, game, new_game_events, result) =
+                    match update_game(game_id, pool, wars::game::action::start).await {
+                        Ok(updated) => updated,
+                        Err(reply) => return reply,
+                    };
+                if let Err(e) = result {
                     tracing::info!("Error starting game: {e}");
                     events.push((Recipient::Actor, EventMessage::GameActionError(game_id, e)));
                 }
 
-                if let Err(e) = save_game(game_id, game, new_game_events, pool).await {
-                    tracing::error!("Error saving game: {e}");
-                    events = Events::from_iter([(Recipient::Actor, EventMessage::ServerError)]);
+                let time_left = timers::record_events(game_id, &game, &new_game_events, pool)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Error updating turn clock: {e}");
+                        None
+                    });
+                match fog::subscriber_messages(game_id, &previous, &game, &new_game_events, pool)
+                    .await
+                {
+                    Ok(messages) => events.extend(messages),
+                    Err(e) => tracing::error!("Error sending events to subscribers: {e}"),
+                }
+                events.extend(time_left.map(|message| (Recipient::Subscribers(game_id), message)));
+                match spectators::released_events(game_id, &game.state, &new_game_events, pool)
+                    .await
+                {
+                    Ok(released) => events.extend(
+                        released
+                            .into_iter()
+                            .map(|message| (Recipient::Spectators(game_id), message)),
+                    ),
+                    Err(e) => tracing::error!("Error releasing events to spectators: {e}"),
                 }
 
                 events
             }
             ActionMessage::GetEvents(game_id, since) => {
-                let Ok(events) = load_game_events(game_id, since, pool).await else {
-                    return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
+                // Only players see the game as it is, everyone else sees what
+                // has been released to spectators
+                let playing =
+                    self.player_number(game_id).is_some() && !self.spectating.contains(&game_id);
+                if playing {
+                    match self.fog_game_state(game_id, pool).await {
+                        Ok(Some(state)) => return Events::from_iter([(Recipient::Actor, state)]),
+                        Ok(None) => (),
+                        Err(_) => {
+                            return reply_error(
+                                ErrorCode::NoSuchGame,
+                                format!("Game {game_id} not found"),
+                            );
+                        }
+                    }
+                }
+                let events = if playing {
+                    load_game_events(game_id, since, pool).await
+                } else {
+                    match load_game_settings(game_id, pool).await {
+                        Ok(settings) => {
+                            if let Some(refusal) = self.check_admitted(game_id, &settings) {
+                                return refusal;
+                            }
+                            spectators::load_events(game_id, &settings, since, pool).await
+                        }
+                        Err(e) => Err(e),
+                    }
+                };
+                let Ok(events) = events else {
+                    return reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"));
                 };
                 events
                     .into_iter()
-                    .map(|(index, event)| (Recipient::Actor, EventMessage::GameEvent(index, event)))
+                    .map(|(index, event)| {
+                        (
+                            Recipient::Actor,
+                            EventMessage::GameEvent(game_id, index, event),
+                        )
+                    })
                     .collect()
             }
-            ActionMessage::GetMaps => {
-                Events::from_iter([(Recipient::Actor, EventMessage::Maps(MAPS.clone()))])
+            ActionMessage::GetMaps => match load_map_infos(pool).await {
+                Ok(maps) => Events::from_iter([(Recipient::Actor, EventMessage::Maps(maps))]),
+                Err(e) => {
+                    tracing::error!("Error loading maps: {e}");
+                    reply_error(ErrorCode::ServerError, "Internal server error")
+                }
+            },
+            ActionMessage::GetGames => match load_public_games(pool).await {
+                Ok(games) => Events::from_iter([(Recipient::Actor, EventMessage::Games(games))]),
+                Err(e) => {
+                    tracing::error!("Error loading games: {e}");
+                    reply_error(ErrorCode::ServerError, "Internal server error")
+                }
+            },
+            ActionMessage::Chat(scope, text) => {
+                let text = text.trim();
+                if text.is_empty() {
+                    return Events::new();
+                }
+                if text.chars().count() > MAX_CHAT_MESSAGE_LENGTH {
+                    return reply_error(
+                        ErrorCode::ChatMessageTooLong,
+                        format!(
+                            "Chat messages can be at most {MAX_CHAT_MESSAGE_LENGTH} characters"
+                        ),
+                    );
+                }
+                let spectating = scope
+                    .game_id()
+                    .is_some_and(|game_id| self.spectating.contains(&game_id));
+                let player_number = scope
+                    .game_id()
+                    .and_then(|game_id| self.player_number(game_id))
+                    .filter(|_| !spectating);
+                // Spectators only talk among themselves so that they can't
+                // tell the players what they have seen
+                let recipients = match (scope, player_number) {
+                    (ChatScope::Lobby, _) => vec![Recipient::Everyone],
+                    (ChatScope::Game(game_id), _) if !spectating => vec![
+                        Recipient::Subscribers(game_id),
+                        Recipient::Spectators(game_id),
+                    ],
+                    (ChatScope::Team(game_id), Some(player_number)) => {
+                        vec![Recipient::Team(game_id, player_number)]
+                    }
+                    (ChatScope::Spectators(game_id), _) if spectating => {
+                        vec![Recipient::Spectators(game_id)]
+                    }
+                    (
+                        ChatScope::Game(game_id)
+                        | ChatScope::Team(game_id)
+                        | ChatScope::Spectators(game_id),
+                        _,
+                    ) => {
+                        return reply_error(
+                            ErrorCode::PermissionDenied,
+                            format!(
+                                "Spectators of game {game_id} can only talk among themselves \
+                                 and only its players to their team"
+                            ),
+                        );
+                    }
+                };
+                let message = ChatMessage {
+                    scope,
+                    author: self.user_id,
+                    player_number,
+                    text: text.to_owned(),
+                };
+                if let Some(game_id) = scope.game_id() {
+                    if load_game_settings(game_id, pool).await.is_err() {
+                        return reply_error(
+                            ErrorCode::NoSuchGame,
+                            format!("Game {game_id} not found"),
+                        );
+                    }
+                    if let Err(e) = save_chat_message(game_id, &message, pool).await {
+                        tracing::error!("Error saving chat message: {e}");
+                        return reply_error(ErrorCode::ServerError, "Internal server error");
+                    }
+                }
+                recipients
+                    .into_iter()
+                    .map(|recipient| (recipient, EventMessage::Chat(message.clone())))
+                    .collect()
             }
+            // Mutes are applied when sending
+            ActionMessage::MuteUser(_) | ActionMessage::UnmuteUser(_) => Events::new(),
             ActionMessage::Quit => Events::new(),
             ActionMessage::SetPlayerSlotType(game_id, player_number, slot) => {
-                let Ok(_) = set_game_player(game_id, player_number, &slot, pool).await else {
-                    return Events::from_iter([(Recipient::Actor, EventMessage::NoSuchGame)]);
+                let Ok(_) = set_game_player(game_id, player_number, &slot, None, pool).await else {
+                    return reply_error(ErrorCode::NoSuchGame, format!("Game {game_id} not found"));
                 };
-                Events::from_iter([(
-                    Recipient::Subscribers(game_id),
-                    EventMessage::GameJoined(game_id, player_number, slot),
-                )])
+                let message = EventMessage::GameJoined(game_id, player_number, slot);
+                Events::from_iter([
+                    (Recipient::Subscribers(game_id), message.clone()),
+                    (Recipient::Spectators(game_id), message),
+                ])
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::model::test::{database_pool, start_game};
+    use crate::state::*;
+    use wars::game::Action;
+
+    /// A connection that has done the handshake
+    async fn connect(user_id: UserId, limits: Limits, pool: &DatabasePool) -> State {
+        let mut state = State::new(user_id, limits);
+        let hello = ActionMessage::Hello(vec![PROTOCOL_VERSION], vec![Capability::Fog]);
+        let events = state.action(hello, pool).await;
+        assert_eq!(error_code(&events), None);
+        state
+    }
+
+    fn error_code(events: &Events) -> Option<ErrorCode> {
+        events.iter().find_map(|(_, message)| match message {
+            EventMessage::Error(reply) => Some(reply.code),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn requests_before_the_handshake_are_refused() {
+        let pool = database_pool().await;
+        let mut state = State::new(1, Limits::default());
+        let events = state.action(ActionMessage::GetGames, &pool).await;
+        assert_eq!(error_code(&events), Some(ErrorCode::IncompatibleVersion));
+        let events = state
+            .action(ActionMessage::Hello(vec![1], Vec::new()), &pool)
+            .await;
+        assert_eq!(error_code(&events), Some(ErrorCode::IncompatibleVersion));
+
+        let hello = ActionMessage::Hello(vec![PROTOCOL_VERSION], Vec::new());
+        let events = state.action(hello, &pool).await;
+        assert!(matches!(
+            &events[..],
+            [(_, EventMessage::Welcome(handshake))] if handshake.version == PROTOCOL_VERSION
+        ));
+        let events = state.action(ActionMessage::GetGames, &pool).await;
+        assert_eq!(error_code(&events), None);
+    }
+
+    #[tokio::test]
+    async fn subscribing_without_joining_spectates_with_delay() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            spectator_delay_turns: 1,
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        let (_, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+
+        let mut state = connect(1, Limits::default(), &pool).await;
+        let events = state
+            .action(ActionMessage::SubscribeGame(game_id), &pool)
+            .await;
+        assert!(state.is_spectating(game_id));
+        let released = events.iter().find_map(|(_, message)| match message {
+            EventMessage::GameState(_, _, _, index) => Some(*index),
+            _ => None,
+        });
+        assert!(released.unwrap() < last_event_index);
+
+        // Joining makes the spectator a player who sees the game as it is
+        let events = state
+            .action(ActionMessage::JoinGame(game_id, 1, None), &pool)
+            .await;
+        assert!(!state.is_spectating(game_id));
+        assert!(events.iter().any(|(_, message)| matches!(
+            message,
+            EventMessage::GameState(_, _, _, index) if *index == last_event_index
+        )));
+    }
+
+    #[tokio::test]
+    async fn only_players_get_events_not_yet_released() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            spectator_delay_turns: 1,
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        let (_, _, last_event_index) = load_game(game_id, &pool).await.unwrap();
+        let last_index = |events: &Events| {
+            events
+                .iter()
+                .filter_map(|(_, message)| match message {
+                    EventMessage::GameEvent(_, index, _) => Some(*index),
+                    _ => None,
+                })
+                .max()
+        };
+
+        let mut stranger = connect(1, Limits::default(), &pool).await;
+        let events = stranger
+            .action(ActionMessage::GetEvents(game_id, 0), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+        assert!(last_index(&events).is_none_or(|index| index < last_event_index));
+
+        let mut player = connect(2, Limits::default(), &pool).await;
+        player
+            .action(ActionMessage::JoinGame(game_id, 1, None), &pool)
+            .await;
+        let events = player
+            .action(ActionMessage::GetEvents(game_id, 0), &pool)
+            .await;
+        assert_eq!(last_index(&events), Some(last_event_index));
+    }
+
+    #[tokio::test]
+    async fn password_protected_games_are_only_followed_by_those_who_joined() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            password: Some("hunter2".to_string()),
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        let stored = load_game_settings(game_id, &pool).await.unwrap();
+        assert!(!stored.password.unwrap().contains("hunter2"));
+
+        let mut stranger = connect(1, Limits::default(), &pool).await;
+        for action in [
+            ActionMessage::SpectateGame(game_id),
+            ActionMessage::SubscribeGame(game_id),
+            ActionMessage::GetEvents(game_id, 0),
+        ] {
+            let events = stranger.action(action, &pool).await;
+            assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+        }
+        let events = stranger
+            .action(ActionMessage::JoinGame(game_id, 1, None), &pool)
+            .await;
+        assert_eq!(error_code(&events), Some(ErrorCode::WrongPassword));
+
+        let password = Some("hunter2".to_string());
+        let mut player = connect(2, Limits::default(), &pool).await;
+        let events = player
+            .action(ActionMessage::JoinGame(game_id, 1, password), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+        for action in [
+            ActionMessage::SubscribeGame(game_id),
+            ActionMessage::GetEvents(game_id, 0),
+        ] {
+            let events = player.action(action, &pool).await;
+            assert_eq!(error_code(&events), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn players_taken_by_another_user_cant_be_joined() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let join = |player_number| ActionMessage::JoinGame(game_id, player_number, None);
+
+        let mut first = connect(1, Limits::default(), &pool).await;
+        let events = first.action(join(1), &pool).await;
+        assert_eq!(error_code(&events), None);
+        let mut second = connect(2, Limits::default(), &pool).await;
+        let events = second.action(join(1), &pool).await;
+        assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+        assert_eq!(second.player_number(game_id), None);
+
+        // The user who has the player may join as them again
+        let events = first.action(join(1), &pool).await;
+        assert_eq!(error_code(&events), None);
+        let events = second.action(join(2), &pool).await;
+        assert_eq!(error_code(&events), None);
+    }
+
+    #[tokio::test]
+    async fn only_the_player_in_turn_can_act() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let end_turn = || ActionMessage::GameAction(game_id, Action::EndTurn);
+
+        let mut stranger = connect(1, Limits::default(), &pool).await;
+        let events = stranger.action(end_turn(), &pool).await;
+        assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+
+        let mut other_player = connect(2, Limits::default(), &pool).await;
+        other_player
+            .action(ActionMessage::JoinGame(game_id, 2, None), &pool)
+            .await;
+        let events = other_player.action(end_turn(), &pool).await;
+        assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+
+        let mut player = connect(3, Limits::default(), &pool).await;
+        player
+            .action(ActionMessage::JoinGame(game_id, 1, None), &pool)
+            .await;
+        let events = player.action(end_turn(), &pool).await;
+        assert_eq!(error_code(&events), None);
+        let (game, _, _) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(game.in_turn_number(), Some(2));
+
+        // The turn passed, so now it's the other player's to end
+        let events = player.action(end_turn(), &pool).await;
+        assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+        let events = other_player.action(end_turn(), &pool).await;
+        assert_eq!(error_code(&events), None);
+    }
+
+    fn recipients(events: &Events) -> Vec<&'static str> {
+        events
+            .iter()
+            .map(|(recipient, _)| match recipient {
+                Recipient::Actor => "actor",
+                Recipient::Everyone => "everyone",
+                Recipient::Subscribers(_) => "subscribers",
+                Recipient::Spectators(_) => "spectators",
+                Recipient::Team(..) => "team",
+                Recipient::Viewers(..) => "viewers",
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chat_messages_have_a_length_limit() {
+        let pool = database_pool().await;
+        let mut state = connect(1, Limits::default(), &pool).await;
+
+        let text = "a".repeat(MAX_CHAT_MESSAGE_LENGTH);
+        let events = state
+            .action(ActionMessage::Chat(ChatScope::Lobby, text), &pool)
+            .await;
+        assert_eq!(recipients(&events), ["everyone"]);
+
+        // Characters are counted rather than bytes
+        let text = "ä".repeat(MAX_CHAT_MESSAGE_LENGTH);
+        let events = state
+            .action(ActionMessage::Chat(ChatScope::Lobby, text), &pool)
+            .await;
+        assert_eq!(recipients(&events), ["everyone"]);
+
+        let text = "a".repeat(MAX_CHAT_MESSAGE_LENGTH + 1);
+        let events = state
+            .action(ActionMessage::Chat(ChatScope::Lobby, text), &pool)
+            .await;
+        assert_eq!(error_code(&events), Some(ErrorCode::ChatMessageTooLong));
+
+        let text = "   ".to_owned();
+        let events = state
+            .action(ActionMessage::Chat(ChatScope::Lobby, text), &pool)
+            .await;
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn spectators_only_talk_among_themselves() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let mut player = connect(1, Limits::default(), &pool).await;
+        player
+            .action(ActionMessage::JoinGame(game_id, 1, None), &pool)
+            .await;
+        let mut spectator = connect(2, Limits::default(), &pool).await;
+        spectator
+            .action(ActionMessage::SpectateGame(game_id), &pool)
+            .await;
+        let chat = |scope| ActionMessage::Chat(scope, "Hello".to_owned());
+
+        let events = player.action(chat(ChatScope::Game(game_id)), &pool).await;
+        assert_eq!(recipients(&events), ["subscribers", "spectators"]);
+        let events = player.action(chat(ChatScope::Team(game_id)), &pool).await;
+        assert_eq!(recipients(&events), ["team"]);
+        let events = player
+            .action(chat(ChatScope::Spectators(game_id)), &pool)
+            .await;
+        assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+
+        let events = spectator
+            .action(chat(ChatScope::Spectators(game_id)), &pool)
+            .await;
+        assert_eq!(recipients(&events), ["spectators"]);
+        for scope in [ChatScope::Game(game_id), ChatScope::Team(game_id)] {
+            let events = spectator.action(chat(scope), &pool).await;
+            assert_eq!(error_code(&events), Some(ErrorCode::PermissionDenied));
+        }
+
+        // Chat history only has what each of them may read
+        let history = |events: Events| -> Vec<ChatScope> {
+            events
+                .into_iter()
+                .filter_map(|(_, message)| match message {
+                    EventMessage::Chat(message) => Some(message.scope),
+                    _ => None,
+                })
+                .collect()
+        };
+        let events = player
+            .action(ActionMessage::SubscribeGame(game_id), &pool)
+            .await;
+        assert_eq!(
+            history(events),
+            [ChatScope::Game(game_id), ChatScope::Team(game_id)]
+        );
+        let events = spectator
+            .action(ActionMessage::SpectateGame(game_id), &pool)
+            .await;
+        assert_eq!(
+            history(events),
+            [ChatScope::Game(game_id), ChatScope::Spectators(game_id)]
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_follow_a_limited_number_of_games() {
+        let pool = database_pool().await;
+        let mut game_ids = Vec::new();
+        for _ in 0..3 {
+            game_ids.push(start_game(&GameSettings::default(), &pool).await);
+        }
+        let limits = Limits {
+            max_subscriptions: 2,
+            ..Limits::default()
+        };
+        let mut state = connect(1, limits, &pool).await;
+        state
+            .action(ActionMessage::JoinGame(game_ids[0], 1, None), &pool)
+            .await;
+
+        let events = state
+            .action(ActionMessage::SubscribeGame(game_ids[0]), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+        let events = state
+            .action(ActionMessage::SpectateGame(game_ids[1]), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+        for action in [
+            ActionMessage::SubscribeGame(game_ids[2]),
+            ActionMessage::SpectateGame(game_ids[2]),
+        ] {
+            let events = state.action(action, &pool).await;
+            assert_eq!(error_code(&events), Some(ErrorCode::TooManySubscriptions));
+        }
+
+        // Following a game again doesn't count twice
+        let events = state
+            .action(ActionMessage::SpectateGame(game_ids[1]), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+
+        let events = state
+            .action(ActionMessage::UnsubscribeGame(game_ids[0]), &pool)
+            .await;
+        assert!(matches!(
+            &events[..],
+            [(_, EventMessage::Subscriptions(subscribed, spectating))]
+                if subscribed.is_empty() && spectating[..] == [game_ids[1]]
+        ));
+        let events = state
+            .action(ActionMessage::SpectateGame(game_ids[2]), &pool)
+            .await;
+        assert_eq!(error_code(&events), None);
+        assert!(state.is_spectating(game_ids[2]));
+    }
+}
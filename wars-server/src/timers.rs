@@ -0,0 +1,355 @@
+use crate::Sender;
+use crate::model::{
+    DatabasePool, DatabaseResult, SaveError, load_game, load_turn_clock, save_game, save_turn_clock,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use wars::game::{Event, Game, PlayerNumber, action};
+use wars::protocol::{EventIndex, EventMessage, GameId, TurnTimer};
+
+/// How often expired turns are looked for
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Turn time bookkeeping of a game with a turn timer, stored with the game
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct TurnClock {
+    pub timer: TurnTimer,
+    /// Unix time in seconds when the current turn started
+    pub turn_started_at: u64,
+    pub time_banks: HashMap<PlayerNumber, u64>,
+    /// Turns in a row each player has run out of time
+    pub timeouts: HashMap<PlayerNumber, u32>,
+}
+
+impl TurnClock {
+    pub fn new(timer: TurnTimer) -> Self {
+        Self {
+            timer,
+            turn_started_at: now(),
+            time_banks: HashMap::new(),
+            timeouts: HashMap::new(),
+        }
+    }
+    fn time_bank(&self, player_number: PlayerNumber) -> u64 {
+        let initial = self.timer.time_bank_seconds.unwrap_or(0);
+        *self.time_banks.get(&player_number).unwrap_or(&initial)
+    }
+    pub fn deadline(&self, player_number: PlayerNumber) -> u64 {
+        self.turn_started_at + self.timer.turn_seconds + self.time_bank(player_number)
+    }
+    pub fn time_left(&self, player_number: PlayerNumber, now: u64) -> u64 {
+        self.deadline(player_number).saturating_sub(now)
+    }
+    fn timeouts(&self, player_number: PlayerNumber) -> u32 {
+        *self.timeouts.get(&player_number).unwrap_or(&0)
+    }
+    /// Whether the player should surrender rather than just lose their turn
+    /// when it runs out
+    fn surrenders_on_timeout(&self, player_number: PlayerNumber) -> bool {
+        self.timer
+            .surrender_after_timeouts
+            .is_some_and(|limit| self.timeouts(player_number) + 1 >= limit)
+    }
+    fn end_turn(&mut self, player_number: PlayerNumber, now: u64) {
+        let time_left = self.time_left(player_number, now);
+        if self.timer.time_bank_seconds.is_some() {
+            self.time_banks.insert(player_number, time_left);
+        }
+        let timeouts = if time_left == 0 {
+            self.timeouts(player_number) + 1
+        } else {
+            0
+        };
+        self.timeouts.insert(player_number, timeouts);
+    }
+}
+
+/// When the current turn of a game runs out
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: u64,
+    turn_count: u32,
+}
+
+static DEADLINES: LazyLock<std::sync::Mutex<HashMap<GameId, Deadline>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn set_deadline(game_id: GameId, game: &Game, clock: &TurnClock) {
+    let mut deadlines = DEADLINES.lock().unwrap();
+    match game.in_turn_number() {
+        Some(player_number) => deadlines.insert(
+            game_id,
+            Deadline {
+                at: clock.deadline(player_number),
+                turn_count: game.turn_count,
+            },
+        ),
+        None => deadlines.remove(&game_id),
+    };
+}
+
+/// Track turns ending and starting in the new events of a game. Returns the
+/// time left message for subscribers when a new turn started.
+pub async fn record_events(
+    game_id: GameId,
+    game: &Game,
+    events: &[(EventIndex, Event)],
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<EventMessage>> {
+    let Some(mut clock) = load_turn_clock(game_id, pool).await? else {
+        return Ok(None);
+    };
+    let now = now();
+    let mut started = None;
+    for (_, event) in events {
+        match *event {
+            Event::EndTurn(player_number) => clock.end_turn(player_number, now),
+            Event::StartTurn(player_number) => {
+                clock.turn_started_at = now;
+                started = Some(player_number);
+            }
+            _ => (),
+        }
+    }
+    save_turn_clock(game_id, &clock, pool).await?;
+    set_deadline(game_id, game, &clock);
+
+    Ok(started.map(|player_number| {
+        EventMessage::TurnTimeLeft(game_id, player_number, clock.time_left(player_number, now))
+    }))
+}
+
+/// Time left in the current turn of the game, if it has a turn timer
+pub async fn time_left(
+    game_id: GameId,
+    game: &Game,
+    pool: &DatabasePool,
+) -> DatabaseResult<Option<EventMessage>> {
+    let Some(player_number) = game.in_turn_number() else {
+        return Ok(None);
+    };
+    let clock = load_turn_clock(game_id, pool).await?;
+    Ok(clock.map(|clock| {
+        EventMessage::TurnTimeLeft(
+            game_id,
+            player_number,
+            clock.time_left(player_number, now()),
+        )
+    }))
+}
+
+/// Start watching the clock of a game loaded at startup
+pub async fn restore(game_id: GameId, pool: &DatabasePool) -> DatabaseResult<()> {
+    if let Some(clock) = load_turn_clock(game_id, pool).await? {
+        let (game, _players, _last_event_index) = load_game(game_id, pool).await?;
+        set_deadline(game_id, &game, &clock);
+    }
+    Ok(())
+}
+
+/// Stop watching the clock of a game that was ended or deleted
+pub fn forget(game_id: GameId) {
+    DEADLINES.lock().unwrap().remove(&game_id);
+}
+
+/// End turns that run out of time, forever
+pub async fn run(pool: DatabasePool, sender: Arc<Mutex<Sender>>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = now();
+        let expired: Vec<_> = {
+            let mut deadlines = DEADLINES.lock().unwrap();
+            let expired: Vec<_> = deadlines
+                .iter()
+                .filter(|(_, deadline)| deadline.at <= now)
+                .map(|(game_id, deadline)| (*game_id, *deadline))
+                .collect();
+            for (game_id, _) in &expired {
+                deadlines.remove(game_id);
+            }
+            expired
+        };
+        for (game_id, deadline) in expired {
+            if let Err(e) = time_out(game_id, deadline, &pool, &sender).await {
+                tracing::error!("Error timing out turn in game {game_id}: {e}");
+            }
+        }
+    }
+}
+
+async fn time_out(
+    game_id: GameId,
+    deadline: Deadline,
+    pool: &DatabasePool,
+    sender: &Arc<Mutex<Sender>>,
+) -> anyhow::Result<()> {
+    let (previous, game, events) = loop {
+        let (previous, _players, last_event_index) = load_game(game_id, pool).await?;
+        let mut game = previous.clone();
+        if game.turn_count != deadline.turn_count {
+            // The turn ended in time after all
+            return Ok(());
+        }
+        let (Some(player_number), Some(clock)) =
+            (game.in_turn_number(), load_turn_clock(game_id, pool).await?)
+        else {
+            return Ok(());
+        };
+
+        let mut events = Vec::new();
+        let mut emit = |event| events.push(event);
+        if clock.surrenders_on_timeout(player_number) {
+            tracing::info!("Player {player_number} in game {game_id} surrenders on timeout");
+            action::surrender(&mut game, &mut emit)?;
+        } else {
+            tracing::info!("Player {player_number} in game {game_id} ran out of time");
+            action::end_turn(&mut game, &mut emit)?;
+        }
+
+        match save_game(game_id, last_event_index, &game, events, pool).await {
+            Ok(events) => break (previous, game, events),
+            Err(SaveError::Conflict) => {
+                tracing::info!("Game {game_id} changed while timing out, trying again");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let time_left = record_events(game_id, &game, &events, pool).await?;
+    let messages =
+        crate::fog::subscriber_messages(game_id, &previous, &game, &events, pool).await?;
+    let released = crate::spectators::released_events(game_id, &game.state, &events, pool).await?;
+
+    {
+        let mut sender = sender.lock().await;
+        for (recipient, message) in messages {
+            sender.send_to(recipient, &message);
+        }
+        if let Some(message) = time_left {
+            sender.send_subscribers(&(game_id as usize), &message);
+        }
+        for message in released {
+            sender.send_spectators(&(game_id as usize), &message);
+        }
+    }
+
+    tokio::spawn(crate::bots::play_turns(
+        game_id,
+        pool.clone(),
+        Arc::clone(sender),
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::test::{database_pool, start_game};
+    use crate::timers::*;
+    use wars::game::GameState;
+    use wars::protocol::GameSettings;
+
+    fn turn_timer() -> TurnTimer {
+        TurnTimer {
+            turn_seconds: 60,
+            time_bank_seconds: Some(30),
+            surrender_after_timeouts: Some(2),
+        }
+    }
+
+    #[test]
+    fn time_left_over_goes_to_the_time_bank() {
+        let mut clock = TurnClock::new(turn_timer());
+        clock.turn_started_at = 1000;
+        assert_eq!(clock.deadline(1), 1090);
+
+        // Ten seconds of the bank used
+        clock.end_turn(1, 1070);
+        assert_eq!(clock.time_bank(1), 20);
+        assert_eq!(clock.timeouts(1), 0);
+        assert_eq!(clock.time_bank(2), 30);
+
+        clock.turn_started_at = 2000;
+        assert_eq!(clock.deadline(1), 2080);
+        assert_eq!(clock.time_left(1, 2050), 30);
+        assert_eq!(clock.time_left(1, 2100), 0);
+    }
+
+    #[test]
+    fn players_surrender_after_timeouts_in_a_row() {
+        let mut clock = TurnClock::new(turn_timer());
+        clock.turn_started_at = 1000;
+        assert!(!clock.surrenders_on_timeout(1));
+        clock.end_turn(1, 1090);
+        assert_eq!(clock.time_bank(1), 0);
+        assert_eq!(clock.timeouts(1), 1);
+        assert!(clock.surrenders_on_timeout(1));
+
+        // Ending a turn in time starts the count over
+        clock.turn_started_at = 2000;
+        clock.end_turn(1, 2010);
+        assert_eq!(clock.timeouts(1), 0);
+        assert!(!clock.surrenders_on_timeout(1));
+    }
+
+    #[tokio::test]
+    async fn turns_running_out_are_ended() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            turn_timer: Some(turn_timer()),
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        restore(game_id, &pool).await.unwrap();
+        let (game, _, _) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(game.in_turn_number(), Some(1));
+        // The turn and the time bank ran out a while ago
+        let mut clock = load_turn_clock(game_id, &pool).await.unwrap().unwrap();
+        clock.turn_started_at -= 100;
+        save_turn_clock(game_id, &clock, &pool).await.unwrap();
+        let deadline = DEADLINES.lock().unwrap()[&game_id];
+        let sender = Arc::new(Mutex::new(Sender::new()));
+
+        time_out(game_id, deadline, &pool, &sender).await.unwrap();
+        let (game, _, _) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(game.in_turn_number(), Some(2));
+        let clock = load_turn_clock(game_id, &pool).await.unwrap().unwrap();
+        assert_eq!(clock.timeouts(1), 1);
+        assert_eq!(clock.time_bank(1), 0);
+
+        // A deadline of a turn that already ended is let go
+        time_out(game_id, deadline, &pool, &sender).await.unwrap();
+        let (game, _, _) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(game.in_turn_number(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn players_out_of_time_too_often_surrender() {
+        let pool = database_pool().await;
+        let settings = GameSettings {
+            turn_timer: Some(turn_timer()),
+            ..GameSettings::default()
+        };
+        let game_id = start_game(&settings, &pool).await;
+        restore(game_id, &pool).await.unwrap();
+        let mut clock = load_turn_clock(game_id, &pool).await.unwrap().unwrap();
+        clock.timeouts.insert(1, 1);
+        save_turn_clock(game_id, &clock, &pool).await.unwrap();
+        let deadline = DEADLINES.lock().unwrap()[&game_id];
+        let sender = Arc::new(Mutex::new(Sender::new()));
+
+        time_out(game_id, deadline, &pool, &sender).await.unwrap();
+        let (game, _, _) = load_game(game_id, &pool).await.unwrap();
+        assert_eq!(game.state, GameState::Finished);
+        assert!(!DEADLINES.lock().unwrap().contains_key(&game_id));
+    }
+}
@@ -0,0 +1,411 @@
+use crate::model::{DatabasePool, load_game_map_name, load_game_players, load_webhook};
+use anyhow::bail;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::Policy;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use url::{Host, Url};
+use wars::game::{Event, Game, PlayerNumber};
+use wars::protocol::{EventIndex, GameId, PlayerSlotType};
+
+/// Deliveries are tried this many times before they are given up on
+const ATTEMPTS: u32 = 5;
+/// Wait before trying a delivery again, doubled after each attempt
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How long connecting and the whole request may each take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posted to the webhook of a player when their turn starts
+#[derive(Serialize)]
+struct TurnStarted {
+    game_id: GameId,
+    /// Missing for games created before maps were stored
+    map: Option<String>,
+    round: u32,
+    player_number: PlayerNumber,
+    opponents: Vec<Opponent>,
+}
+
+#[derive(Serialize)]
+struct Opponent {
+    player_number: PlayerNumber,
+    /// Name of the human or bot playing, if known
+    name: Option<String>,
+}
+
+/// Whether the address is on the public internet rather than on the
+/// server's own machine or network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // This network and the shared address space of carriers
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Check that the webhook URL can be posted to. Its host must only resolve
+/// to public addresses so that webhooks can't reach services on the server's
+/// own network.
+pub async fn check_url(url: &str) -> Result<(Url, SocketAddr), String> {
+    resolve(url, is_public).await
+}
+
+/// Parse the URL and resolve its host to an address that is allowed
+async fn resolve(url: &str, allowed: fn(IpAddr) -> bool) -> Result<(Url, SocketAddr), String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid webhook URL: {e}"))?;
+    let (Some(host), Some(port)) = (url.host(), url.port_or_known_default()) else {
+        return Err("Webhook URLs must be HTTP or HTTPS URLs".to_owned());
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Webhook URLs must be HTTP or HTTPS URLs".to_owned());
+    }
+    let addresses: Vec<SocketAddr> = match host {
+        Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|e| format!("Could not resolve {domain}: {e}"))?
+            .collect(),
+        Host::Ipv4(ip) => vec![SocketAddr::new(ip.into(), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(ip.into(), port)],
+    };
+    if let Some(address) = addresses.iter().find(|address| !allowed(address.ip())) {
+        return Err(format!(
+            "Webhook host {host} is at {}, which is not a public address",
+            address.ip()
+        ));
+    }
+    let Some(address) = addresses.first().copied() else {
+        return Err(format!("Could not resolve {host}"));
+    };
+    Ok((url, address))
+}
+
+/// Let the player whose turn the saved events started know, if the user
+/// playing them has a webhook. Delivery happens in the background.
+pub fn notify_turn_start(
+    game_id: GameId,
+    game: &Game,
+    events: &[(EventIndex, Event)],
+    pool: &DatabasePool,
+) {
+    let Some(player_number) = game.in_turn_number() else {
+        return;
+    };
+    if !events
+        .iter()
+        .any(|(_, event)| *event == Event::StartTurn(player_number))
+    {
+        return;
+    }
+    let round = game.round_count;
+    let opponent_numbers: Vec<_> = game
+        .players
+        .iter()
+        .filter(|player| player.alive && player.number != player_number)
+        .map(|player| player.number)
+        .collect();
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        if let Err(e) = notify(game_id, player_number, round, opponent_numbers, &pool).await {
+            tracing::error!("Error notifying player {player_number} in game {game_id}: {e}");
+        }
+    });
+}
+
+async fn notify(
+    game_id: GameId,
+    player_number: PlayerNumber,
+    round: u32,
+    opponent_numbers: Vec<PlayerNumber>,
+    pool: &DatabasePool,
+) -> anyhow::Result<()> {
+    let Some(url) = load_webhook(game_id, player_number, pool).await? else {
+        return Ok(());
+    };
+    let payload = turn_started(game_id, player_number, round, opponent_numbers, pool).await?;
+    deliver(
+        &url,
+        &serde_json::to_string(&payload)?,
+        FIRST_RETRY_DELAY,
+        is_public,
+    )
+    .await
+}
+
+async fn turn_started(
+    game_id: GameId,
+    player_number: PlayerNumber,
+    round: u32,
+    opponent_numbers: Vec<PlayerNumber>,
+    pool: &DatabasePool,
+) -> anyhow::Result<TurnStarted> {
+    let players = load_game_players(game_id, pool).await?;
+    let opponents = opponent_numbers
+        .into_iter()
+        .map(|number| Opponent {
+            player_number: number,
+            name: players.iter().find_map(|(n, slot)| match slot {
+                PlayerSlotType::Human(name) if *n == number => name.clone(),
+                PlayerSlotType::Bot(name) if *n == number => Some(name.clone()),
+                _ => None,
+            }),
+        })
+        .collect();
+    Ok(TurnStarted {
+        game_id,
+        map: load_game_map_name(game_id, pool).await?,
+        round,
+        player_number,
+        opponents,
+    })
+}
+
+/// Post the body to the URL, trying again with a growing delay while the
+/// server can't be reached or has trouble of its own
+async fn deliver(
+    url: &str,
+    body: &str,
+    first_retry_delay: Duration,
+    allowed: fn(IpAddr) -> bool,
+) -> anyhow::Result<()> {
+    let mut delay = first_retry_delay;
+    for attempt in 1..=ATTEMPTS {
+        match post_json(url, body, allowed).await {
+            Ok(status) if status.is_success() => return Ok(()),
+            // Redirects could lead anywhere, so they aren't followed, and
+            // other client errors won't go away by trying again
+            Ok(status)
+                if status.is_redirection()
+                    || (status.is_client_error()
+                        && status != reqwest::StatusCode::REQUEST_TIMEOUT
+                        && status != reqwest::StatusCode::TOO_MANY_REQUESTS) =>
+            {
+                bail!("Webhook {url} refused the notification with status {status}")
+            }
+            Ok(status) => tracing::info!("Webhook {url} answered {status} on attempt {attempt}"),
+            Err(e) => tracing::info!("Webhook {url} failed on attempt {attempt}: {e:#}"),
+        }
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    bail!("Gave up on webhook {url} after {ATTEMPTS} attempts")
+}
+
+/// Post the JSON body to the URL and return the status of the response. The
+/// host is resolved and checked again each time, and the request is sent to
+/// the address that passed the check.
+async fn post_json(
+    url: &str,
+    body: &str,
+    allowed: fn(IpAddr) -> bool,
+) -> anyhow::Result<reqwest::StatusCode> {
+    let (url, address) = resolve(url, allowed).await.map_err(anyhow::Error::msg)?;
+    let mut client = reqwest::Client::builder()
+        .connect_timeout(TIMEOUT)
+        .timeout(TIMEOUT)
+        .redirect(Policy::none())
+        .user_agent("wars-server");
+    if let Some(Host::Domain(domain)) = url.host() {
+        client = client.resolve(domain, address);
+    }
+    let response = client
+        .build()?
+        .post(url.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_owned())
+        .send()
+        .await?;
+    Ok(response.status())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::test::{database_pool, start_game};
+    use crate::model::{save_webhook, set_game_player};
+    use crate::webhooks::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use wars::protocol::GameSettings;
+
+    const RETRY_DELAY: Duration = Duration::from_millis(20);
+
+    /// A local HTTP server that answers requests with the statuses in turn
+    /// and then stops. Returns its URL and the requests it got.
+    async fn stand_in(statuses: Vec<u16>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/turns", listener.local_addr().unwrap());
+        let requests = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                // Headers, then as much body as they announce
+                let complete = |request: &[u8]| {
+                    let text = String::from_utf8_lossy(request).to_lowercase();
+                    let Some(header_end) = text.find("\r\n\r\n") else {
+                        return false;
+                    };
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |length| length.trim().parse().unwrap());
+                    request.len() >= header_end + 4 + length
+                };
+                while !complete(&request) {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    assert!(read > 0, "Request ended early");
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                let response = format!(
+                    "HTTP/1.1 {status} Stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (url, requests)
+    }
+
+    fn allow_any(_: IpAddr) -> bool {
+        true
+    }
+
+    #[tokio::test]
+    async fn turn_starts_are_posted_as_json_until_delivered() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let slot = PlayerSlotType::Bot("random".to_owned());
+        set_game_player(game_id, 2, &slot, None, &pool)
+            .await
+            .unwrap();
+        let payload = turn_started(game_id, 1, 3, vec![2], &pool).await.unwrap();
+        let (url, requests) = stand_in(vec![503, 500, 200]).await;
+
+        let started = Instant::now();
+        deliver(
+            &url,
+            &serde_json::to_string(&payload).unwrap(),
+            RETRY_DELAY,
+            allow_any,
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() >= RETRY_DELAY * 3);
+
+        let requests = requests.await.unwrap();
+        assert_eq!(requests.len(), 3);
+        for request in requests {
+            assert!(request.starts_with("POST /turns HTTP/1.1\r\n"));
+            assert!(
+                request
+                    .to_lowercase()
+                    .contains("content-type: application/json")
+            );
+            let (_, body) = request.split_once("\r\n\r\n").unwrap();
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "game_id": game_id,
+                    "map": "Third party",
+                    "round": 3,
+                    "player_number": 1,
+                    "opponents": [{"player_number": 2, "name": "random"}],
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn delivery_is_given_up_after_attempts() {
+        let (url, requests) = stand_in(vec![500; ATTEMPTS as usize]).await;
+        assert!(deliver(&url, "{}", RETRY_DELAY, allow_any).await.is_err());
+        assert_eq!(requests.await.unwrap().len(), ATTEMPTS as usize);
+
+        // Nothing listens on the port any more
+        let started = Instant::now();
+        assert!(deliver(&url, "{}", RETRY_DELAY, allow_any).await.is_err());
+        assert!(started.elapsed() >= RETRY_DELAY * (2u32.pow(ATTEMPTS - 1) - 1));
+    }
+
+    #[tokio::test]
+    async fn refused_notifications_are_not_retried() {
+        let (url, requests) = stand_in(vec![404]).await;
+        assert!(deliver(&url, "{}", RETRY_DELAY, allow_any).await.is_err());
+        assert_eq!(requests.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn webhooks_to_private_addresses_are_refused() {
+        for url in [
+            "http://127.0.0.1:8080/turns",
+            "http://10.0.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "ftp://93.184.215.14/",
+        ] {
+            assert!(check_url(url).await.is_err(), "{url}");
+        }
+        assert!(check_url("https://93.184.215.14/turns").await.is_ok());
+        assert!(
+            check_url("http://[2606:2800:21f:cb07:6820:80da:af6b:8b2c]/")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn webhooks_follow_the_user_playing() {
+        let pool = database_pool().await;
+        let game_id = start_game(&GameSettings::default(), &pool).await;
+        let slot = PlayerSlotType::Human(None);
+        set_game_player(game_id, 1, &slot, Some(7), &pool)
+            .await
+            .unwrap();
+        save_webhook(7, Some("https://example.com/turns"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            load_webhook(game_id, 1, &pool).await.unwrap().as_deref(),
+            Some("https://example.com/turns")
+        );
+        assert_eq!(load_webhook(game_id, 2, &pool).await.unwrap(), None);
+
+        // Another user taking the seat doesn't inherit the webhook
+        set_game_player(game_id, 1, &slot, Some(8), &pool)
+            .await
+            .unwrap();
+        assert_eq!(load_webhook(game_id, 1, &pool).await.unwrap(), None);
+    }
+}